@@ -0,0 +1,453 @@
+//! Playing VMD motions over time.
+//!
+//! [`Clock`] keeps the position in a motion on MMD's timebase of 30 frames a
+//! second, between frames while playing; [`Player`] poses a skeleton and its
+//! morphs at that position with the clips of [`crate::blend`]. The player's
+//! keys:
+//!
+//! - P plays and pauses
+//! - Comma and Period step a frame back and forward, PageUp and PageDown a
+//!   second
+//! - Home and End seek to the start and the end
+//! - L turns looping on and off; I and O set where the loop starts and ends
+//!   to the current frame, Back clears them
+//! - `[` and `]` halve and double the speed
+//! - 1 to 9 fade the first nine clips out and back in
+//! - Tab cross-fades from the first clip to the second
+//! - C lets the view follow the camera track of the motions, or not
+
+use std::time::Duration;
+use winit::event::{ElementState, VirtualKeyCode};
+
+use crate::blend::{self, Clip};
+use crate::interpolation::{self, CameraPose};
+use crate::morph::Morphs;
+use crate::skeleton::Skeleton;
+
+/// MMD's frame rate, which VMD keyframes are numbered in.
+pub const FPS: f32 = 30.0;
+
+const MIN_SPEED: f32 = 1.0 / 8.0;
+const MAX_SPEED: f32 = 8.0;
+/// Seconds a clip takes to fade out or in from the keyboard.
+const TOGGLE_FADE: f32 = 0.5;
+
+/// What one [`Clock::advance`] did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tick {
+    /// Seconds of animation time played, already scaled by the speed.
+    pub elapsed: f32,
+    /// Whether the clock jumped since the last tick, by a seek or by looping
+    /// back, so that anything simulated along the way has to start over.
+    pub jumped: bool,
+}
+
+/// Position in a motion and how it moves on.
+pub struct Clock {
+    /// Position in frames, between frames while playing.
+    frame: f32,
+    /// The last frame of the motion, where playing stops or loops.
+    end: f32,
+    pub playing: bool,
+    pub looping: bool,
+    /// Frames to loop between instead of the whole motion.
+    loop_range: Option<(f32, f32)>,
+    speed: f32,
+    jumped: bool,
+}
+
+impl Clock {
+    /// A paused clock at frame 0 for a motion ending at `end`.
+    pub fn new(end: f32) -> Self {
+        Self {
+            frame: 0.0,
+            end,
+            playing: false,
+            looping: true,
+            loop_range: None,
+            speed: 1.0,
+            jumped: false,
+        }
+    }
+
+    pub fn frame(&self) -> f32 {
+        self.frame
+    }
+
+    /// Moves the end of the motion, for when clips come and go.
+    pub fn set_end(&mut self, end: f32) {
+        self.end = end.max(0.0);
+        self.frame = self.frame.min(self.end);
+        if let Some((start, end)) = self.loop_range {
+            self.set_loop_range(Some((start, end)));
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Sets the playback speed, 1 being real time; kept between 1/8 and 8.
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+    }
+
+    /// The frames played between while looping.
+    pub fn range(&self) -> (f32, f32) {
+        match (self.looping, self.loop_range) {
+            (true, Some(range)) => range,
+            _ => (0.0, self.end),
+        }
+    }
+
+    /// Loops between `start` and `end`, in either order, or over the whole
+    /// motion for `None`. Turns looping on.
+    pub fn set_loop_range(&mut self, range: Option<(f32, f32)>) {
+        self.loop_range = range.map(|(start, end)| {
+            let (start, end) = (start.clamp(0.0, self.end), end.clamp(0.0, self.end));
+            (start.min(end), start.max(end))
+        });
+        self.looping = true;
+    }
+
+    /// Moves to `frame`, kept within the motion.
+    pub fn seek(&mut self, frame: f32) {
+        self.frame = frame.clamp(0.0, self.end);
+        self.jumped = true;
+    }
+
+    /// Plays on for `dt` of real time. At the end of the motion playing
+    /// stops, or when looping, carries on from the start of the loop range.
+    pub fn advance(&mut self, dt: Duration) -> Tick {
+        let mut elapsed = 0.0;
+        if self.playing {
+            elapsed = dt.as_secs_f32() * self.speed;
+            self.frame += elapsed * FPS;
+            let (start, end) = self.range();
+            if self.frame > end {
+                if self.looping && end > start {
+                    self.frame = start + (self.frame - end) % (end - start);
+                    self.jumped = true;
+                } else {
+                    self.frame = end;
+                    self.playing = false;
+                }
+            }
+        }
+        let jumped = std::mem::replace(&mut self.jumped, false);
+        Tick { elapsed, jumped }
+    }
+}
+
+/// The motions on a model and where in them it is.
+pub struct Player {
+    pub clips: Vec<Clip>,
+    pub clock: Clock,
+    /// Whether the view follows the camera track of the clips.
+    pub follow_camera: bool,
+}
+
+impl Player {
+    /// Plays `clips` from frame 0.
+    pub fn new(clips: Vec<Clip>) -> Self {
+        let end = clips.iter().map(Clip::end).fold(0.0, f32::max);
+        let mut clock = Clock::new(end);
+        clock.playing = true;
+        Self {
+            clips,
+            clock,
+            follow_camera: true,
+        }
+    }
+
+    /// Plays on for `dt` of real time, fading clips as they are set to and
+    /// dropping those that are done with. Fades go on while paused, at the
+    /// playback speed, so that clips can be switched on a still frame.
+    pub fn advance(&mut self, dt: Duration) -> Tick {
+        let tick = self.clock.advance(dt);
+        let fade_time = dt.as_secs_f32() * self.clock.speed();
+        for clip in &mut self.clips {
+            clip.advance(fade_time);
+        }
+        let count = self.clips.len();
+        self.clips.retain(|clip| !clip.finished());
+        if self.clips.len() != count {
+            self.update_end();
+        }
+        tick
+    }
+
+    /// Poses `skeleton` and sets `morphs` as the clips have them at the
+    /// current frame.
+    pub fn apply(&self, skeleton: &mut Skeleton, morphs: &mut Morphs) {
+        blend::apply(&self.clips, self.clock.frame(), skeleton, morphs);
+    }
+
+    /// The camera at the current frame of the first clip playing with a
+    /// camera track, while the view follows it.
+    pub fn camera_pose(&self) -> Option<CameraPose> {
+        if !self.follow_camera {
+            return None;
+        }
+        let clip = self
+            .clips
+            .iter()
+            .find(|clip| clip.weight() > 0.0 && !clip.motion.camera.is_empty())?;
+        interpolation::camera_pose(&clip.motion.camera, self.clock.frame() - clip.start)
+    }
+
+    /// Replaces the clip at `index` with `clip` over `seconds`: `clip` starts
+    /// at the current frame and fades in as the old one fades out, which is
+    /// dropped once the fade ends. `clip` is added last when there is no clip
+    /// at `index`.
+    pub fn cross_fade(&mut self, index: usize, mut clip: Clip, seconds: f32) {
+        clip.start = self.clock.frame();
+        let weight = clip.weight();
+        clip.set_weight(0.0);
+        clip.fade_to(weight, seconds);
+        match self.clips.get_mut(index) {
+            Some(old) => {
+                old.fade_out(seconds);
+                self.clips.insert(index + 1, clip);
+            }
+            None => self.clips.push(clip),
+        }
+        self.update_end();
+    }
+
+    fn update_end(&mut self) {
+        let end = self.clips.iter().map(Clip::end).fold(0.0, f32::max);
+        self.clock.set_end(end);
+    }
+
+    /// Handles the keys listed in the module documentation when pressed.
+    pub fn process_keyboard(&mut self, key: VirtualKeyCode, state: ElementState) -> bool {
+        if state != ElementState::Pressed {
+            return false;
+        }
+        if let Some(index) = clip_key(key) {
+            return match self.clips.get_mut(index) {
+                Some(clip) => {
+                    let weight = if clip.target_weight() > 0.0 { 0.0 } else { 1.0 };
+                    clip.fade_to(weight, TOGGLE_FADE);
+                    log::info!("fading {} to {}", clip.name, weight);
+                    true
+                }
+                None => false,
+            };
+        }
+        let clock = &mut self.clock;
+        let frame = clock.frame();
+        match key {
+            VirtualKeyCode::P => {
+                clock.playing = !clock.playing;
+                // Playing again after the end starts over
+                if clock.playing && frame >= clock.range().1 {
+                    clock.seek(clock.range().0);
+                }
+            }
+            VirtualKeyCode::Comma => clock.seek(frame.ceil() - 1.0),
+            VirtualKeyCode::Period => clock.seek(frame.floor() + 1.0),
+            VirtualKeyCode::PageUp => clock.seek(frame - FPS),
+            VirtualKeyCode::PageDown => clock.seek(frame + FPS),
+            VirtualKeyCode::Home => clock.seek(clock.range().0),
+            VirtualKeyCode::End => clock.seek(clock.range().1),
+            VirtualKeyCode::L => clock.looping = !clock.looping,
+            VirtualKeyCode::I => {
+                let end = clock.loop_range.map_or(clock.end, |(_, end)| end);
+                clock.set_loop_range(Some((frame.floor(), end)));
+            }
+            VirtualKeyCode::O => {
+                let start = clock.loop_range.map_or(0.0, |(start, _)| start);
+                clock.set_loop_range(Some((start, frame.ceil())));
+            }
+            VirtualKeyCode::Back => clock.set_loop_range(None),
+            VirtualKeyCode::LBracket => clock.set_speed(clock.speed() * 0.5),
+            VirtualKeyCode::RBracket => clock.set_speed(clock.speed() * 2.0),
+            VirtualKeyCode::Tab if self.clips.len() > 1 => {
+                let clip = self.clips.remove(1);
+                log::info!("cross-fading {} to {}", self.clips[0].name, clip.name);
+                self.cross_fade(0, clip, TOGGLE_FADE);
+                return true;
+            }
+            VirtualKeyCode::C => {
+                self.follow_camera = !self.follow_camera;
+                log::info!("following the camera track: {}", self.follow_camera);
+                return true;
+            }
+            _ => return false,
+        }
+        log::info!(
+            "frame {:.1} of {}, {}, speed {}x, {}",
+            clock.frame(),
+            clock.end,
+            if clock.playing { "playing" } else { "paused" },
+            clock.speed(),
+            match (clock.looping, clock.range()) {
+                (true, (start, end)) => format!("looping {} to {}", start, end),
+                (false, _) => "not looping".to_string(),
+            }
+        );
+        true
+    }
+}
+
+/// The clip a number key toggles.
+fn clip_key(key: VirtualKeyCode) -> Option<usize> {
+    let keys = [
+        VirtualKeyCode::Key1,
+        VirtualKeyCode::Key2,
+        VirtualKeyCode::Key3,
+        VirtualKeyCode::Key4,
+        VirtualKeyCode::Key5,
+        VirtualKeyCode::Key6,
+        VirtualKeyCode::Key7,
+        VirtualKeyCode::Key8,
+        VirtualKeyCode::Key9,
+    ];
+    keys.iter().position(|&k| k == key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vmd::{MorphKeyframe, Motion};
+
+    #[test]
+    fn clock_wraps_inside_the_loop_range() {
+        let mut clock = Clock::new(100.0);
+        clock.set_loop_range(Some((60.0, 20.0)));
+        assert_eq!(clock.range(), (20.0, 60.0));
+        clock.seek(50.0);
+        assert!(clock.advance(Duration::ZERO).jumped);
+
+        clock.playing = true;
+        let tick = clock.advance(Duration::from_secs(1));
+        assert_eq!((tick.elapsed, tick.jumped), (1.0, true));
+        assert_eq!(clock.frame(), 40.0);
+        assert!(!clock.advance(Duration::from_millis(100)).jumped);
+        assert!((clock.frame() - 43.0).abs() < 1e-4);
+
+        // Without looping the range is the whole motion again
+        clock.looping = false;
+        assert_eq!(clock.range(), (0.0, 100.0));
+    }
+
+    #[test]
+    fn clock_stops_at_the_end() {
+        let mut clock = Clock::new(100.0);
+        clock.looping = false;
+        clock.playing = true;
+        clock.seek(90.0);
+        let tick = clock.advance(Duration::from_secs(1));
+        assert_eq!((clock.frame(), clock.playing), (100.0, false));
+        assert!(tick.jumped, "the seek is reported with the next tick");
+        let tick = clock.advance(Duration::from_secs(1));
+        assert_eq!((tick.elapsed, tick.jumped), (0.0, false));
+        assert_eq!(clock.frame(), 100.0);
+    }
+
+    #[test]
+    fn clock_seeks_within_the_motion() {
+        let mut clock = Clock::new(100.0);
+        clock.seek(-5.0);
+        assert_eq!(clock.frame(), 0.0);
+        clock.seek(500.0);
+        assert_eq!(clock.frame(), 100.0);
+        assert!(clock.advance(Duration::ZERO).jumped);
+    }
+
+    #[test]
+    fn clock_speed_is_clamped() {
+        let mut clock = Clock::new(100.0);
+        clock.set_speed(0.01);
+        assert_eq!(clock.speed(), 1.0 / 8.0);
+        clock.set_speed(100.0);
+        assert_eq!(clock.speed(), 8.0);
+        clock.set_speed(2.0);
+        clock.playing = true;
+        assert_eq!(clock.advance(Duration::from_secs(1)).elapsed, 2.0);
+        assert_eq!(clock.frame(), 60.0);
+    }
+
+    #[test]
+    fn clock_end_clamps_the_loop_range() {
+        let mut clock = Clock::new(100.0);
+        clock.set_loop_range(Some((20.0, 80.0)));
+        clock.seek(70.0);
+        clock.set_end(50.0);
+        assert_eq!(clock.range(), (20.0, 50.0));
+        assert_eq!(clock.frame(), 50.0);
+        clock.set_end(-10.0);
+        assert_eq!(clock.range(), (0.0, 0.0));
+    }
+
+    /// A clip with one morph keyed up to `last_frame`.
+    fn clip(name: &str, last_frame: u32) -> Clip {
+        let mut motion = Motion::default();
+        let key = MorphKeyframe {
+            frame: last_frame,
+            weight: 1.0,
+        };
+        motion.morphs.insert("あ".to_string(), vec![key]);
+        Clip::new(name, motion)
+    }
+
+    fn weights(player: &Player) -> Vec<(&str, f32)> {
+        player
+            .clips
+            .iter()
+            .map(|clip| (clip.name.as_str(), clip.weight()))
+            .collect()
+    }
+
+    #[test]
+    fn cross_fade_swaps_weights_and_drops_the_old_clip() {
+        let mut player = Player::new(vec![clip("dance", 100), clip("face", 100)]);
+        player.clock.seek(10.0);
+        player.cross_fade(0, clip("walk", 300), 1.0);
+        assert_eq!(player.clips[1].start, 10.0);
+        assert_eq!(player.clock.range(), (0.0, 310.0));
+        assert_eq!(
+            weights(&player),
+            [("dance", 1.0), ("walk", 0.0), ("face", 1.0)]
+        );
+
+        let step = Duration::from_millis(250);
+        player.advance(step);
+        assert_eq!(
+            weights(&player),
+            [("dance", 0.75), ("walk", 0.25), ("face", 1.0)]
+        );
+        player.advance(step);
+        assert_eq!(
+            weights(&player),
+            [("dance", 0.5), ("walk", 0.5), ("face", 1.0)]
+        );
+        player.advance(step);
+        player.advance(step);
+        assert_eq!(weights(&player), [("walk", 1.0), ("face", 1.0)]);
+    }
+
+    #[test]
+    fn cross_fade_adds_a_clip_past_the_end() {
+        let mut player = Player::new(vec![clip("dance", 100)]);
+        player.cross_fade(3, clip("face", 50), 0.5);
+        assert_eq!(weights(&player), [("dance", 1.0), ("face", 0.0)]);
+        player.advance(Duration::from_millis(500));
+        assert_eq!(weights(&player), [("dance", 1.0), ("face", 1.0)]);
+    }
+
+    #[test]
+    fn tab_cross_fades_to_the_second_clip() {
+        let mut player = Player::new(vec![clip("dance", 100), clip("walk", 100)]);
+        player.clock.playing = false;
+        player.clock.seek(20.0);
+        assert!(player.process_keyboard(VirtualKeyCode::Tab, ElementState::Pressed));
+        assert_eq!(weights(&player), [("dance", 1.0), ("walk", 0.0)]);
+        assert_eq!(player.clips[1].start, 20.0);
+        player.advance(Duration::from_secs_f32(TOGGLE_FADE));
+        assert_eq!(weights(&player), [("walk", 1.0)]);
+        assert!(!player.process_keyboard(VirtualKeyCode::Tab, ElementState::Pressed));
+    }
+}
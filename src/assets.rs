@@ -0,0 +1,462 @@
+use anyhow::*;
+use std::path::{Path, PathBuf};
+
+use crate::model::MaterialOverrides;
+use crate::obj::ImportOptions;
+use crate::resolver::TextureResolver;
+
+const USAGE: &str = "usage: taggix [MODEL] [--texture [NAME=]PATH]... [--normal-map NAME=PATH]... [--bind MTL=NAME]...
+              [--search-root DIR]... [--uv-projection MODE] [--crease-angle DEGREES]
+              [--motion PATH]... [--face-motion PATH]... [--additive-motion PATH]...
+              [--bone-map PATH] [--retarget-from MODEL] [--pose PATH] [--export-pose PATH]
+              [--export-motion PATH] [--cpu-skinning] [--no-physics]
+
+  MODEL                   OBJ, PMX or PMD file to display
+  --texture [NAME=]PATH   texture for the MTL material NAME (default: file stem)
+  --normal-map NAME=PATH  normal map for the texture named NAME
+  --bind MTL=NAME         draw meshes using MTL material MTL with material NAME
+  --search-root DIR       extra folder to look for MTL textures in (repeatable)
+  --uv-projection MODE    none, planar or spherical UVs for meshes without any
+  --crease-angle DEGREES  sharpest edge smoothed when generating normals (default 60)
+  --motion PATH           VMD motion to play on the model; later ones play over earlier ones
+  --face-motion PATH      VMD motion played on the head bones and the morphs only
+  --additive-motion PATH  VMD motion added on top of the others
+  --bone-map PATH         `motion name = model name` lines naming the model's bones and morphs
+  --retarget-from MODEL   PMX or PMD model the motions were made for, to make up for its rest pose
+  --pose PATH             VPD pose to put the model in
+  --export-pose PATH      VPD file F5 saves the model's current pose to
+  --export-motion PATH    VMD file F6 saves the motions, blended into one, to
+  --cpu-skinning          deform the model on the CPU even where the shader could
+  --no-physics            leave hair and skirts to the motion instead of simulating them";
+
+/// A texture given on the command line, bound to the MTL material `name`.
+#[derive(Debug)]
+pub struct TextureAsset {
+    pub name: String,
+    pub path: PathBuf,
+    pub normal_map: Option<PathBuf>,
+}
+
+impl TextureAsset {
+    fn new<S: Into<String>>(name: S, path: PathBuf) -> Self {
+        Self {
+            name: name.into(),
+            path,
+            normal_map: None,
+        }
+    }
+
+    fn parse(arg: &str) -> Self {
+        match arg.split_once('=') {
+            Some((name, path)) if !name.is_empty() => Self::new(name, PathBuf::from(path)),
+            _ => {
+                let path = PathBuf::from(arg);
+                let name = path
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned();
+                Self::new(name, path)
+            }
+        }
+    }
+}
+
+/// A motion given on the command line and how it plays with the others.
+#[derive(Debug)]
+pub struct MotionAsset {
+    pub path: PathBuf,
+    /// Only moves the head and the bones below it, and sets morphs.
+    pub face_only: bool,
+    /// Added to the motions before it instead of replacing them.
+    pub additive: bool,
+}
+
+impl MotionAsset {
+    fn new(path: String) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            face_only: false,
+            additive: false,
+        }
+    }
+}
+
+/// Which assets the viewer loads at startup.
+///
+/// When `textures` is empty the model keeps the materials its format loader
+/// read, such as those of the OBJ's MTL files. Otherwise each texture becomes
+/// one material and the meshes are bound to them by material name, see
+/// [`crate::model::ModelData::bind_materials`].
+#[derive(Debug)]
+pub struct AssetConfig {
+    pub model: PathBuf,
+    pub textures: Vec<TextureAsset>,
+    pub material_overrides: MaterialOverrides,
+    pub search_roots: Vec<PathBuf>,
+    pub import_options: ImportOptions,
+    /// Played together, in order.
+    pub motions: Vec<MotionAsset>,
+    /// Mapping file for motion track names, see [`crate::retarget`].
+    pub bone_map: Option<PathBuf>,
+    /// Model the motions were made for.
+    pub retarget_from: Option<PathBuf>,
+    pub pose: Option<PathBuf>,
+    /// Where the current pose is saved to on request.
+    pub export_pose: Option<PathBuf>,
+    /// Where the motions are saved to, baked into one, on request.
+    pub export_motion: Option<PathBuf>,
+    /// Skin on the CPU and upload the vertices every frame, as is done anyway
+    /// on adapters without storage buffers in the vertex stage.
+    pub cpu_skinning: bool,
+    /// Leave out the rigid-body simulation of MMD models.
+    pub no_physics: bool,
+}
+
+impl AssetConfig {
+    pub fn res_dir() -> PathBuf {
+        Path::new(env!("OUT_DIR")).join("res")
+    }
+
+    /// The yyb school miku setup the viewer used to bake into the binary.
+    pub fn default_assets() -> Self {
+        let res_dir = Self::res_dir();
+        let pose_dir = res_dir.join("yyb_school_miku_pose");
+        let model_dir = res_dir.join("yyb_school_miku");
+
+        Self {
+            model: pose_dir.join("yyb bake r.obj"),
+            textures: vec![
+                TextureAsset::new("Hairclip", pose_dir.join("hairpin bake.png")),
+                TextureAsset::new("Body", model_dir.join("head.png")),
+                TextureAsset::new("Shoes", pose_dir.join("shoes bake.png")),
+                TextureAsset::new("Hair01", model_dir.join("Hair01.png")),
+                TextureAsset::new("Hair03", model_dir.join("Hair02.png")),
+                TextureAsset::new("Socls", model_dir.join("socks.png")),
+                TextureAsset::new("Dress_White", pose_dir.join("Material 4_UVP1.png")),
+                TextureAsset::new("Bow", pose_dir.join("bow bake.png")),
+                TextureAsset::new("Dress", pose_dir.join("dress bake.png")),
+            ],
+            material_overrides: vec![("Hairshadow".to_string(), "Hair01".to_string())]
+                .into_iter()
+                .collect(),
+            search_roots: vec![res_dir],
+            import_options: ImportOptions::default(),
+            motions: Vec::new(),
+            bone_map: None,
+            retarget_from: None,
+            pose: None,
+            export_pose: None,
+            export_motion: None,
+            cpu_skinning: false,
+            no_physics: false,
+        }
+    }
+
+    /// Parses the command line. Without a model argument the default assets
+    /// are used; a model given on the command line starts with no textures.
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Self> {
+        let mut model = None;
+        let mut textures = Vec::new();
+        let mut normal_maps = Vec::new();
+        let mut material_overrides = MaterialOverrides::new();
+        let mut search_roots = Vec::new();
+        let mut uv_projection = None;
+        let mut crease_angle = None;
+        let mut motions = Vec::new();
+        let mut bone_map = None;
+        let mut retarget_from = None;
+        let mut pose = None;
+        let mut export_pose = None;
+        let mut export_motion = None;
+        let mut cpu_skinning = false;
+        let mut no_physics = false;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => bail!("{}", USAGE),
+                "--texture" => {
+                    textures.push(TextureAsset::parse(&Self::value(&mut args, &arg)?));
+                }
+                "--normal-map" => {
+                    let value = Self::value(&mut args, &arg)?;
+                    let (name, path) = value.split_once('=').with_context(|| {
+                        format!("--normal-map expects NAME=PATH, got {:?}", value)
+                    })?;
+                    normal_maps.push((name.to_string(), PathBuf::from(path)));
+                }
+                "--bind" => {
+                    let binding = Self::value(&mut args, &arg)?;
+                    let (mtl_name, name) = binding
+                        .split_once('=')
+                        .with_context(|| format!("--bind expects MTL=NAME, got {:?}", binding))?;
+                    material_overrides.insert(mtl_name.to_string(), name.to_string());
+                }
+                "--search-root" => {
+                    search_roots.push(PathBuf::from(Self::value(&mut args, &arg)?));
+                }
+                "--uv-projection" => {
+                    uv_projection = Some(Self::value(&mut args, &arg)?.parse()?);
+                }
+                "--crease-angle" => {
+                    let value = Self::value(&mut args, &arg)?;
+                    crease_angle = Some(
+                        value
+                            .parse::<f32>()
+                            .with_context(|| format!("invalid crease angle {:?}", value))?,
+                    );
+                }
+                "--motion" => motions.push(MotionAsset::new(Self::value(&mut args, &arg)?)),
+                "--face-motion" => motions.push(MotionAsset {
+                    face_only: true,
+                    ..MotionAsset::new(Self::value(&mut args, &arg)?)
+                }),
+                "--additive-motion" => motions.push(MotionAsset {
+                    additive: true,
+                    ..MotionAsset::new(Self::value(&mut args, &arg)?)
+                }),
+                "--bone-map" => {
+                    bone_map = Some(PathBuf::from(Self::value(&mut args, &arg)?));
+                }
+                "--retarget-from" => {
+                    retarget_from = Some(PathBuf::from(Self::value(&mut args, &arg)?));
+                }
+                "--pose" => {
+                    pose = Some(PathBuf::from(Self::value(&mut args, &arg)?));
+                }
+                "--export-pose" => {
+                    export_pose = Some(PathBuf::from(Self::value(&mut args, &arg)?));
+                }
+                "--export-motion" => {
+                    export_motion = Some(PathBuf::from(Self::value(&mut args, &arg)?));
+                }
+                "--cpu-skinning" => cpu_skinning = true,
+                "--no-physics" => no_physics = true,
+                _ if arg.starts_with('-') => bail!("unknown option {:?}\n\n{}", arg, USAGE),
+                _ if model.is_none() => model = Some(PathBuf::from(arg)),
+                _ => bail!("unexpected argument {:?}\n\n{}", arg, USAGE),
+            }
+        }
+
+        let mut config = match model {
+            Some(model) => Self {
+                model,
+                textures: Vec::new(),
+                material_overrides: MaterialOverrides::new(),
+                search_roots: vec![Self::res_dir()],
+                import_options: ImportOptions::default(),
+                motions: Vec::new(),
+                bone_map: None,
+                retarget_from: None,
+                pose: None,
+                export_pose: None,
+                export_motion: None,
+                cpu_skinning: false,
+                no_physics: false,
+            },
+            None => Self::default_assets(),
+        };
+        if !textures.is_empty() {
+            config.textures = textures;
+            config.material_overrides.clear();
+        }
+        for (name, path) in normal_maps {
+            let texture = config
+                .textures
+                .iter_mut()
+                .find(|texture| texture.name == name)
+                .with_context(|| format!("--normal-map for unknown texture {:?}", name))?;
+            texture.normal_map = Some(path);
+        }
+        config.material_overrides.extend(material_overrides);
+        config.search_roots.extend(search_roots);
+        if let Some(uv_projection) = uv_projection {
+            config.import_options.uv_projection = uv_projection;
+        }
+        if let Some(crease_angle) = crease_angle {
+            config.import_options.crease_angle = crease_angle;
+        }
+        config.motions = motions;
+        config.bone_map = bone_map;
+        config.retarget_from = retarget_from;
+        config.pose = pose;
+        config.export_pose = export_pose;
+        config.export_motion = export_motion;
+        config.cpu_skinning = cpu_skinning;
+        config.no_physics = no_physics;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Resolver for the textures named in the model's MTL file, falling back
+    /// to `res/default tex.png`.
+    pub fn texture_resolver(&self) -> TextureResolver {
+        TextureResolver::new(
+            self.search_roots.clone(),
+            Self::res_dir().join("default tex.png"),
+        )
+    }
+
+    fn value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String> {
+        args.next()
+            .with_context(|| format!("{} expects a value\n\n{}", option, USAGE))
+    }
+
+    /// Checks that every referenced file exists so a missing asset is reported
+    /// by name before any GPU resources are created.
+    pub fn validate(&self) -> Result<()> {
+        Self::check_file("model", &self.model)?;
+        for texture in &self.textures {
+            Self::check_file("texture", &texture.path)?;
+            if let Some(normal_map) = &texture.normal_map {
+                Self::check_file("normal map", normal_map)?;
+            }
+        }
+        for motion in &self.motions {
+            Self::check_file("motion", &motion.path)?;
+        }
+        if let Some(bone_map) = &self.bone_map {
+            Self::check_file("bone map", bone_map)?;
+        }
+        if let Some(model) = &self.retarget_from {
+            Self::check_file("retarget model", model)?;
+        }
+        if let Some(pose) = &self.pose {
+            Self::check_file("pose", pose)?;
+        }
+        for root in &self.search_roots {
+            if !root.is_dir() {
+                bail!("search root {:?} is not a directory", root);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_file(kind: &str, path: &Path) -> Result<()> {
+        if !path.is_file() {
+            bail!("{} file {:?} does not exist", kind, path);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `args` after the cube model, whose files are in `res`.
+    fn parse(args: &[&str]) -> Result<AssetConfig> {
+        let model = AssetConfig::res_dir().join("cube.obj");
+        let args = std::iter::once(model.to_string_lossy().into_owned())
+            .chain(args.iter().map(|arg| arg.to_string()));
+        AssetConfig::from_args(args)
+    }
+
+    fn res(name: &str) -> String {
+        AssetConfig::res_dir()
+            .join(name)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn textures_and_bindings() {
+        let head = res("head.png");
+        let config = parse(&[
+            "--texture",
+            &format!("Skin={}", head),
+            "--texture",
+            &res("cube-diffuse.jpg"),
+            "--bind",
+            "Material=Skin",
+        ])
+        .unwrap();
+
+        let textures = config
+            .textures
+            .iter()
+            .map(|texture| (texture.name.as_str(), texture.path.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            textures,
+            [
+                ("Skin", PathBuf::from(head)),
+                ("cube-diffuse", PathBuf::from(res("cube-diffuse.jpg"))),
+            ]
+        );
+        assert_eq!(config.material_overrides["Material"], "Skin");
+    }
+
+    #[test]
+    fn bind_expects_a_pair() {
+        let error = parse(&["--bind", "Material"]).unwrap_err();
+        assert!(
+            error.to_string().contains("--bind expects MTL=NAME"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn unknown_option() {
+        let error = parse(&["--wireframe"]).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("unknown option \"--wireframe\""),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn option_without_value() {
+        let error = parse(&["--motion"]).unwrap_err();
+        assert!(
+            error.to_string().starts_with("--motion expects a value"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn bad_import_options() {
+        let error = parse(&["--uv-projection", "cubic"]).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("unknown UV projection \"cubic\""),
+            "{}",
+            error
+        );
+        let error = parse(&["--crease-angle", "steep"]).unwrap_err();
+        assert!(
+            error.to_string().contains("invalid crease angle \"steep\""),
+            "{}",
+            error
+        );
+
+        let config = parse(&["--uv-projection", "planar", "--crease-angle", "30"]).unwrap();
+        assert_eq!(config.import_options.crease_angle, 30.0);
+    }
+
+    #[test]
+    fn missing_files_are_named() {
+        let error = parse(&["--motion", "missing.vmd"]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "motion file \"missing.vmd\" does not exist"
+        );
+
+        let mut config = parse(&[]).unwrap();
+        config.validate().unwrap();
+        config.model = PathBuf::from("missing.pmx");
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "model file \"missing.pmx\" does not exist"
+        );
+    }
+}
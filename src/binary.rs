@@ -0,0 +1,166 @@
+use cgmath::{Vector2, Vector3, Vector4};
+use std::io::{self, Read, Write};
+
+/// Little-endian reader for the binary MMD formats.
+pub struct BinaryReader<R> {
+    inner: R,
+    position: u64,
+}
+
+impl<R: Read> BinaryReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, position: 0 }
+    }
+
+    /// Number of bytes read so far, for error messages.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Reads `len` bytes. The length usually comes from the file itself, so
+    /// it is not trusted for the allocation: a file cut short fails with
+    /// `UnexpectedEof` instead of reserving whatever it claims.
+    pub fn read_bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(len.min(1 << 16));
+        (&mut self.inner).take(len as u64).read_to_end(&mut bytes)?;
+        self.position += bytes.len() as u64;
+        if bytes.len() != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(bytes)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.inner.read_exact(&mut bytes)?;
+        self.position += N as u64;
+        Ok(bytes)
+    }
+
+    /// Whether the input is exhausted, without consuming anything.
+    pub fn at_end(&mut self) -> io::Result<bool>
+    where
+        R: io::BufRead,
+    {
+        Ok(self.inner.fill_buf()?.is_empty())
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_i8(&mut self) -> io::Result<i8> {
+        Ok(self.read_u8()? as i8)
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_le_bytes(self.read_array()?))
+    }
+
+    /// Reads a fixed size Shift-JIS string field, as used by PMD and VMD.
+    /// The text ends at the first NUL; whatever follows it is padding.
+    pub fn read_shift_jis(&mut self, len: usize) -> io::Result<String> {
+        let bytes = self.read_bytes(len)?;
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(len);
+        let (text, _) = encoding_rs::SHIFT_JIS.decode_without_bom_handling(&bytes[..end]);
+        Ok(text.into_owned())
+    }
+
+    pub fn read_vec2(&mut self) -> io::Result<Vector2<f32>> {
+        Ok(Vector2::new(self.read_f32()?, self.read_f32()?))
+    }
+
+    pub fn read_vec3(&mut self) -> io::Result<Vector3<f32>> {
+        Ok(Vector3::new(
+            self.read_f32()?,
+            self.read_f32()?,
+            self.read_f32()?,
+        ))
+    }
+
+    pub fn read_vec4(&mut self) -> io::Result<Vector4<f32>> {
+        Ok(Vector4::new(
+            self.read_f32()?,
+            self.read_f32()?,
+            self.read_f32()?,
+            self.read_f32()?,
+        ))
+    }
+}
+
+/// Little-endian writer for the binary MMD formats, the counterpart of
+/// [`BinaryReader`].
+pub struct BinaryWriter<W> {
+    inner: W,
+}
+
+impl<W: Write> BinaryWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_all(bytes)
+    }
+
+    pub fn write_u8(&mut self, value: u8) -> io::Result<()> {
+        self.write_bytes(&[value])
+    }
+
+    pub fn write_u32(&mut self, value: u32) -> io::Result<()> {
+        self.write_bytes(&value.to_le_bytes())
+    }
+
+    pub fn write_f32(&mut self, value: f32) -> io::Result<()> {
+        self.write_bytes(&value.to_le_bytes())
+    }
+
+    /// Writes a fixed size Shift-JIS string field of `len` bytes, padded with
+    /// NULs. Text that does not fit is cut at the last whole character, so
+    /// that no double-byte character is split; characters Shift-JIS lacks
+    /// become `?`.
+    pub fn write_shift_jis(&mut self, text: &str, len: usize) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(len);
+        let mut buffer = [0; 4];
+        for c in text.chars() {
+            let (encoded, _, unmappable) =
+                encoding_rs::SHIFT_JIS.encode(c.encode_utf8(&mut buffer));
+            let encoded: &[u8] = if unmappable { b"?" } else { &encoded };
+            if bytes.len() + encoded.len() > len {
+                break;
+            }
+            bytes.extend_from_slice(encoded);
+        }
+        bytes.resize(len, 0);
+        self.write_bytes(&bytes)
+    }
+
+    pub fn write_vec3(&mut self, value: Vector3<f32>) -> io::Result<()> {
+        self.write_f32(value.x)?;
+        self.write_f32(value.y)?;
+        self.write_f32(value.z)
+    }
+
+    pub fn write_vec4(&mut self, value: Vector4<f32>) -> io::Result<()> {
+        self.write_f32(value.x)?;
+        self.write_f32(value.y)?;
+        self.write_f32(value.z)?;
+        self.write_f32(value.w)
+    }
+}
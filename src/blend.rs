@@ -0,0 +1,476 @@
+//! Several motions on one model at once.
+//!
+//! Each [`Clip`] is a motion with a weight, which can fade over time. Clips
+//! apply in order: a clip replaces what the clips before it did to the bones
+//! and morphs it has tracks for, in proportion to its weight, or adds to it
+//! when additive. A bone mask keeps a clip to part of the skeleton, such as a
+//! facial motion to the head. Bones and morphs that no clip has a track for
+//! keep their pose.
+
+use cgmath::prelude::*;
+use cgmath::Quaternion;
+use std::collections::BTreeSet;
+
+use crate::interpolation::{bone_pose, morph_weight, slerp};
+use crate::morph::Morphs;
+use crate::skeleton::{BonePose, Skeleton};
+use crate::vmd::{BoneKeyframe, IkKeyframe, MorphKeyframe, Motion};
+
+/// The head bone of standard MMD models, which facial motions move.
+const HEAD: &str = "頭";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Moves towards the clip's pose by the clip's weight.
+    Override,
+    /// Adds the clip's pose, scaled by the clip's weight, to the pose so far.
+    Additive,
+}
+
+/// The bones a clip may move.
+#[derive(Debug, Clone)]
+pub struct BoneMask {
+    bones: Vec<bool>,
+}
+
+impl BoneMask {
+    /// `root` and every bone below it.
+    pub fn subtree(skeleton: &Skeleton, root: usize) -> Self {
+        let mut bones = vec![false; skeleton.bones.len()];
+        let mut stack = vec![root];
+        while let Some(bone) = stack.pop() {
+            bones[bone] = true;
+            stack.extend(skeleton.bones[bone].children.iter().copied());
+        }
+        Self { bones }
+    }
+
+    /// The head and the bones below it; `None` if the model has no head bone.
+    pub fn face(skeleton: &Skeleton) -> Option<Self> {
+        skeleton
+            .bone_index(HEAD)
+            .map(|head| Self::subtree(skeleton, head))
+    }
+
+    pub fn contains(&self, bone: usize) -> bool {
+        self.bones.get(bone).copied().unwrap_or(false)
+    }
+}
+
+/// A weight changing over time.
+#[derive(Debug, Clone, Copy)]
+struct Fade {
+    from: f32,
+    to: f32,
+    /// Seconds from `from` to `to`.
+    duration: f32,
+    elapsed: f32,
+    /// Whether the clip is done with once the fade ends.
+    remove: bool,
+}
+
+/// A motion playing on a model.
+pub struct Clip {
+    /// Where the motion came from, for messages.
+    pub name: String,
+    pub motion: Motion,
+    pub mode: BlendMode,
+    pub mask: Option<BoneMask>,
+    /// The clock frame at which the motion's frame 0 plays.
+    pub start: f32,
+    weight: f32,
+    fade: Option<Fade>,
+}
+
+impl Clip {
+    /// `motion` at full weight on the whole skeleton, from frame 0.
+    pub fn new<S: Into<String>>(name: S, motion: Motion) -> Self {
+        Self {
+            name: name.into(),
+            motion,
+            mode: BlendMode::Override,
+            mask: None,
+            start: 0.0,
+            weight: 1.0,
+            fade: None,
+        }
+    }
+
+    /// The weight at this point of any fade.
+    pub fn weight(&self) -> f32 {
+        self.weight
+    }
+
+    /// The weight the clip is fading to, or is at.
+    pub fn target_weight(&self) -> f32 {
+        self.fade.map_or(self.weight, |fade| fade.to)
+    }
+
+    /// Sets the weight at once, ending any fade.
+    pub fn set_weight(&mut self, weight: f32) {
+        self.weight = weight;
+        self.fade = None;
+    }
+
+    /// Moves the weight to `weight` evenly over `seconds`.
+    pub fn fade_to(&mut self, weight: f32, seconds: f32) {
+        self.fade = Some(Fade {
+            from: self.weight,
+            to: weight,
+            duration: seconds,
+            elapsed: 0.0,
+            remove: false,
+        });
+        self.advance(0.0);
+    }
+
+    /// Fades the weight to 0 over `seconds`, after which [`Clip::finished`]
+    /// holds.
+    pub fn fade_out(&mut self, seconds: f32) {
+        self.fade_to(0.0, seconds);
+        if let Some(fade) = &mut self.fade {
+            fade.remove = true;
+        }
+    }
+
+    /// Whether the clip has faded out and can be dropped.
+    pub fn finished(&self) -> bool {
+        self.fade
+            .is_some_and(|fade| fade.remove && fade.elapsed >= fade.duration)
+    }
+
+    /// The last clock frame the clip has a keyframe on.
+    pub fn end(&self) -> f32 {
+        self.start + self.motion.last_frame() as f32
+    }
+
+    /// Moves any fade on by `elapsed` seconds.
+    pub fn advance(&mut self, elapsed: f32) {
+        if let Some(fade) = &mut self.fade {
+            fade.elapsed += elapsed;
+            let t = if fade.duration > 0.0 {
+                (fade.elapsed / fade.duration).min(1.0)
+            } else {
+                1.0
+            };
+            self.weight = fade.from + (fade.to - fade.from) * t;
+            if t >= 1.0 && !fade.remove {
+                self.fade = None;
+            }
+        }
+    }
+
+    fn allows(&self, bone: usize) -> bool {
+        self.mask.as_ref().is_none_or(|mask| mask.contains(bone))
+    }
+}
+
+/// Poses `skeleton` and sets `morphs` as `clips` have them at clock frame
+/// `frame`. Bones and morphs are blended from the rest pose and weight 0, so
+/// a single clip below full weight moves only part of the way. IK bones are
+/// switched by the last clip at half weight or more that keys them.
+pub fn apply(clips: &[Clip], frame: f32, skeleton: &mut Skeleton, morphs: &mut Morphs) {
+    let mut poses: Vec<Option<BonePose>> = vec![None; skeleton.bones.len()];
+    let mut weights: Vec<Option<f32>> = vec![None; morphs.weights.len()];
+    for clip in clips {
+        let weight = clip.weight();
+        if weight <= 0.0 {
+            continue;
+        }
+        let frame = frame - clip.start;
+
+        for (name, track) in &clip.motion.bones {
+            let bone = match skeleton.bone_index(name) {
+                Some(bone) if clip.allows(bone) => bone,
+                _ => continue,
+            };
+            let sample = bone_pose(track, frame);
+            let pose = poses[bone].get_or_insert_with(BonePose::default);
+            *pose = match clip.mode {
+                BlendMode::Override => blend_pose(pose, &sample, weight),
+                BlendMode::Additive => add_pose(pose, &sample, weight),
+            };
+        }
+
+        for (name, track) in &clip.motion.morphs {
+            let morph = match morphs.morph_index(name) {
+                Some(morph) => morph,
+                None => continue,
+            };
+            let sample = morph_weight(track, frame);
+            let value = weights[morph].get_or_insert(0.0);
+            *value = match clip.mode {
+                BlendMode::Override => *value + (sample - *value) * weight,
+                BlendMode::Additive => *value + sample * weight,
+            };
+        }
+
+        if weight >= 0.5 {
+            for (name, enabled) in clip
+                .motion
+                .ik_key(frame)
+                .into_iter()
+                .flat_map(|key| &key.ik)
+            {
+                match skeleton.bone_index(name) {
+                    Some(bone) if clip.allows(bone) => skeleton.ik_enabled[bone] = *enabled,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    for (bone, pose) in poses.into_iter().enumerate() {
+        if let Some(pose) = pose {
+            skeleton.pose[bone] = pose;
+        }
+    }
+    for (morph, weight) in weights.into_iter().enumerate() {
+        if let Some(weight) = weight {
+            morphs.weights[morph] = weight;
+        }
+    }
+}
+
+/// `clips` as one motion with a keyframe on every frame up to the end of the
+/// last clip, for the bones, morphs and IK bones they have tracks for. Camera
+/// and light tracks are left out. Poses `skeleton` and `morphs` frame by frame
+/// and puts them back as they were.
+pub fn bake(clips: &[Clip], skeleton: &mut Skeleton, morphs: &mut Morphs) -> Motion {
+    let bones = clips
+        .iter()
+        .flat_map(|clip| {
+            let bones = clip
+                .motion
+                .bones
+                .keys()
+                .filter_map(|name| skeleton.bone_index(name));
+            bones.filter(move |&bone| clip.allows(bone))
+        })
+        .collect::<BTreeSet<_>>();
+    let morph_indices = clips
+        .iter()
+        .flat_map(|clip| {
+            clip.motion
+                .morphs
+                .keys()
+                .filter_map(|name| morphs.morph_index(name))
+        })
+        .collect::<BTreeSet<_>>();
+    let ik_bones = clips
+        .iter()
+        .flat_map(|clip| clip.motion.ik.iter().flat_map(|key| &key.ik))
+        .filter_map(|(name, _)| skeleton.bone_index(name))
+        .collect::<BTreeSet<_>>();
+    let end = clips.iter().map(Clip::end).fold(0.0, f32::max).ceil() as u32;
+
+    let pose = skeleton.pose.clone();
+    let ik_enabled = skeleton.ik_enabled.clone();
+    let weights = morphs.weights.clone();
+    let mut motion = Motion::default();
+    for frame in 0..=end {
+        apply(clips, frame as f32, skeleton, morphs);
+        for &bone in &bones {
+            let pose = skeleton.pose[bone];
+            let track = motion
+                .bones
+                .entry(skeleton.bones[bone].name.clone())
+                .or_default();
+            track.push(BoneKeyframe {
+                frame,
+                translation: pose.translation,
+                rotation: pose.rotation,
+                interpolation: Default::default(),
+                physics_disabled: false,
+            });
+        }
+        for &morph in &morph_indices {
+            let track = motion
+                .morphs
+                .entry(morphs.morphs[morph].name.clone())
+                .or_default();
+            track.push(MorphKeyframe {
+                frame,
+                weight: morphs.weights[morph],
+            });
+        }
+        let ik = ik_bones
+            .iter()
+            .map(|&bone| (skeleton.bones[bone].name.clone(), skeleton.ik_enabled[bone]))
+            .collect::<Vec<_>>();
+        if !ik.is_empty() && motion.ik.last().is_none_or(|key| key.ik != ik) {
+            motion.ik.push(IkKeyframe {
+                frame,
+                visible: true,
+                ik,
+            });
+        }
+    }
+    skeleton.pose = pose;
+    skeleton.ik_enabled = ik_enabled;
+    morphs.weights = weights;
+    motion
+}
+
+/// `a` moved towards `b` by `t`.
+fn blend_pose(a: &BonePose, b: &BonePose, t: f32) -> BonePose {
+    BonePose {
+        translation: a.translation.lerp(b.translation, t),
+        rotation: slerp(a.rotation, b.rotation, t),
+        scale: a.scale.lerp(b.scale, t),
+    }
+}
+
+/// `b`, taken as a change from the rest pose, added to `a` at `t`.
+fn add_pose(a: &BonePose, b: &BonePose, t: f32) -> BonePose {
+    BonePose {
+        translation: a.translation + b.translation * t,
+        rotation: a.rotation * slerp(Quaternion::one(), b.rotation, t),
+        scale: a.scale,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Vector3};
+
+    use crate::pmx::{self, PmxModel};
+
+    /// センター, then 首 and 頭 below it, and two morphs.
+    fn model() -> (Skeleton, Morphs) {
+        let mut model = PmxModel::empty();
+        model.bones = vec![
+            pmx::Bone::new("センター", Vector3::new(0.0, 8.0, 0.0), -1),
+            pmx::Bone::new("首", Vector3::new(0.0, 15.0, 0.0), 0),
+            pmx::Bone::new(HEAD, Vector3::new(0.0, 16.0, 0.0), 1),
+        ];
+        for name in &["あ", "まばたき"] {
+            model.morphs.push(pmx::Morph {
+                name: name.to_string(),
+                name_en: String::new(),
+                panel: 3,
+                offsets: pmx::MorphOffsets::Vertex(Vec::new()),
+            });
+        }
+        let skeleton = Skeleton::from_pmx(&model);
+        let morphs = Morphs::new(&model, Vec::new());
+        (skeleton, morphs)
+    }
+
+    /// A clip holding each bone and morph at the same value on frames 0 and
+    /// 10.
+    fn clip(bones: &[(&str, Vector3<f32>, Quaternion<f32>)], morphs: &[(&str, f32)]) -> Clip {
+        let mut motion = Motion::default();
+        for &(name, translation, rotation) in bones {
+            let keys = [0, 10].map(|frame| BoneKeyframe {
+                frame,
+                translation,
+                rotation,
+                interpolation: Default::default(),
+                physics_disabled: false,
+            });
+            motion.bones.insert(name.to_string(), keys.to_vec());
+        }
+        for &(name, weight) in morphs {
+            let keys = [0, 10].map(|frame| MorphKeyframe { frame, weight });
+            motion.morphs.insert(name.to_string(), keys.to_vec());
+        }
+        Clip::new("clip", motion)
+    }
+
+    fn assert_rotation(actual: Quaternion<f32>, expected: Quaternion<f32>) {
+        assert!(
+            actual.dot(expected).abs() > 1.0 - 1e-6,
+            "{:?} is not {:?}",
+            actual,
+            expected
+        );
+    }
+
+    /// A full body clip, a face clip added at half weight on the head only
+    /// and a quarter of a neck turn.
+    fn clips(skeleton: &Skeleton) -> Vec<Clip> {
+        let body = clip(
+            &[
+                ("センター", Vector3::new(1.0, 0.0, 0.0), Quaternion::one()),
+                (HEAD, Vector3::zero(), Quaternion::from_angle_x(Deg(20.0))),
+            ],
+            &[("あ", 1.0)],
+        );
+        let mut face = clip(
+            &[
+                ("センター", Vector3::new(0.0, 4.0, 0.0), Quaternion::one()),
+                (HEAD, Vector3::zero(), Quaternion::from_angle_y(Deg(40.0))),
+            ],
+            &[("あ", 0.4), ("まばたき", 1.0)],
+        );
+        face.mode = BlendMode::Additive;
+        face.mask = BoneMask::face(skeleton);
+        face.set_weight(0.5);
+        let mut lean = clip(
+            &[("首", Vector3::zero(), Quaternion::from_angle_z(Deg(40.0)))],
+            &[("あ", 0.0)],
+        );
+        lean.set_weight(0.25);
+        vec![body, face, lean]
+    }
+
+    #[test]
+    fn masked_additive_clip_over_a_full_body_clip() {
+        let (mut skeleton, mut morphs) = model();
+        let clips = clips(&skeleton);
+        apply(&clips, 5.0, &mut skeleton, &mut morphs);
+
+        // The face clip cannot move the centre
+        assert_eq!(skeleton.pose[0].translation, Vector3::new(1.0, 0.0, 0.0));
+        // Half of its turn goes on top of the body's
+        let head = Quaternion::from_angle_x(Deg(20.0)) * Quaternion::from_angle_y(Deg(20.0));
+        assert_rotation(skeleton.pose[2].rotation, head);
+        // A quarter of the way from the rest pose
+        assert_rotation(
+            skeleton.pose[1].rotation,
+            Quaternion::from_angle_z(Deg(10.0)),
+        );
+
+        // 1 plus half of 0.4, then a quarter of the way to 0
+        assert!(
+            (morphs.weights[0] - 0.9).abs() < 1e-6,
+            "{:?}",
+            morphs.weights
+        );
+        assert!(
+            (morphs.weights[1] - 0.5).abs() < 1e-6,
+            "{:?}",
+            morphs.weights
+        );
+    }
+
+    #[test]
+    fn bake_keys_every_frame_and_restores_the_pose() {
+        let (mut skeleton, mut morphs) = model();
+        let clips = clips(&skeleton);
+        skeleton.pose[0].translation = Vector3::new(0.0, 0.0, 7.0);
+        morphs.weights[1] = 0.75;
+        let motion = bake(&clips, &mut skeleton, &mut morphs);
+
+        assert_eq!(skeleton.pose[0].translation, Vector3::new(0.0, 0.0, 7.0));
+        assert_eq!(morphs.weights, [0.0, 0.75]);
+
+        let mut names = motion.bones.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["センター", "頭", "首"]);
+        for track in motion.bones.values() {
+            let frames = track.iter().map(|key| key.frame).collect::<Vec<_>>();
+            assert_eq!(frames, (0..=10).collect::<Vec<_>>());
+        }
+        let center = &motion.bones["センター"][5];
+        assert_eq!(center.translation, Vector3::new(1.0, 0.0, 0.0));
+        let head = Quaternion::from_angle_x(Deg(20.0)) * Quaternion::from_angle_y(Deg(20.0));
+        assert_rotation(motion.bones[HEAD][5].rotation, head);
+        let blink = &motion.morphs["まばたき"];
+        assert_eq!(blink.len(), 11);
+        assert!((blink[5].weight - 0.5).abs() < 1e-6);
+        assert!(motion.ik.is_empty());
+    }
+}
@@ -4,6 +4,8 @@ use std::time::Duration;
 use winit::dpi::PhysicalPosition;
 use winit::event::*;
 
+use crate::interpolation::CameraPose;
+
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
@@ -44,6 +46,22 @@ impl Camera {
             Vector3::unit_y(),
         )
     }
+
+    /// Moves the camera where an MMD camera keyframe has it, for a model drawn
+    /// from `origin` with MMD's left-handed z mirrored. MMD turns the camera
+    /// about y, then x, then z, and keeps it `distance` along its z from the
+    /// target, looking down z. The viewer's camera stays upright, so the turn
+    /// about z is left out.
+    pub fn set_mmd_pose(&mut self, pose: &CameraPose, origin: Point3<f32>) {
+        let rotation = Matrix3::from_angle_y(Rad(pose.rotation.y))
+            * Matrix3::from_angle_x(Rad(pose.rotation.x));
+        let mirror = |v: Vector3<f32>| Vector3::new(v.x, v.y, -v.z);
+        let target = origin + mirror(pose.target);
+        let forward = mirror(rotation * Vector3::unit_z());
+        self.position = target + mirror(rotation * Vector3::new(0.0, 0.0, pose.distance));
+        self.yaw = Rad(forward.z.atan2(forward.x));
+        self.pitch = Rad(forward.y.asin().clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2));
+    }
 }
 
 pub struct Projection {
@@ -67,6 +85,10 @@ impl Projection {
         self.aspect = width as f32 / height as f32;
     }
 
+    pub fn set_fovy<F: Into<Rad<f32>>>(&mut self, fovy: F) {
+        self.fovy = fovy.into();
+    }
+
     pub fn calc_matrix(&self) -> Matrix4<f32> {
         OPENGL_TO_WGPU_MATRIX * perspective(self.fovy, self.aspect, self.znear, self.zfar)
     }
@@ -0,0 +1,565 @@
+use cgmath::prelude::*;
+use cgmath::{Vector2, Vector3};
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use crate::model::{Bounds, ModelVertex};
+
+/// How texture coordinates are made up for meshes that have none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UvProjection {
+    /// Every vertex samples the corner of the texture.
+    #[default]
+    None,
+    /// Projects along the axis in which the mesh is thinnest.
+    Planar,
+    /// Longitude and latitude around the centre of the mesh.
+    Spherical,
+}
+
+impl FromStr for UvProjection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "planar" => Ok(Self::Planar),
+            "spherical" => Ok(Self::Spherical),
+            _ => anyhow::bail!(
+                "unknown UV projection {:?}, expected none, planar or spherical",
+                s
+            ),
+        }
+    }
+}
+
+/// Overwrites the texture coordinates of `vertices` with `projection`.
+pub fn project_uvs(vertices: &mut [ModelVertex], projection: UvProjection) {
+    let bounds = Bounds::from_vertices(vertices);
+    let size = [
+        bounds.max[0] - bounds.min[0],
+        bounds.max[1] - bounds.min[1],
+        bounds.max[2] - bounds.min[2],
+    ];
+
+    match projection {
+        UvProjection::None => {
+            for vertex in vertices {
+                vertex.tex_coords = [0.0; 2];
+            }
+        }
+        UvProjection::Planar => {
+            // Keep the two largest axes, u along the larger one
+            let mut axes = [0, 1, 2];
+            axes.sort_by(|&a, &b| size[b].total_cmp(&size[a]));
+            let (u_axis, v_axis) = (axes[0], axes[1]);
+
+            for vertex in vertices {
+                vertex.tex_coords = [
+                    normalized(vertex.position[u_axis], bounds.min[u_axis], size[u_axis]),
+                    normalized(vertex.position[v_axis], bounds.min[v_axis], size[v_axis]),
+                ];
+            }
+        }
+        UvProjection::Spherical => {
+            let center = [
+                bounds.min[0] + size[0] * 0.5,
+                bounds.min[1] + size[1] * 0.5,
+                bounds.min[2] + size[2] * 0.5,
+            ];
+
+            for vertex in vertices {
+                let x = vertex.position[0] - center[0];
+                let y = vertex.position[1] - center[1];
+                let z = vertex.position[2] - center[2];
+                let length = (x * x + y * y + z * z).sqrt();
+                vertex.tex_coords = if length > f32::EPSILON {
+                    [
+                        0.5 + z.atan2(x) / (2.0 * PI),
+                        0.5 + (y / length).clamp(-1.0, 1.0).asin() / PI,
+                    ]
+                } else {
+                    [0.5, 0.5]
+                };
+            }
+        }
+    }
+}
+
+fn normalized(value: f32, min: f32, size: f32) -> f32 {
+    if size > f32::EPSILON {
+        (value - min) / size
+    } else {
+        0.5
+    }
+}
+
+/// Smoothing group of faces that are never smoothed, as in OBJ `s off`.
+pub const FLAT_GROUP: u32 = 0;
+
+/// Computes vertex normals for an indexed triangle list.
+///
+/// Every corner gets the angle-weighted average of the normals of the faces
+/// around its position that share its smoothing group and meet it at less
+/// than `crease_angle` (in degrees). Faces in [`FLAT_GROUP`] keep their face
+/// normal. `smoothing_groups` holds one group per triangle; `None` smooths
+/// everything by angle alone. Vertices whose corners end up with different
+/// normals are split, so the returned mesh may have more vertices.
+pub fn generate_normals(
+    vertices: &[ModelVertex],
+    indices: &[u32],
+    smoothing_groups: Option<&[u32]>,
+    crease_angle: f32,
+) -> (Vec<ModelVertex>, Vec<u32>) {
+    let triangle_count = indices.len() / 3;
+    let group_of = |triangle: usize| {
+        smoothing_groups
+            .and_then(|groups| groups.get(triangle).copied())
+            .unwrap_or(1)
+    };
+    let position = |triangle: usize, corner: usize| {
+        Vector3::from(vertices[indices[triangle * 3 + corner] as usize].position)
+    };
+
+    // Unit face normal and the interior angle at each corner
+    let mut face_normals = Vec::with_capacity(triangle_count);
+    let mut corner_angles = Vec::with_capacity(triangle_count);
+    for triangle in 0..triangle_count {
+        let p = [
+            position(triangle, 0),
+            position(triangle, 1),
+            position(triangle, 2),
+        ];
+        let normal = (p[1] - p[0]).cross(p[2] - p[0]);
+        face_normals.push(if normal.magnitude2() > 0.0 {
+            normal.normalize()
+        } else {
+            Vector3::zero()
+        });
+
+        let mut angles = [0.0; 3];
+        for (corner, angle) in angles.iter_mut().enumerate() {
+            let to_next = p[(corner + 1) % 3] - p[corner];
+            let to_prev = p[(corner + 2) % 3] - p[corner];
+            if to_next.magnitude2() > 0.0 && to_prev.magnitude2() > 0.0 {
+                *angle = to_next.angle(to_prev).0;
+            }
+        }
+        corner_angles.push(angles);
+    }
+
+    // Corners that touch the same position, regardless of UV seams
+    let mut corners_at = HashMap::<[u32; 3], Vec<(usize, usize)>>::new();
+    for triangle in 0..triangle_count {
+        for corner in 0..3 {
+            let key = position_key(position(triangle, corner));
+            corners_at.entry(key).or_default().push((triangle, corner));
+        }
+    }
+
+    let min_cos = crease_angle.to_radians().cos();
+    let mut out_vertices = Vec::with_capacity(vertices.len());
+    let mut out_indices = Vec::with_capacity(indices.len());
+    let mut split = HashMap::<(u32, [u32; 3]), u32>::new();
+
+    for triangle in 0..triangle_count {
+        let face_normal = face_normals[triangle];
+        let group = group_of(triangle);
+
+        for corner in 0..3 {
+            let mut normal = face_normal;
+            if group != FLAT_GROUP {
+                let mut sum = Vector3::zero();
+                for &(other, other_corner) in &corners_at[&position_key(position(triangle, corner))]
+                {
+                    let other_normal = face_normals[other];
+                    if group_of(other) == group && face_normal.dot(other_normal) >= min_cos {
+                        sum += other_normal * corner_angles[other][other_corner];
+                    }
+                }
+                if sum.magnitude2() > 0.0 {
+                    normal = sum.normalize();
+                }
+            }
+
+            let index = indices[triangle * 3 + corner];
+            let normal: [f32; 3] = normal.into();
+            let new_index = *split
+                .entry((index, position_key(normal.into())))
+                .or_insert_with(|| {
+                    let mut vertex = vertices[index as usize];
+                    vertex.normal = normal;
+                    out_vertices.push(vertex);
+                    out_vertices.len() as u32 - 1
+                });
+            out_indices.push(new_index);
+        }
+    }
+
+    (out_vertices, out_indices)
+}
+
+fn position_key(position: Vector3<f32>) -> [u32; 3] {
+    // Treat -0.0 and 0.0 as the same position
+    [
+        (position.x + 0.0).to_bits(),
+        (position.y + 0.0).to_bits(),
+        (position.z + 0.0).to_bits(),
+    ]
+}
+
+/// Fills in the tangent of every vertex, following MikkTSpace: the tangent
+/// and bitangent of every face, from its texture coordinate derivatives, are
+/// made orthogonal to the normal of each corner and normalized, then summed
+/// weighted by corner angle. `w` holds the sign of the bitangent,
+/// `bitangent = w * cross(normal, tangent)`.
+///
+/// Vertices are expected to be split at UV seams already, which `single_index`
+/// OBJ import and PMX/PMD vertices are.
+pub fn generate_tangents(vertices: &mut [ModelVertex], indices: &[u32]) {
+    let mut tangents = vec![Vector3::zero(); vertices.len()];
+    let mut bitangents = vec![Vector3::zero(); vertices.len()];
+
+    for triangle in indices.chunks_exact(3) {
+        let corners = [
+            triangle[0] as usize,
+            triangle[1] as usize,
+            triangle[2] as usize,
+        ];
+        let p = corners.map(|i| Vector3::from(vertices[i].position));
+        let uv = corners.map(|i| Vector2::from(vertices[i].tex_coords));
+
+        let edge1 = p[1] - p[0];
+        let edge2 = p[2] - p[0];
+        let duv1 = uv[1] - uv[0];
+        let duv2 = uv[2] - uv[0];
+        let det = duv1.x * duv2.y - duv2.x * duv1.y;
+        if det == 0.0 {
+            continue;
+        }
+        // Only the directions are used, so the 1 / det scale is left out
+        // but for its sign, which mirrored UVs flip
+        let sign = det.signum();
+        let tangent = (edge1 * duv2.y - edge2 * duv1.y) * sign;
+        let bitangent = (edge2 * duv1.x - edge1 * duv2.x) * sign;
+
+        for (corner, &index) in corners.iter().enumerate() {
+            let to_next = p[(corner + 1) % 3] - p[corner];
+            let to_prev = p[(corner + 2) % 3] - p[corner];
+            if to_next.magnitude2() == 0.0 || to_prev.magnitude2() == 0.0 {
+                continue;
+            }
+            let weight = to_next.angle(to_prev).0;
+            let normal = Vector3::from(vertices[index].normal);
+            tangents[index] += orthonormal(tangent, normal) * weight;
+            bitangents[index] += orthonormal(bitangent, normal) * weight;
+        }
+    }
+
+    for ((vertex, tangent), bitangent) in vertices.iter_mut().zip(tangents).zip(bitangents) {
+        let normal = Vector3::from(vertex.normal);
+        // A sum of unit vectors, so this does not depend on the mesh scale
+        let mut tangent = tangent - normal * normal.dot(tangent);
+        if tangent.magnitude2() <= f32::EPSILON {
+            tangent = any_perpendicular(normal);
+        }
+        let tangent = tangent.normalize();
+        let sign = if normal.cross(tangent).dot(bitangent) < 0.0 {
+            -1.0
+        } else {
+            1.0
+        };
+        vertex.tangent = [tangent.x, tangent.y, tangent.z, sign];
+    }
+}
+
+/// `vector` without its component along `normal`, normalized, or zero if
+/// nothing is left.
+fn orthonormal(vector: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+    let vector = vector - normal * normal.dot(vector);
+    if vector.magnitude2() > 0.0 {
+        vector.normalize()
+    } else {
+        Vector3::zero()
+    }
+}
+
+/// A unit vector perpendicular to `normal`, for vertices without usable UVs.
+fn any_perpendicular(normal: Vector3<f32>) -> Vector3<f32> {
+    let axis = if normal.x.abs() < 0.9 {
+        Vector3::unit_x()
+    } else {
+        Vector3::unit_y()
+    };
+    let tangent = axis - normal * normal.dot(axis);
+    if tangent.magnitude2() > 0.0 {
+        tangent
+    } else {
+        Vector3::unit_x()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(position: [f32; 3]) -> ModelVertex {
+        ModelVertex {
+            position,
+            ..Default::default()
+        }
+    }
+
+    fn assert_near(actual: Vector3<f32>, expected: Vector3<f32>) {
+        assert!(
+            (actual - expected).magnitude() < 1e-5,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    /// A cube of side 2 with one vertex per corner, faces in the order -x,
+    /// +x, -y, +y, -z, +z, each as two triangles wound outwards.
+    fn cube() -> (Vec<ModelVertex>, Vec<u32>) {
+        let vertices = (0..8)
+            .map(|i| {
+                let coordinate = |bit| if i & bit == 0 { -1.0 } else { 1.0 };
+                vertex([coordinate(1), coordinate(2), coordinate(4)])
+            })
+            .collect::<Vec<_>>();
+        let mut indices = Vec::new();
+        for axis in 0..3 {
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            for side in 0..2 {
+                let corner =
+                    |u_side: u32, v_side: u32| (side << axis) | (u_side << u) | (v_side << v);
+                let mut quad = [corner(0, 0), corner(1, 0), corner(1, 1), corner(0, 1)];
+                if side == 0 {
+                    quad.reverse();
+                }
+                indices.extend_from_slice(&[quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+            }
+        }
+        (vertices, indices)
+    }
+
+    fn corner_normal(vertices: &[ModelVertex], indices: &[u32], index: usize) -> Vector3<f32> {
+        Vector3::from(vertices[indices[index] as usize].normal)
+    }
+
+    /// The normals at every corner that sits at `position`, by triangle.
+    fn normals_at(
+        vertices: &[ModelVertex],
+        indices: &[u32],
+        position: [f32; 3],
+    ) -> Vec<(usize, Vector3<f32>)> {
+        (0..indices.len())
+            .filter(|&i| vertices[indices[i] as usize].position == position)
+            .map(|i| (i / 3, corner_normal(vertices, indices, i)))
+            .collect()
+    }
+
+    fn face_normal(triangle: usize) -> Vector3<f32> {
+        let face = triangle / 2;
+        let mut normal = Vector3::zero();
+        normal[face / 2] = [-1.0, 1.0][face % 2];
+        normal
+    }
+
+    #[test]
+    fn cube_winding_faces_outwards() {
+        let (vertices, indices) = cube();
+        for (triangle, corners) in indices.chunks_exact(3).enumerate() {
+            let p = [0, 1, 2].map(|c| Vector3::from(vertices[corners[c] as usize].position));
+            let normal = (p[1] - p[0]).cross(p[2] - p[0]).normalize();
+            assert_near(normal, face_normal(triangle));
+        }
+    }
+
+    #[test]
+    fn default_crease_splits_cube_corners() {
+        let (vertices, indices) = cube();
+        let crease_angle = crate::obj::ImportOptions::default().crease_angle;
+        let (vertices, indices) = generate_normals(&vertices, &indices, None, crease_angle);
+
+        // Three faces meet at every corner, at 90 degrees to each other
+        assert_eq!(vertices.len(), 24);
+        assert_eq!(indices.len(), 36);
+        for i in 0..indices.len() {
+            assert_near(corner_normal(&vertices, &indices, i), face_normal(i / 3));
+        }
+    }
+
+    #[test]
+    fn crease_above_right_angle_smooths_cube_corners() {
+        let (vertices, indices) = cube();
+        let (vertices, _) = generate_normals(&vertices, &indices, None, 100.0);
+
+        assert_eq!(vertices.len(), 8);
+        for vertex in &vertices {
+            // The diagonal of a face counts twice at its ends, but with half
+            // the angle each time, so every face still weighs the same
+            let position = Vector3::from(vertex.position);
+            assert_near(Vector3::from(vertex.normal), position.normalize());
+        }
+    }
+
+    #[test]
+    fn smoothing_groups_are_kept_apart() {
+        let (vertices, indices) = cube();
+        // +x and +y share a group, every other face has its own
+        let groups = [3, 3, 1, 1, 4, 4, 1, 1, 5, 5, 6, 6];
+        let (vertices, indices) = generate_normals(&vertices, &indices, Some(&groups), 180.0);
+
+        let edge = Vector3::new(1.0, 1.0, 0.0).normalize();
+        for (triangle, normal) in normals_at(&vertices, &indices, [1.0, 1.0, 1.0]) {
+            let expected = match triangle / 2 {
+                1 | 3 => edge,
+                _ => face_normal(triangle),
+            };
+            assert_near(normal, expected);
+        }
+    }
+
+    #[test]
+    fn flat_group_keeps_face_normals() {
+        let (vertices, indices) = cube();
+        let mut groups = [1; 12];
+        groups[6] = FLAT_GROUP;
+        groups[7] = FLAT_GROUP;
+        let (vertices, indices) = generate_normals(&vertices, &indices, Some(&groups), 180.0);
+
+        // The flat +y face is left out of its neighbours' normals too
+        let edge = Vector3::new(1.0, 0.0, 1.0).normalize();
+        for (triangle, normal) in normals_at(&vertices, &indices, [1.0, 1.0, 1.0]) {
+            let expected = match triangle / 2 {
+                3 => face_normal(triangle),
+                _ => edge,
+            };
+            assert_near(normal, expected);
+        }
+    }
+
+    #[test]
+    fn fan_is_weighted_by_corner_angle() {
+        let vertices = [
+            vertex([0.0, 0.0, 0.0]),
+            vertex([1.0, 0.0, 0.0]),
+            vertex([0.0, 1.0, 0.0]),
+            vertex([0.0, 1.0, 1.0]),
+        ];
+        // 90 degrees at the apex facing +z, 45 degrees facing +x
+        let indices = [0, 1, 2, 0, 2, 3];
+        let (vertices, indices) = generate_normals(&vertices, &indices, None, 180.0);
+
+        let apex = corner_normal(&vertices, &indices, 0);
+        assert_eq!(indices[3], indices[0]);
+        assert_near(apex, Vector3::new(1.0, 0.0, 2.0).normalize());
+    }
+
+    fn textured(position: [f32; 3], tex_coords: [f32; 2], normal: [f32; 3]) -> ModelVertex {
+        ModelVertex {
+            position,
+            tex_coords,
+            normal,
+            ..Default::default()
+        }
+    }
+
+    fn tangent_of(vertex: &ModelVertex) -> (Vector3<f32>, f32) {
+        let [x, y, z, w] = vertex.tangent;
+        (Vector3::new(x, y, z), w)
+    }
+
+    /// Two triangles folded along their shared edge, with UVs from x and y
+    /// and a tilted normal shared by all vertices, scaled by `scale`.
+    fn folded_strip(scale: f32) -> (Vec<ModelVertex>, Vec<u32>) {
+        let normal = Vector3::new(0.2, -0.3, 1.0).normalize().into();
+        let vertices = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [1.0, 1.0, 1.0],
+        ]
+        .iter()
+        .map(|&[x, y, z]| textured([x * scale, y * scale, z * scale], [x, y], normal))
+        .collect();
+        (vertices, vec![0, 1, 2, 1, 3, 2])
+    }
+
+    #[test]
+    fn tangents_are_unit_and_orthogonal_to_the_normal() {
+        let (mut vertices, indices) = folded_strip(1.0);
+        generate_tangents(&mut vertices, &indices);
+
+        for vertex in &vertices {
+            let (tangent, w) = tangent_of(vertex);
+            assert!((tangent.magnitude() - 1.0).abs() < 1e-5, "{:?}", tangent);
+            assert!(tangent.dot(Vector3::from(vertex.normal)).abs() < 1e-5);
+            assert_eq!(w, 1.0);
+        }
+    }
+
+    #[test]
+    fn tangents_do_not_depend_on_scale() {
+        let (mut expected, indices) = folded_strip(1.0);
+        generate_tangents(&mut expected, &indices);
+
+        for &scale in &[1e-4, 1e3] {
+            let (mut vertices, _) = folded_strip(scale);
+            generate_tangents(&mut vertices, &indices);
+            for (vertex, expected) in vertices.iter().zip(&expected) {
+                let (tangent, w) = tangent_of(vertex);
+                let (expected_tangent, expected_w) = tangent_of(expected);
+                assert_near(tangent, expected_tangent);
+                assert_eq!(w, expected_w);
+            }
+        }
+    }
+
+    #[test]
+    fn faces_weigh_the_same_whatever_their_uv_density() {
+        let normal = [0.0, 0.0, 1.0];
+        let mut vertices = [
+            textured([0.0, 0.0, 0.0], [0.0, 0.0], normal),
+            // u along +x at one texel per unit
+            textured([1.0, 0.0, 0.0], [1.0, 0.0], normal),
+            textured([0.0, 1.0, 0.0], [0.0, 1.0], normal),
+            // u along +y at a hundred texels per unit
+            textured([0.0, -1.0, 0.0], [-100.0, 0.0], normal),
+            textured([1.0, 0.0, 0.0], [0.0, -100.0], normal),
+        ];
+        generate_tangents(&mut vertices, &[0, 1, 2, 0, 3, 4]);
+
+        // Both faces meet the shared vertex at 90 degrees
+        let (tangent, w) = tangent_of(&vertices[0]);
+        assert_near(tangent, Vector3::new(1.0, 1.0, 0.0).normalize());
+        assert_eq!(w, 1.0);
+    }
+
+    #[test]
+    fn mirrored_uvs_flip_the_bitangent_sign() {
+        let normal = [0.0, 0.0, 1.0];
+        let positions = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+        let triangle = |mirror: bool| {
+            positions.map(|[x, y, z]| {
+                let u = if mirror { 1.0 - x } else { x };
+                textured([x, y, z], [u, y], normal)
+            })
+        };
+
+        let mut vertices = triangle(false);
+        generate_tangents(&mut vertices, &[0, 1, 2]);
+        for vertex in &vertices {
+            assert_eq!(tangent_of(vertex), (Vector3::unit_x(), 1.0));
+        }
+
+        let mut vertices = triangle(true);
+        generate_tangents(&mut vertices, &[0, 1, 2]);
+        for vertex in &vertices {
+            assert_eq!(tangent_of(vertex), (-Vector3::unit_x(), -1.0));
+        }
+    }
+}
@@ -0,0 +1,316 @@
+//! CCD IK for PMX IK bones.
+//!
+//! Follows MMD: each iteration turns every link in turn, nearest the target
+//! first, by at most the chain's limit angle. Links limited to a single axis,
+//! such as knees, are turned in that axis' plane and may fold the other way
+//! on the first iteration, which is what keeps knees from bending backwards.
+
+use cgmath::prelude::*;
+use cgmath::{Matrix3, Point3, Quaternion, Rad, Vector3};
+
+use crate::skeleton::{IkChain, IkLink, Skeleton};
+
+/// Closer than this the target counts as reaching the IK bone.
+const TOLERANCE: f32 = 1e-5;
+
+/// Solves the IK of `bone`, whose world matrix has to be up to date, and
+/// updates the world matrices of its links and their descendants.
+pub fn solve(skeleton: &mut Skeleton, bone: usize) {
+    let chain = match &skeleton.bones[bone].ik {
+        Some(chain) => chain.clone(),
+        None => return,
+    };
+    let root = match chain.links.last() {
+        Some(root) => root.bone,
+        None => return,
+    };
+
+    for link in &chain.links {
+        skeleton.ik_rotation[link.bone] = Quaternion::one();
+    }
+    skeleton.update_subtree(root);
+    if !skeleton.ik_enabled[bone] {
+        return;
+    }
+
+    // Angle of each single axis link around its axis, starting from the pose
+    let mut plane_angles = chain
+        .links
+        .iter()
+        .map(|link| match link.limits.and_then(single_axis) {
+            Some(axis) => euler_angles(pose_rotation(skeleton, link.bone))[axis],
+            None => 0.0,
+        })
+        .collect::<Vec<_>>();
+
+    let goal = skeleton.world_position(bone);
+    for iteration in 0..chain.loop_count {
+        for (index, link) in chain.links.iter().enumerate() {
+            if link.bone == chain.target {
+                continue;
+            }
+            match link.limits.and_then(single_axis) {
+                Some(axis) => {
+                    let angle = &mut plane_angles[index];
+                    solve_plane(skeleton, &chain, link, goal, axis, angle, iteration);
+                }
+                None => solve_link(skeleton, &chain, link, goal),
+            }
+        }
+        if skeleton.world_position(chain.target).distance(goal) < TOLERANCE {
+            break;
+        }
+    }
+}
+
+/// Directions from `link` to the chain's target and to `goal`, in the
+/// link's space; `None` if either is too short to have a direction.
+fn link_directions(
+    skeleton: &Skeleton,
+    chain: &IkChain,
+    link: &IkLink,
+    goal: Vector3<f32>,
+) -> Option<(Vector3<f32>, Vector3<f32>)> {
+    let inverse = skeleton.world[link.bone].invert()?;
+    let to_target = inverse
+        .transform_point(Point3::from_vec(skeleton.world_position(chain.target)))
+        .to_vec();
+    let to_goal = inverse.transform_point(Point3::from_vec(goal)).to_vec();
+    if to_target.magnitude2() < TOLERANCE * TOLERANCE
+        || to_goal.magnitude2() < TOLERANCE * TOLERANCE
+    {
+        return None;
+    }
+    Some((to_target.normalize(), to_goal.normalize()))
+}
+
+/// Turns `link` towards the goal, then clamps it to its limits if it has any.
+fn solve_link(skeleton: &mut Skeleton, chain: &IkChain, link: &IkLink, goal: Vector3<f32>) {
+    let (to_target, to_goal) = match link_directions(skeleton, chain, link, goal) {
+        Some(directions) => directions,
+        None => return,
+    };
+    let mut angle = to_target.dot(to_goal).clamp(-1.0, 1.0).acos();
+    if angle < 1e-3 {
+        return;
+    }
+    if chain.limit_angle > 0.0 {
+        angle = angle.min(chain.limit_angle);
+    }
+    let axis = to_target.cross(to_goal);
+    if axis.magnitude2() < 1e-12 {
+        return;
+    }
+
+    let pose = pose_rotation(skeleton, link.bone);
+    let turn = Quaternion::from_axis_angle(axis.normalize(), Rad(angle));
+    let mut rotation = skeleton.ik_rotation[link.bone] * pose * turn;
+    if let Some((min, max)) = link.limits {
+        let angles = euler_angles(rotation);
+        rotation = from_euler_angles(Vector3::new(
+            angles.x.clamp(min.x, max.x),
+            angles.y.clamp(min.y, max.y),
+            angles.z.clamp(min.z, max.z),
+        ));
+    }
+    skeleton.ik_rotation[link.bone] = rotation * pose.invert();
+    skeleton.update_subtree(link.bone);
+}
+
+/// Turns a link limited to `axis` around it, `angle` being how far it is
+/// turned so far.
+fn solve_plane(
+    skeleton: &mut Skeleton,
+    chain: &IkChain,
+    link: &IkLink,
+    goal: Vector3<f32>,
+    axis: usize,
+    angle: &mut f32,
+    iteration: u32,
+) {
+    let (min, max) = match link.limits {
+        Some((min, max)) => (min[axis], max[axis]),
+        None => return,
+    };
+    let (to_target, to_goal) = match link_directions(skeleton, chain, link, goal) {
+        Some(directions) => directions,
+        None => return,
+    };
+    let mut unit = Vector3::zero();
+    unit[axis] = 1.0;
+
+    let mut turn = to_target.dot(to_goal).clamp(-1.0, 1.0).acos();
+    if chain.limit_angle > 0.0 {
+        turn = turn.min(chain.limit_angle);
+    }
+    // Which way round the axis brings the target closer
+    let forward = Quaternion::from_axis_angle(unit, Rad(turn)).rotate_vector(to_target);
+    let backward = Quaternion::from_axis_angle(unit, Rad(-turn)).rotate_vector(to_target);
+    let mut new_angle = if forward.dot(to_goal) > backward.dot(to_goal) {
+        *angle + turn
+    } else {
+        *angle - turn
+    };
+
+    // An angle out of range at first is folded to the side the limits allow
+    if iteration == 0 && (new_angle < min || new_angle > max) {
+        if -new_angle > min && -new_angle < max {
+            new_angle = -new_angle;
+        } else {
+            let middle = (min + max) * 0.5;
+            if (middle - new_angle).abs() > (middle + new_angle).abs() {
+                new_angle = -new_angle;
+            }
+        }
+    }
+    *angle = new_angle.clamp(min, max);
+
+    let rotation = Quaternion::from_axis_angle(unit, Rad(*angle));
+    skeleton.ik_rotation[link.bone] = rotation * pose_rotation(skeleton, link.bone).invert();
+    skeleton.update_subtree(link.bone);
+}
+
+/// The local rotation of `bone` without the IK: its pose and what it
+/// inherits from its append parent. The directions the solver turns between
+/// are measured in a frame that includes both, so the correction goes after
+/// them.
+fn pose_rotation(skeleton: &Skeleton, bone: usize) -> Quaternion<f32> {
+    skeleton.own_rotation(bone) * skeleton.append_rotation[bone]
+}
+
+/// The one axis a link turns around when its limits allow no other.
+fn single_axis((min, max): (Vector3<f32>, Vector3<f32>)) -> Option<usize> {
+    let free = (0..3)
+        .filter(|&axis| min[axis] != 0.0 || max[axis] != 0.0)
+        .collect::<Vec<_>>();
+    match free.as_slice() {
+        &[axis] => Some(axis),
+        _ => None,
+    }
+}
+
+/// Angles around x, y and z, applied in that order from the parent:
+/// `rotation = Rx * Ry * Rz`.
+fn euler_angles(rotation: Quaternion<f32>) -> Vector3<f32> {
+    let m = Matrix3::from(rotation);
+    // cgmath matrices are indexed by column, then row
+    Vector3::new(
+        (-m.z.y).atan2(m.z.z),
+        m.z.x.clamp(-1.0, 1.0).asin(),
+        (-m.y.x).atan2(m.x.x),
+    )
+}
+
+fn from_euler_angles(angles: Vector3<f32>) -> Quaternion<f32> {
+    Quaternion::from_angle_x(Rad(angles.x))
+        * Quaternion::from_angle_y(Rad(angles.y))
+        * Quaternion::from_angle_z(Rad(angles.z))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Deg;
+
+    use crate::pmx::{self, bone_flags, PmxModel};
+
+    /// Hip, knee and ankle 5 apart straight down, and a leg IK on the ankle
+    /// with the knee limited to bending around x.
+    fn leg() -> (PmxModel, Vector3<f32>, Vector3<f32>) {
+        let min = Vector3::new(Rad::from(Deg(-180.0)).0, 0.0, 0.0);
+        let max = Vector3::new(Rad::from(Deg(-0.5)).0, 0.0, 0.0);
+        let mut model = PmxModel::empty();
+        model.bones = vec![
+            pmx::Bone::new("足", Vector3::new(0.0, 10.0, 0.0), -1),
+            pmx::Bone::new("ひざ", Vector3::new(0.0, 5.0, 0.0), 0),
+            pmx::Bone::new("足首", Vector3::zero(), 1),
+            pmx::Bone::new("足ＩＫ", Vector3::zero(), -1),
+        ];
+        let ik = &mut model.bones[3];
+        ik.flags |= bone_flags::IK | bone_flags::TRANSLATABLE;
+        ik.ik = Some(pmx::Ik {
+            target: 2,
+            loop_count: 40,
+            limit_angle: 2.0,
+            links: vec![
+                pmx::IkLink {
+                    bone: 1,
+                    limits: Some((min, max)),
+                },
+                pmx::IkLink {
+                    bone: 0,
+                    limits: None,
+                },
+            ],
+        });
+        (model, min, max)
+    }
+
+    fn local_rotation(skeleton: &Skeleton, bone: usize) -> Quaternion<f32> {
+        skeleton.ik_rotation[bone] * pose_rotation(skeleton, bone)
+    }
+
+    /// Links stop turning a milliradian short, which is about 0.01 at the
+    /// length of the leg.
+    fn assert_reaches(skeleton: &Skeleton, goal: Vector3<f32>) {
+        let ankle = skeleton.world_position(2);
+        assert!(ankle.distance(goal) < 0.02, "{:?} is not {:?}", ankle, goal);
+    }
+
+    #[test]
+    fn knee() {
+        let (model, min, max) = leg();
+        let mut skeleton = Skeleton::from_pmx(&model);
+        let goal = Vector3::new(0.0, 3.0, -2.0);
+        skeleton.pose[3].translation = goal;
+        skeleton.evaluate();
+
+        assert_reaches(&skeleton, goal);
+        let knee = euler_angles(local_rotation(&skeleton, 1));
+        assert!(knee.x >= min.x && knee.x <= max.x, "{:?}", knee);
+        assert!(knee.x < -0.1, "the knee is not bent: {:?}", knee);
+        assert!(knee.y.abs() < 1e-4 && knee.z.abs() < 1e-4, "{:?}", knee);
+    }
+
+    #[test]
+    fn knee_never_bends_backwards() {
+        // Straight down would need no bend, pulling up needs one
+        let (model, min, max) = leg();
+        let mut skeleton = Skeleton::from_pmx(&model);
+        for &goal in &[Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 6.0, 2.0)] {
+            skeleton.pose[3].translation = goal;
+            skeleton.evaluate();
+            let knee = euler_angles(local_rotation(&skeleton, 1));
+            assert!(
+                knee.x >= min.x - 1e-4 && knee.x <= max.x + 1e-4,
+                "{:?}",
+                knee
+            );
+        }
+    }
+
+    #[test]
+    fn link_with_append_parent() {
+        // The hip inherits a turn around z, which the solver has to undo
+        let (mut model, _, _) = leg();
+        model
+            .bones
+            .push(pmx::Bone::new("腰キャンセル", Vector3::zero(), -1));
+        let hip = &mut model.bones[0];
+        hip.flags |= bone_flags::APPEND_ROTATION;
+        hip.append = Some(pmx::Append {
+            parent: 4,
+            weight: 1.0,
+        });
+        hip.layer = 1;
+        model.bones[3].layer = 1;
+
+        let mut skeleton = Skeleton::from_pmx(&model);
+        let goal = Vector3::new(1.0, 3.0, -2.0);
+        skeleton.pose[3].translation = goal;
+        skeleton.pose[4].rotation = Quaternion::from_angle_z(Deg(30.0));
+        skeleton.evaluate();
+
+        assert_reaches(&skeleton, goal);
+    }
+}
@@ -0,0 +1,307 @@
+//! Sampling VMD tracks between keyframes the way MMD does.
+//!
+//! Every keyframe carries the easing curves from the keyframe before it, so
+//! the curves of the later of two keyframes shape the motion between them.
+
+use cgmath::prelude::*;
+use cgmath::{Quaternion, Vector3};
+
+use crate::skeleton::BonePose;
+use crate::vmd::{Bezier, BoneKeyframe, CameraKeyframe, IkKeyframe, MorphKeyframe, Motion};
+
+impl Bezier {
+    /// Progress along the curve at `t`, both from 0 to 1. Solves the curve's
+    /// x for `t` and returns its y, with the control points scaled from 0..127.
+    pub fn evaluate(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        if self.x1 == self.y1 && self.x2 == self.y2 {
+            return t;
+        }
+        let (x1, y1) = (self.x1 as f32 / 127.0, self.y1 as f32 / 127.0);
+        let (x2, y2) = (self.x2 as f32 / 127.0, self.y2 as f32 / 127.0);
+
+        // x(s) rises monotonically as both x control points are in 0..1, so
+        // bisection always finds the one s with x(s) = t
+        let (mut low, mut high) = (0.0f32, 1.0f32);
+        let mut s = t;
+        for _ in 0..32 {
+            let x = cubic(x1, x2, s);
+            if (x - t).abs() < 1e-6 {
+                break;
+            }
+            if x < t {
+                low = s;
+            } else {
+                high = s;
+            }
+            s = (low + high) * 0.5;
+        }
+        cubic(y1, y2, s)
+    }
+}
+
+/// One coordinate of the Bezier from 0 to 1 with control points `p1`, `p2`.
+fn cubic(p1: f32, p2: f32, s: f32) -> f32 {
+    let inv = 1.0 - s;
+    3.0 * inv * inv * s * p1 + 3.0 * inv * s * s * p2 + s * s * s
+}
+
+/// Spherical interpolation from `a` to `b` the short way round.
+pub fn slerp(a: Quaternion<f32>, b: Quaternion<f32>, t: f32) -> Quaternion<f32> {
+    let (b, d) = match a.dot(b) {
+        d if d < 0.0 => (-b, -d),
+        d => (b, d),
+    };
+    if d > 0.9995 {
+        return (a * (1.0 - t) + b * t).normalize();
+    }
+    let theta = d.acos();
+    (a * ((1.0 - t) * theta).sin() + b * (t * theta).sin()) / theta.sin()
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// The keyframes around `frame` and how far between them it is, the
+/// previous keyframe alone before the first and after the last keyframe.
+/// `track` has to be sorted by frame.
+fn surrounding<T>(track: &[T], frame: f32, frame_of: impl Fn(&T) -> u32) -> Option<(&T, &T, f32)> {
+    let next = track.partition_point(|key| frame_of(key) as f32 <= frame);
+    match next {
+        0 => track.first().map(|key| (key, key, 0.0)),
+        _ if next == track.len() => track.last().map(|key| (key, key, 0.0)),
+        _ => {
+            let (previous, next) = (&track[next - 1], &track[next]);
+            let start = frame_of(previous) as f32;
+            let t = (frame - start) / (frame_of(next) as f32 - start);
+            Some((previous, next, t))
+        }
+    }
+}
+
+/// Pose of a bone at `frame`, which may fall between frames. The rest pose
+/// for an empty track.
+pub fn bone_pose(track: &[BoneKeyframe], frame: f32) -> BonePose {
+    let (previous, next, t) = match surrounding(track, frame, |key| key.frame) {
+        Some(keys) => keys,
+        None => return BonePose::default(),
+    };
+    let curves = &next.interpolation;
+    let (a, b) = (previous.translation, next.translation);
+    BonePose {
+        translation: Vector3::new(
+            lerp(a.x, b.x, curves.x.evaluate(t)),
+            lerp(a.y, b.y, curves.y.evaluate(t)),
+            lerp(a.z, b.z, curves.z.evaluate(t)),
+        ),
+        rotation: slerp(
+            previous.rotation,
+            next.rotation,
+            curves.rotation.evaluate(t),
+        ),
+        ..Default::default()
+    }
+}
+
+/// Weight of a morph at `frame`; morphs ease linearly. 0 for an empty track.
+pub fn morph_weight(track: &[MorphKeyframe], frame: f32) -> f32 {
+    match surrounding(track, frame, |key| key.frame) {
+        Some((previous, next, t)) => lerp(previous.weight, next.weight, t),
+        None => 0.0,
+    }
+}
+
+/// The camera at one point in time, with the fields of [`CameraKeyframe`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraPose {
+    pub distance: f32,
+    pub target: Vector3<f32>,
+    /// Euler angles in radians.
+    pub rotation: Vector3<f32>,
+    /// Vertical field of view in degrees.
+    pub fov: f32,
+    pub perspective: bool,
+}
+
+/// The camera at `frame`, `None` for an empty track. Keyframes on adjacent
+/// frames are a cut: the camera jumps instead of moving between them.
+pub fn camera_pose(track: &[CameraKeyframe], frame: f32) -> Option<CameraPose> {
+    let (previous, next, t) = surrounding(track, frame, |key| key.frame)?;
+    let t = if next.frame - previous.frame <= 1 {
+        0.0
+    } else {
+        t
+    };
+    let curves = &next.interpolation;
+    let (a, b) = (previous.target, next.target);
+    let rotation = curves.rotation.evaluate(t);
+    Some(CameraPose {
+        distance: lerp(
+            previous.distance,
+            next.distance,
+            curves.distance.evaluate(t),
+        ),
+        target: Vector3::new(
+            lerp(a.x, b.x, curves.x.evaluate(t)),
+            lerp(a.y, b.y, curves.y.evaluate(t)),
+            lerp(a.z, b.z, curves.z.evaluate(t)),
+        ),
+        // Euler angles, so turns of more than half a circle keep their way
+        rotation: previous.rotation.lerp(next.rotation, rotation),
+        fov: lerp(previous.fov as f32, next.fov as f32, curves.fov.evaluate(t)),
+        perspective: previous.perspective,
+    })
+}
+
+impl Motion {
+    /// The IK keyframe in effect at `frame`: the last one at or before it.
+    pub fn ik_key(&self, frame: f32) -> Option<&IkKeyframe> {
+        let next = self.ik.partition_point(|key| key.frame as f32 <= frame);
+        next.checked_sub(1).map(|index| &self.ik[index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Rotation3};
+
+    use crate::vmd::{BoneInterpolation, CameraInterpolation};
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    fn bone_key(frame: u32, translation: Vector3<f32>, rotation: Quaternion<f32>) -> BoneKeyframe {
+        BoneKeyframe {
+            frame,
+            translation,
+            rotation,
+            interpolation: BoneInterpolation::default(),
+            physics_disabled: false,
+        }
+    }
+
+    fn camera_key(frame: u32, target: Vector3<f32>, fov: u32) -> CameraKeyframe {
+        CameraKeyframe {
+            frame,
+            distance: -45.0,
+            target,
+            rotation: Vector3::zero(),
+            interpolation: CameraInterpolation::default(),
+            fov,
+            perspective: true,
+        }
+    }
+
+    #[test]
+    fn linear_curve() {
+        for &t in &[0.0, 0.1, 0.25, 0.5, 0.75, 1.0] {
+            assert_near(Bezier::LINEAR.evaluate(t), t);
+        }
+    }
+
+    #[test]
+    fn curves_match_saba() {
+        // Expected values from saba's VMDBezier (benikabocha/saba,
+        // src/Saba/Model/MMD/VMDAnimation.cpp) in f32: FindBezierX bisects x to
+        // within 1e-5, then EvalY, with the control points divided by 127
+        let cases = [
+            // Ease in and out
+            (
+                (64, 0, 63, 127),
+                [0.014419, 0.104819, 0.5, 0.895181, 0.985581],
+            ),
+            // Fast start
+            (
+                (10, 90, 40, 127),
+                [0.474555, 0.740844, 0.919284, 0.984270, 0.997773],
+            ),
+            // Slow start and end, steep in the middle
+            (
+                (107, 20, 20, 107),
+                [0.023028, 0.081561, 0.5, 0.918439, 0.976972],
+            ),
+        ];
+        for &((x1, y1, x2, y2), expected) in &cases {
+            let curve = Bezier { x1, y1, x2, y2 };
+            for (&t, &expected) in [0.1, 0.25, 0.5, 0.75, 0.9].iter().zip(&expected) {
+                assert_near(curve.evaluate(t), expected);
+            }
+            assert_near(curve.evaluate(0.0), 0.0);
+            assert_near(curve.evaluate(1.0), 1.0);
+        }
+    }
+
+    #[test]
+    fn slerps_rotation_between_keys() {
+        let track = [
+            bone_key(0, Vector3::zero(), Quaternion::one()),
+            bone_key(
+                10,
+                Vector3::new(0.0, 2.0, 0.0),
+                Quaternion::from_angle_y(Deg(90.0)),
+            ),
+        ];
+        let pose = bone_pose(&track, 5.0);
+        assert_near(pose.translation.y, 1.0);
+        let expected = Quaternion::from_angle_y(Deg(45.0));
+        assert_near(pose.rotation.dot(expected).abs(), 1.0);
+    }
+
+    #[test]
+    fn holds_first_and_last_key() {
+        let first = Quaternion::from_angle_x(Deg(30.0));
+        let last = Quaternion::from_angle_z(Deg(60.0));
+        let track = [
+            bone_key(10, Vector3::new(1.0, 0.0, 0.0), first),
+            bone_key(20, Vector3::new(3.0, 0.0, 0.0), last),
+        ];
+        let before = bone_pose(&track, 0.0);
+        assert_eq!(before.translation, Vector3::new(1.0, 0.0, 0.0));
+        assert_near(before.rotation.dot(first), 1.0);
+        let after = bone_pose(&track, 35.5);
+        assert_eq!(after.translation, Vector3::new(3.0, 0.0, 0.0));
+        assert_near(after.rotation.dot(last), 1.0);
+
+        let weights = [
+            MorphKeyframe {
+                frame: 5,
+                weight: 0.25,
+            },
+            MorphKeyframe {
+                frame: 15,
+                weight: 0.75,
+            },
+        ];
+        assert_near(morph_weight(&weights, 0.0), 0.25);
+        assert_near(morph_weight(&weights, 10.0), 0.5);
+        assert_near(morph_weight(&weights, 40.0), 0.75);
+    }
+
+    #[test]
+    fn camera_cuts_between_adjacent_frames() {
+        let (a, b) = (Vector3::new(0.0, 10.0, 0.0), Vector3::new(5.0, 12.0, -3.0));
+        let track = [
+            camera_key(10, a, 30),
+            camera_key(11, b, 45),
+            camera_key(21, a, 30),
+        ];
+        let pose = |frame| camera_pose(&track, frame).unwrap();
+        assert_eq!(pose(10.0).target, a);
+        assert_eq!(pose(10.5).target, a);
+        assert_eq!(pose(10.99).fov, 30.0);
+        assert_eq!(pose(11.0).target, b);
+        assert_eq!(pose(11.0).fov, 45.0);
+        // Keys further apart still move in between
+        assert_near(pose(16.0).target.x, 2.5);
+        assert_near(pose(16.0).fov, 37.5);
+        assert_eq!(camera_pose(&[], 0.0), None);
+    }
+}
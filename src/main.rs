@@ -1,3 +1,4 @@
+use anyhow::Context;
 use cgmath::prelude::*;
 use rayon::prelude::*;
 use wgpu::util::DeviceExt;
@@ -7,10 +8,29 @@ use winit::{
     window::Window,
 };
 
+mod animation;
+mod assets;
+mod binary;
+mod blend;
 mod camera;
+mod geometry;
+mod ik;
+mod interpolation;
 mod model;
+mod morph;
+mod mtl;
+mod obj;
+mod physics;
+mod pmd;
+mod pmx;
+mod resolver;
+mod retarget;
+mod skeleton;
+mod skinning;
 mod texture;
 mod instance;
+mod vmd;
+mod vpd;
 
 use model::{DrawModel, Vertex};
 use instance::*;
@@ -58,8 +78,25 @@ struct State {
     debug_material: model::Material,
     mouse_pressed: bool,
     mouse_position: Option<winit::dpi::PhysicalPosition<f64>>,
+    /// Bones and morphs of MMD models, `None` for OBJ models.
+    pmx_model: Option<pmx::PmxModel>,
+    skeleton: Option<skeleton::Skeleton>,
+    morphs: Option<morph::Morphs>,
+    /// Set when skinning on the CPU.
+    cpu_deformer: Option<skinning::CpuDeformer>,
+    /// Rigid bodies of MMD models that have any, unless turned off.
+    physics: Option<physics::Physics>,
+    /// Set when a motion plays on the model.
+    player: Option<animation::Player>,
+    /// Where F5 saves the current pose.
+    export_pose: Option<std::path::PathBuf>,
+    export_motion: Option<std::path::PathBuf>,
 }
 
+/// Storage buffers the skinning vertex shader reads: bones, morph offsets and
+/// morph weights.
+const DEFORM_STORAGE_BUFFERS: u32 = 3;
+
 fn create_render_pipeline(
     device: &wgpu::Device,
     layout: &wgpu::PipelineLayout,
@@ -83,10 +120,8 @@ fn create_render_pipeline(
             entry_point: "main",
             targets: &[wgpu::ColorTargetState {
                 format: color_format,
-                blend: Some(wgpu::BlendState {
-                    alpha: wgpu::BlendComponent::REPLACE,
-                    color: wgpu::BlendComponent::REPLACE,
-                }),
+                // Materials with a dissolve below 1 or a transparent texture blend
+                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
                 write_mask: wgpu::ColorWrites::ALL,
             }],
         }),
@@ -118,7 +153,7 @@ fn create_render_pipeline(
 }
 
 impl State {
-    async fn new(window: &Window) -> Self {
+    async fn new(window: &Window, assets: &assets::AssetConfig) -> anyhow::Result<Self> {
         let size = window.inner_size();
 
         // The instance is a handle to our GPU
@@ -138,7 +173,7 @@ impl State {
                 &wgpu::DeviceDescriptor {
                     label: None,
                     features: wgpu::Features::empty(),
-                    limits: wgpu::Limits::default(),
+                    limits: adapter.limits(),
                 },
                 trace_dir.ok().as_ref().map(std::path::Path::new), // Trace path
             )
@@ -177,6 +212,35 @@ impl State {
                         },
                         count: None,
                     },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: false,
+                            filtering: true,
+                        },
+                        count: None,
+                    },
                 ],
                 label: Some("texture_bind_group_layout"),
             });
@@ -195,6 +259,132 @@ impl State {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
+        let (model_data, pmx_model) = {
+            let (mut data, pmx_model) = load_model_data(assets)?;
+            for warning in data.warnings() {
+                log::warn!("{:?}: {}", data.name, warning);
+            }
+            if !assets.textures.is_empty() {
+                let materials = assets
+                    .textures
+                    .iter()
+                    .map(|texture| model::MaterialData {
+                        name: texture.name.clone(),
+                        params: Default::default(),
+                        diffuse_texture: Some(texture.path.clone()),
+                        normal_texture: texture.normal_map.clone(),
+                    })
+                    .collect();
+                let unused = data
+                    .bind_materials(materials, &assets.material_overrides)
+                    .with_context(|| format!("failed to load model {:?}", assets.model))?;
+                if !unused.is_empty() {
+                    log::warn!(
+                        "{:?}: materials not used by any mesh {:?}",
+                        data.name,
+                        unused
+                    );
+                }
+            }
+            if let Some(bounds) = data.bounds() {
+                log::info!("{:?}: bounds {:?} to {:?}", data.name, bounds.min, bounds.max);
+            }
+            (data, pmx_model)
+        };
+
+        let mut skeleton = pmx_model.as_ref().map(skeleton::Skeleton::from_pmx);
+        let mut morphs = pmx_model.as_ref().map(|pmx_model| {
+            let materials = model_data.materials.iter().map(|material| material.params).collect();
+            morph::Morphs::new(pmx_model, materials)
+        });
+        if let Some(path) = &assets.pose {
+            let pose = vpd::load(path)?;
+            match (&mut skeleton, &mut morphs) {
+                (Some(skeleton), Some(morphs)) => {
+                    let missing = pose.apply(skeleton);
+                    if !missing.is_empty() {
+                        log::warn!("{:?}: the model has no bones {:?}", path, missing);
+                    }
+                    let missing = pose.apply_morphs(morphs);
+                    if !missing.is_empty() {
+                        log::warn!("{:?}: the model has no morphs {:?}", path, missing);
+                    }
+                }
+                _ => log::warn!("{:?}: the model has no skeleton to pose", path),
+            }
+        }
+        let retargeter = {
+            let mapping = match &assets.bone_map {
+                Some(path) => retarget::load_mapping(path)?,
+                None => Default::default(),
+            };
+            let source = assets.retarget_from.as_deref().map(load_pmx).transpose()?;
+            retarget::Retargeter::new(mapping, source)
+        };
+        let mut clips = Vec::new();
+        for asset in &assets.motions {
+            let path = &asset.path;
+            let motion =
+                vmd::load(path).with_context(|| format!("failed to load motion {:?}", path))?;
+            log::info!(
+                "{:?}: motion for {:?}, {} bone and {} morph tracks, {} frames",
+                path,
+                motion.model_name,
+                motion.bones.len(),
+                motion.morphs.len(),
+                motion.last_frame() + 1
+            );
+            let (skeleton, pmx_model) = match (&skeleton, &pmx_model) {
+                (Some(skeleton), Some(pmx_model)) => (skeleton, pmx_model),
+                _ => {
+                    log::warn!("{:?}: the model has no skeleton to animate", path);
+                    continue;
+                }
+            };
+            let (motion, report) = retargeter.retarget(motion, pmx_model);
+            report.log(path);
+            let mut clip = blend::Clip::new(path.display().to_string(), motion);
+            if asset.additive {
+                clip.mode = blend::BlendMode::Additive;
+            }
+            if asset.face_only {
+                clip.mask = blend::BoneMask::face(skeleton);
+                if clip.mask.is_none() {
+                    log::warn!("{:?}: the model has no head bone, playing on all bones", path);
+                }
+            }
+            clips.push(clip);
+        }
+        let player = match (&mut skeleton, &mut morphs) {
+            (Some(skeleton), Some(morphs)) if !clips.is_empty() => {
+                let player = animation::Player::new(clips);
+                player.apply(skeleton, morphs);
+                Some(player)
+            }
+            _ => None,
+        };
+        let physics = match (&pmx_model, &mut skeleton) {
+            (Some(pmx_model), Some(skeleton)) if !assets.no_physics => {
+                let mut physics = physics::Physics::new(pmx_model, skeleton);
+                if let Some(physics) = &mut physics {
+                    log::info!(
+                        "{} rigid bodies, {} joints",
+                        pmx_model.rigid_bodies.len(),
+                        pmx_model.joints.len()
+                    );
+                    skeleton.evaluate_phase(false);
+                    physics.reset(skeleton);
+                }
+                physics
+            }
+            _ => None,
+        };
+        let scale = if model_data.left_handed {
+            cgmath::Vector3::new(1.0, 1.0, -1.0)
+        } else {
+            cgmath::Vector3::new(1.0, 1.0, 1.0)
+        };
+
         const SPACE_BETWEEN: f32 = 3.0;
         const NUM_INSTANCES_PER_ROW: u32 = 1;
 
@@ -218,7 +408,7 @@ impl State {
                         cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(0.0))
                     };
 
-                    Instance { position, rotation }
+                    Instance { position, rotation, scale }
                 })
             })
             .collect::<Vec<_>>();
@@ -254,99 +444,88 @@ impl State {
             label: Some("camera_bind_group"),
         });
 
-        //let res_dir = std::path::Path::new(env!("OUT_DIR")).join("res");
-        let obj_model = model::Model::load_mesh_buf(
+        // Skinning in the shader reads the bones, morph offsets and morph
+        // weights from storage buffers, which not every adapter has
+        let storage_buffers = adapter.limits().max_storage_buffers_per_shader_stage;
+        let vertex_storage = adapter
+            .get_downlevel_properties()
+            .flags
+            .contains(wgpu::DownlevelFlags::VERTEX_STORAGE);
+        let gpu_skinning = vertex_storage && storage_buffers >= DEFORM_STORAGE_BUFFERS;
+        let cpu_skinning = assets.cpu_skinning || !gpu_skinning;
+        if !gpu_skinning {
+            log::info!(
+                "the adapter has no {} storage buffers in the vertex stage, skinning on the CPU",
+                DEFORM_STORAGE_BUFFERS
+            );
+        }
+        let deform_bind_group_layout = if cpu_skinning {
+            None
+        } else {
+            Some(device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[0, 1, 2].map(|binding| wgpu::BindGroupLayoutEntry {
+                    binding,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }),
+                label: Some("deform_bind_group_layout"),
+            }))
+        };
+
+        let cpu_deformer = match &skeleton {
+            Some(_) if cpu_skinning => Some(skinning::CpuDeformer::new(&model_data)),
+            _ => None,
+        };
+
+        let obj_model = model::Model::upload(
             &device,
-            &include_bytes!("../res/yyb_school_miku_pose/yyb bake r.obj")[..],
-            vec![
-                model::Material::new(
-                    &device,
-                    "hairpin",
-                    texture::Texture::from_bytes(&device, &queue, include_bytes!("../res/yyb_school_miku_pose/hairpin bake.png"), "socks", false).unwrap(),
-                    &texture_bind_group_layout,
-                ),
-                model::Material::new(
-                    &device,
-                    "head",
-                    texture::Texture::from_bytes(&device, &queue, include_bytes!("../res/yyb_school_miku/head.png"), "socks", false).unwrap(),
-                    &texture_bind_group_layout,
-                ),
-                model::Material::new(
-                    &device,
-                    "shoes",
-                    texture::Texture::from_bytes(&device, &queue, include_bytes!("../res/yyb_school_miku_pose/shoes bake.png"), "socks", false).unwrap(),
-                    &texture_bind_group_layout,
-                ),
-                model::Material::new(
-                    &device,
-                    "hair01",
-                    texture::Texture::from_bytes(&device, &queue, include_bytes!("../res/yyb_school_miku/Hair01.png"), "socks", false).unwrap(),
-                    &texture_bind_group_layout,
-                ),
-                model::Material::new(
-                    &device,
-                    "hair02",
-                    texture::Texture::from_bytes(&device, &queue, include_bytes!("../res/yyb_school_miku/Hair02.png"), "socks", false).unwrap(),
-                    &texture_bind_group_layout,
-                ),
-                model::Material::new(
-                    &device,
-                    "socks",
-                    texture::Texture::from_bytes(&device, &queue, include_bytes!("../res/yyb_school_miku/socks.png"), "socks", false).unwrap(),
-                    &texture_bind_group_layout,
-                ),
-                model::Material::new(
-                    &device,
-                    "dress white",
-                    texture::Texture::from_bytes(&device, &queue, include_bytes!("../res/yyb_school_miku_pose/Material 4_UVP1.png"), "socks", false).unwrap(),
-                    &texture_bind_group_layout,
-                ),
-                model::Material::new(
-                    &device,
-                    "bow",
-                    texture::Texture::from_bytes(&device, &queue, include_bytes!("../res/yyb_school_miku_pose/bow bake.png"), "socks", false).unwrap(),
-                    &texture_bind_group_layout,
-                ),
-                model::Material::new(
-                    &device,
-                    "dress",
-                    texture::Texture::from_bytes(&device, &queue, include_bytes!("../res/yyb_school_miku_pose/dress bake.png"), "socks", false).unwrap(),
-                    &texture_bind_group_layout,
-                ),
-            ],
-            vec![
-                0, //Hairclip
-                1, //Body
-                2, //Shoes
-                3, //Hair01
-                3, //Hairshadow
-                4, //Hair03
-                5, //Socls
-                6, //Dress_White
-                7, //Bow
-                8, //Dress
-            ],
-            "yyb school miku",
+            &queue,
+            &texture_bind_group_layout,
+            deform_bind_group_layout.as_ref(),
+            model_data,
         )
-        .unwrap();
+        .with_context(|| format!("failed to load model {:?}", assets.model))?;
 
         let depth_texture =
             texture::Texture::create_depth_texture(&device, &config, "depth_texture");
 
+        let mut bind_group_layouts = vec![&texture_bind_group_layout, &camera_bind_group_layout];
+        bind_group_layouts.extend(&deform_bind_group_layout);
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[
-                    &texture_bind_group_layout,
-                    &camera_bind_group_layout,
-                ],
+                bind_group_layouts: &bind_group_layouts,
                 push_constant_ranges: &[],
             });
 
         let render_pipeline = {
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Normal Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("shader/shader.wgsl").into()),
+            let shader = if cpu_skinning {
+                wgpu::ShaderModuleDescriptor {
+                    label: Some("CPU Skinned Shader"),
+                    source: wgpu::ShaderSource::Wgsl(
+                        concat!(
+                            include_str!("shader/shader.wgsl"),
+                            include_str!("shader/cpu_skinning.wgsl")
+                        )
+                        .into(),
+                    ),
+                }
+            } else {
+                wgpu::ShaderModuleDescriptor {
+                    label: Some("Normal Shader"),
+                    source: wgpu::ShaderSource::Wgsl(
+                        concat!(
+                            include_str!("shader/shader.wgsl"),
+                            include_str!("shader/gpu_skinning.wgsl")
+                        )
+                        .into(),
+                    ),
+                }
             };
             create_render_pipeline(
                 &device,
@@ -359,26 +538,21 @@ impl State {
         };
 
         let debug_material = {
-            let diffuse_bytes = include_bytes!("../res/head.png");
-
-            let diffuse_texture = texture::Texture::from_bytes(
-                &device,
-                &queue,
-                diffuse_bytes,
-                "res/alt-diffuse.png",
-                false,
-            )
-            .unwrap();
+            let diffuse_path = assets::AssetConfig::res_dir().join("head.png");
+            let diffuse_texture = texture::Texture::load(&device, &queue, diffuse_path, false)?;
 
             model::Material::new(
                 &device,
+                &queue,
                 "alt-material",
                 diffuse_texture,
+                None,
+                Default::default(),
                 &texture_bind_group_layout,
-            )
+            )?
         };
 
-        Self {
+        Ok(Self {
             surface,
             device,
             queue,
@@ -399,6 +573,48 @@ impl State {
             debug_material,
             mouse_pressed: false,
             mouse_position: None,
+            pmx_model,
+            skeleton,
+            morphs,
+            cpu_deformer,
+            physics,
+            player,
+            export_pose: assets.export_pose.clone(),
+            export_motion: assets.export_motion.clone(),
+        })
+    }
+
+    /// Saves the pose of the model's bones and morphs as VPD, if asked to.
+    fn export_pose(&self) {
+        let (path, skeleton, morphs) = match (&self.export_pose, &self.skeleton, &self.morphs) {
+            (Some(path), Some(skeleton), Some(morphs)) => (path, skeleton, morphs),
+            (None, ..) => return log::warn!("no --export-pose file to save the pose to"),
+            _ => return log::warn!("the model has no skeleton to save the pose of"),
+        };
+        let model_name = self.pmx_model.as_ref().map_or("", |model| model.name.as_str());
+        let pose = vpd::Pose::from_skeleton(model_name, skeleton).with_morphs(morphs);
+        match pose.save(path) {
+            Ok(()) => log::info!("saved the pose to {:?}", path),
+            Err(e) => log::error!("{:?}", e),
+        }
+    }
+
+    /// Saves the clips playing on the model, baked into one motion, as VMD,
+    /// if asked to.
+    fn export_motion(&mut self) {
+        let (path, player, skeleton, morphs) =
+            match (&self.export_motion, &self.player, &mut self.skeleton, &mut self.morphs) {
+                (Some(path), Some(player), Some(skeleton), Some(morphs)) => {
+                    (path, player, skeleton, morphs)
+                }
+                (None, ..) => return log::warn!("no --export-motion file to save the motion to"),
+                _ => return log::warn!("the model has no motion to save"),
+            };
+        let mut motion = blend::bake(&player.clips, skeleton, morphs);
+        motion.model_name = self.pmx_model.as_ref().map_or(String::new(), |model| model.name.clone());
+        match motion.save(path) {
+            Ok(()) => log::info!("saved the motion to {:?}", path),
+            Err(e) => log::error!("{:?}: {}", path, e),
         }
     }
 
@@ -423,7 +639,24 @@ impl State {
                     ..
                 },
                 ..
-            } => self.camera_controller.process_keyboard(*key, *state),
+            } => {
+                if *state == ElementState::Pressed {
+                    match key {
+                        VirtualKeyCode::F5 => {
+                            self.export_pose();
+                            return true;
+                        }
+                        VirtualKeyCode::F6 => {
+                            self.export_motion();
+                            return true;
+                        }
+                        _ => {}
+                    }
+                }
+                let player = self.player.as_mut();
+                player.is_some_and(|player| player.process_keyboard(*key, *state))
+                    || self.camera_controller.process_keyboard(*key, *state)
+            }
             WindowEvent::MouseWheel { delta, .. } => {
                 self.camera_controller.process_scroll(delta);
                 true
@@ -461,6 +694,47 @@ impl State {
 
     fn update(&mut self, dt: std::time::Duration) {
         self.camera_controller.update_camera(&mut self.camera, dt);
+        if let Some(skeleton) = &mut self.skeleton {
+            // Physics follows the animation clock when a motion plays
+            let mut tick = animation::Tick {
+                elapsed: dt.as_secs_f32(),
+                jumped: false,
+            };
+            if let (Some(player), Some(morphs)) = (&mut self.player, &mut self.morphs) {
+                tick = player.advance(dt);
+                player.apply(skeleton, morphs);
+            }
+            let morph_weights = match &self.morphs {
+                Some(morphs) => morphs.apply(Some(skeleton), &self.obj_model, &self.queue),
+                None => Vec::new(),
+            };
+            match &mut self.physics {
+                Some(physics) => {
+                    skeleton.evaluate_phase(false);
+                    if tick.jumped {
+                        physics.reset(skeleton);
+                    }
+                    physics.update(skeleton, tick.elapsed);
+                    skeleton.evaluate_phase(true);
+                }
+                None => skeleton.evaluate(),
+            }
+            match &self.cpu_deformer {
+                Some(deformer) => {
+                    let bones = skinning::BoneTransform::from_skeleton(skeleton);
+                    for (mesh, vertices) in deformer.deform(&bones, &morph_weights).iter().enumerate() {
+                        self.obj_model.write_vertices(&self.queue, mesh, vertices);
+                    }
+                }
+                None => self.obj_model.write_bones(&self.queue, skeleton),
+            }
+        }
+        // A camera track in the motion takes over the view at the frame just posed
+        if let Some(pose) = self.player.as_ref().and_then(animation::Player::camera_pose) {
+            let origin = cgmath::Point3::from_vec(self.instances[0].position);
+            self.camera.set_mmd_pose(&pose, origin);
+            self.projection.set_fovy(cgmath::Deg(pose.fov));
+        }
         self.camera_uniform
             .update_view_proj(&self.camera, &self.projection);
         self.queue.write_buffer(
@@ -531,15 +805,66 @@ impl State {
     }
 }
 
+/// Imports the model named by `assets`, picking the format by extension. MMD
+/// models come with their PMX description, which holds the bones and morphs.
+fn load_model_data(
+    assets: &assets::AssetConfig,
+) -> anyhow::Result<(model::ModelData, Option<pmx::PmxModel>)> {
+    let path = &assets.model;
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("pmx") | Some("pmd") => {
+            let pmx = load_pmx(path)?;
+            let model_dir = path.parent().unwrap_or_else(|| std::path::Path::new(""));
+            let data = pmx.to_model_data(model_dir, &assets.texture_resolver());
+            Ok((data, Some(pmx)))
+        }
+        _ => {
+            let data = obj::load(path, &assets.texture_resolver(), &assets.import_options)
+                .with_context(|| format!("failed to load model {:?}", path))?;
+            Ok((data, None))
+        }
+    }
+}
+
+/// Reads a PMX model, or a PMD model converted to PMX.
+fn load_pmx(path: &std::path::Path) -> anyhow::Result<pmx::PmxModel> {
+    let is_pmd = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("pmd"));
+    if is_pmd {
+        Ok(pmd::load(path)
+            .with_context(|| format!("failed to load model {:?}", path))?
+            .to_pmx())
+    } else {
+        pmx::load(path).with_context(|| format!("failed to load model {:?}", path))
+    }
+}
+
 fn main() {
     env_logger::init();
+    let assets = match assets::AssetConfig::from_args(std::env::args().skip(1)) {
+        Ok(assets) => assets,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
     let event_loop = EventLoop::new();
     let title = env!("CARGO_PKG_NAME");
     let window = winit::window::WindowBuilder::new()
         .with_title(title)
         .build(&event_loop)
         .unwrap();
-    let mut state = pollster::block_on(State::new(&window)); // NEW!
+    let mut state = match pollster::block_on(State::new(&window, &assets)) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
     let mut last_render_time = std::time::Instant::now();
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
@@ -1,21 +1,74 @@
 use anyhow::*;
-use std::io::BufRead;
+use cgmath::SquareMatrix;
+use std::collections::HashMap;
 use std::ops::Range;
-use std::path::Path;
-use tobj::LoadOptions;
+use std::path::PathBuf;
 use wgpu::util::DeviceExt;
 
+use crate::mtl::TextureOptions;
+use crate::skeleton::Skeleton;
 use crate::texture;
 
+/// Maps an MTL material name (as used by `usemtl`) to the name of the
+/// material it should be drawn with, e.g. `Hairshadow` -> `Hair01`.
+pub type MaterialOverrides = HashMap<String, String>;
+
 pub trait Vertex {
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a>;
 }
 
+/// How a vertex follows its bones, see [`ModelVertex::skinning`].
+pub mod skinning {
+    /// Linear blend of up to four bones; BDEF1, BDEF2 and BDEF4.
+    pub const LINEAR: u32 = 0;
+    /// MMD's spherical deform between two bones.
+    pub const SDEF: u32 = 1;
+    /// Dual quaternion blend of up to four bones.
+    pub const QDEF: u32 = 2;
+    /// Already in its pose, skinned on the CPU; the bones are ignored.
+    pub const NONE: u32 = 3;
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct ModelVertex {
-    position: [f32; 3],
-    tex_coords: [f32; 2],
+    pub position: [f32; 3],
+    pub tex_coords: [f32; 2],
+    pub normal: [f32; 3],
+    /// xyz is the tangent, w the handedness of the bitangent
+    pub tangent: [f32; 4],
+    pub bone_indices: [u32; 4],
+    /// Sums to 1; unused bones have weight 0
+    pub bone_weights: [f32; 4],
+    /// SDEF centre, and the rotation centres of the first and second bone
+    /// already corrected the way MMD does. Unused by other skinning modes.
+    pub sdef_c: [f32; 3],
+    pub sdef_r0: [f32; 3],
+    pub sdef_r1: [f32; 3],
+    /// One of the [`skinning`] modes
+    pub skinning: u32,
+    /// First entry and number of entries in [`ModelData::morph_offsets`]
+    /// that move this vertex.
+    pub morph_range: [u32; 2],
+}
+
+impl Default for ModelVertex {
+    /// A vertex at the origin that follows bone 0 alone.
+    fn default() -> Self {
+        Self {
+            position: [0.0; 3],
+            tex_coords: [0.0; 2],
+            normal: [0.0; 3],
+            tangent: [0.0; 4],
+            bone_indices: [0; 4],
+            bone_weights: [1.0, 0.0, 0.0, 0.0],
+            sdef_c: [0.0; 3],
+            sdef_r0: [0.0; 3],
+            sdef_r1: [0.0; 3],
+            skinning: skinning::LINEAR,
+            morph_range: [0; 2],
+        }
+    }
 }
 
 impl Vertex for ModelVertex {
@@ -35,44 +88,505 @@ impl Vertex for ModelVertex {
                     shader_location: 1,
                     format: wgpu::VertexFormat::Float32x2,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                // Locations 5 to 8 are taken by the instance matrix
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Uint32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 20]>() as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 23]>() as wgpu::BufferAddress,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 26]>() as wgpu::BufferAddress,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 29]>() as wgpu::BufferAddress,
+                    shader_location: 13,
+                    format: wgpu::VertexFormat::Uint32,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 30]>() as wgpu::BufferAddress,
+                    shader_location: 14,
+                    format: wgpu::VertexFormat::Uint32x2,
+                },
             ],
         }
     }
 }
 
+/// One bone as the vertex shader reads it from the bone storage buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct BoneRaw {
+    skinning: [[f32; 4]; 4],
+    // Rotation of the skinning matrix as a quaternion, x, y, z, w
+    rotation: [f32; 4],
+    // Dual part of the skinning dual quaternion, for QDEF
+    dual: [f32; 4],
+}
+
+impl BoneRaw {
+    pub fn identity() -> Self {
+        Self {
+            skinning: cgmath::Matrix4::identity().into(),
+            rotation: [0.0, 0.0, 0.0, 1.0],
+            dual: [0.0; 4],
+        }
+    }
+
+    pub fn from_skeleton(skeleton: &Skeleton) -> Vec<Self> {
+        (0..skeleton.bones.len())
+            .map(|bone| {
+                let (rotation, dual) = skeleton.skinning_dual_quaternion(bone);
+                Self {
+                    skinning: skeleton.skinning_matrix(bone).into(),
+                    rotation: [rotation.v.x, rotation.v.y, rotation.v.z, rotation.s],
+                    dual: [dual.v.x, dual.v.y, dual.v.z, dual.s],
+                }
+            })
+            .collect()
+    }
+}
+
+/// How far one morph moves one vertex, as the vertex shader reads it from the
+/// morph offset storage buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MorphOffset {
+    pub position: [f32; 3],
+    /// Index of the morph whose weight scales the offset
+    pub morph: u32,
+    /// Added to the texture coordinates; only `xy` is used
+    pub uv: [f32; 4],
+}
+
+/// Surface parameters of a material, as given by an MTL `newmtl` block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaterialParams {
+    /// `Ka`
+    pub ambient: [f32; 3],
+    /// `Kd`, multiplied with the diffuse texture
+    pub diffuse: [f32; 3],
+    /// `Ks`
+    pub specular: [f32; 3],
+    /// `Ns`
+    pub shininess: f32,
+    /// `Ke`
+    pub emissive: [f32; 3],
+    /// `d`
+    pub dissolve: f32,
+    /// `illum`: 0 is unlit, 1 adds diffuse lighting and 2 and above specular
+    pub illumination: u8,
+    pub texture_options: TextureOptions,
+    /// Colour and alpha of the outline drawn around PMX models
+    pub edge_color: [f32; 4],
+    /// Width of the outline, 0 for none
+    pub edge_size: f32,
+}
+
+impl Default for MaterialParams {
+    /// Draws the texture unlit, as it is.
+    fn default() -> Self {
+        Self {
+            ambient: [1.0; 3],
+            diffuse: [1.0; 3],
+            specular: [0.0; 3],
+            shininess: 0.0,
+            emissive: [0.0; 3],
+            dissolve: 1.0,
+            illumination: 0,
+            texture_options: TextureOptions::default(),
+            edge_color: [0.0, 0.0, 0.0, 1.0],
+            edge_size: 0.0,
+        }
+    }
+}
+
+/// Scales and offsets the colour of a material's texture, what PMX material
+/// morphs call the texture tint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextureTint {
+    pub multiply: [f32; 4],
+    pub add: [f32; 4],
+}
+
+impl Default for TextureTint {
+    fn default() -> Self {
+        Self {
+            multiply: [1.0; 4],
+            add: [0.0; 4],
+        }
+    }
+}
+
+/// The tints material morphs put on the texture, sphere map and toon texture
+/// of a material.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MaterialTints {
+    pub texture: TextureTint,
+    pub sphere: TextureTint,
+    pub toon: TextureTint,
+}
+
+/// Per-material values read by the fragment shader.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+    ambient: [f32; 4],
+    // w is the dissolve
+    diffuse: [f32; 4],
+    // w is the specular exponent
+    specular: [f32; 4],
+    emissive: [f32; 4],
+    uv_offset: [f32; 2],
+    uv_scale: [f32; 2],
+    illumination: u32,
+    // Non-zero when the material has a normal map
+    normal_map: u32,
+    // Keeps the vec4s below on WGSL's 16 byte alignment
+    _padding: [u32; 2],
+    texture_multiply: [f32; 4],
+    texture_add: [f32; 4],
+}
+
+impl MaterialUniform {
+    pub fn new(params: &MaterialParams, tint: &TextureTint, has_normal_map: bool) -> Self {
+        let [ar, ag, ab] = params.ambient;
+        let [dr, dg, db] = params.diffuse;
+        let [sr, sg, sb] = params.specular;
+        let [er, eg, eb] = params.emissive;
+        let texture_options = &params.texture_options;
+
+        Self {
+            ambient: [ar, ag, ab, 1.0],
+            diffuse: [dr, dg, db, params.dissolve],
+            specular: [sr, sg, sb, params.shininess],
+            emissive: [er, eg, eb, 1.0],
+            uv_offset: [texture_options.offset[0], texture_options.offset[1]],
+            uv_scale: [texture_options.scale[0], texture_options.scale[1]],
+            illumination: params.illumination as u32,
+            normal_map: has_normal_map as u32,
+            _padding: [0; 2],
+            texture_multiply: tint.multiply,
+            texture_add: tint.add,
+        }
+    }
+}
+
 pub struct Material {
     pub name: String,
-    pub emissive_texture: texture::Texture,
+    #[allow(dead_code)]
+    pub diffuse_texture: texture::Texture,
+    /// A flat normal map when the material has none, so the bind group
+    /// layout is the same for every material.
+    #[allow(dead_code)]
+    pub normal_texture: texture::Texture,
+    pub uniform_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
+    has_normal_map: bool,
 }
 
 impl Material {
     pub fn new(
         device: &wgpu::Device,
+        queue: &wgpu::Queue,
         name: &str,
-        emissive_texture: texture::Texture,
+        mut diffuse_texture: texture::Texture,
+        normal_texture: Option<texture::Texture>,
+        params: MaterialParams,
         layout: &wgpu::BindGroupLayout,
-    ) -> Self {
+    ) -> Result<Self> {
+        let has_normal_map = normal_texture.is_some();
+        let mut normal_texture = match normal_texture {
+            Some(normal_texture) => normal_texture,
+            None => texture::Texture::from_color(device, queue, [128, 128, 255, 255], name, true)?,
+        };
+        // `-clamp on` stops the texture repeating; clamping in the sampler
+        // keeps the edges clean where mip levels meet
+        if params.texture_options.clamp {
+            let clamp = wgpu::AddressMode::ClampToEdge;
+            diffuse_texture = diffuse_texture.with_address_mode(device, clamp);
+            normal_texture = normal_texture.with_address_mode(device, clamp);
+        }
+
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Material Buffer", name)),
+            contents: bytemuck::cast_slice(&[MaterialUniform::new(
+                &params,
+                &TextureTint::default(),
+                has_normal_map,
+            )]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&emissive_texture.view),
+                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&emissive_texture.sampler),
-                }
+                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&normal_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
+                },
             ],
             label: Some(name),
         });
 
-        Self {
+        Ok(Self {
             name: String::from(name),
-            emissive_texture,
+            diffuse_texture,
+            normal_texture,
+            uniform_buffer,
             bind_group,
+            has_normal_map,
+        })
+    }
+
+    /// Overwrites the material's uniform, so that it draws with `params` and
+    /// `tints` from now on.
+    pub fn write_params(
+        &self,
+        queue: &wgpu::Queue,
+        params: &MaterialParams,
+        tints: &MaterialTints,
+    ) {
+        let uniform = MaterialUniform::new(params, &tints.texture, self.has_normal_map);
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+}
+
+/// Axis aligned bounding box of a mesh or model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+impl Bounds {
+    /// The bounds of `vertices`, or an empty box at the origin when there are
+    /// none.
+    pub fn from_vertices(vertices: &[ModelVertex]) -> Self {
+        if vertices.is_empty() {
+            return Self {
+                min: [0.0; 3],
+                max: [0.0; 3],
+            };
+        }
+
+        let mut bounds = Self {
+            min: [f32::MAX; 3],
+            max: [f32::MIN; 3],
+        };
+        for vertex in vertices {
+            for axis in 0..3 {
+                bounds.min[axis] = bounds.min[axis].min(vertex.position[axis]);
+                bounds.max[axis] = bounds.max[axis].max(vertex.position[axis]);
+            }
+        }
+        bounds
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        let mut bounds = *self;
+        for axis in 0..3 {
+            bounds.min[axis] = bounds.min[axis].min(other.min[axis]);
+            bounds.max[axis] = bounds.max[axis].max(other.max[axis]);
+        }
+        bounds
+    }
+}
+
+/// A material as described by a model file, before any texture is loaded.
+#[derive(Debug, Clone)]
+pub struct MaterialData {
+    pub name: String,
+    pub params: MaterialParams,
+    /// Resolved path of the diffuse texture. `None` draws the plain colour.
+    pub diffuse_texture: Option<PathBuf>,
+    /// Resolved path of a tangent space normal map.
+    pub normal_texture: Option<PathBuf>,
+}
+
+/// CPU side geometry of one mesh, ready to be uploaded.
+#[derive(Debug, Clone)]
+pub struct MeshData {
+    pub name: String,
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+    /// Index into [`ModelData::materials`]
+    pub material: Option<usize>,
+    pub bounds: Bounds,
+    /// Problems found in the source data that the loader worked around.
+    pub warnings: Vec<String>,
+    /// For every vertex, the index of the vertex in the source file it was
+    /// copied from. Empty for formats whose vertices are not shared between
+    /// meshes, such as OBJ.
+    pub source_vertices: Vec<u32>,
+}
+
+impl MeshData {
+    pub fn new(
+        name: String,
+        vertices: Vec<ModelVertex>,
+        indices: Vec<u32>,
+        material: Option<usize>,
+    ) -> Self {
+        let bounds = Bounds::from_vertices(&vertices);
+        Self {
+            name,
+            vertices,
+            indices,
+            material,
+            bounds,
+            warnings: Vec::new(),
+            source_vertices: Vec::new(),
+        }
+    }
+}
+
+/// Everything a format loader produces. Nothing in here touches the GPU, so
+/// importers can be run and inspected headless; [`Model::upload`] turns it
+/// into a drawable [`Model`].
+#[derive(Debug, Clone)]
+pub struct ModelData {
+    pub name: String,
+    pub meshes: Vec<MeshData>,
+    pub materials: Vec<MaterialData>,
+    /// Positions are in a left-handed coordinate system, as in MMD models,
+    /// and have to be mirrored along z to be drawn.
+    pub left_handed: bool,
+    /// Number of bones the vertices refer to. Models without a skeleton have
+    /// none and follow a single fixed bone.
+    pub bone_count: usize,
+    /// Number of morphs the offsets refer to.
+    pub morph_count: usize,
+    /// Vertex and UV morph offsets, grouped by vertex, see
+    /// [`ModelVertex::morph_range`].
+    pub morph_offsets: Vec<MorphOffset>,
+}
+
+impl ModelData {
+    /// Every warning of every mesh, prefixed with the mesh name.
+    pub fn warnings(&self) -> impl Iterator<Item = String> + '_ {
+        self.meshes.iter().flat_map(|mesh| {
+            mesh.warnings
+                .iter()
+                .map(move |warning| format!("{}: {}", mesh.name, warning))
+        })
+    }
+
+    pub fn bounds(&self) -> Option<Bounds> {
+        self.meshes
+            .iter()
+            .map(|mesh| mesh.bounds)
+            .reduce(|a, b| a.union(&b))
+    }
+
+    /// Replaces the model's own materials with `materials`, rebinding every
+    /// mesh by the name of the material it used.
+    ///
+    /// A mesh's material name is looked up in `overrides` first and then
+    /// matched against the names of `materials`. Any mesh that cannot be bound
+    /// fails the whole model with a list of the unmatched meshes and the
+    /// materials there are. Returns the names of the materials no mesh uses.
+    pub fn bind_materials(
+        &mut self,
+        materials: Vec<MaterialData>,
+        overrides: &MaterialOverrides,
+    ) -> Result<Vec<String>> {
+        let mut indices = Vec::with_capacity(self.meshes.len());
+        let mut used = vec![false; materials.len()];
+        let mut unmatched = Vec::new();
+
+        for mesh in &self.meshes {
+            let mtl_name = match mesh.material.and_then(|id| self.materials.get(id)) {
+                Some(mat) => &mat.name,
+                None => {
+                    unmatched.push(format!("{:?} has no usemtl material", mesh.name));
+                    continue;
+                }
+            };
+            let wanted = overrides.get(mtl_name).unwrap_or(mtl_name);
+
+            match materials.iter().position(|mat| &mat.name == wanted) {
+                Some(index) => {
+                    used[index] = true;
+                    indices.push(index);
+                }
+                None if wanted != mtl_name => unmatched.push(format!(
+                    "{:?} uses {:?}, overridden to missing material {:?}",
+                    mesh.name, mtl_name, wanted
+                )),
+                None => unmatched.push(format!(
+                    "{:?} uses missing material {:?}",
+                    mesh.name, mtl_name
+                )),
+            }
+        }
+
+        if !unmatched.is_empty() {
+            let available = materials
+                .iter()
+                .map(|mat| format!("{:?}", mat.name))
+                .collect::<Vec<_>>();
+            bail!(
+                "failed to bind materials of {:?}\nunmatched meshes:\n  {}\navailable materials: {}",
+                self.name,
+                unmatched.join("\n  "),
+                if available.is_empty() { "none".to_string() } else { available.join(", ") }
+            );
         }
+        let unused = materials
+            .iter()
+            .zip(&used)
+            .filter(|(_, &used)| !used)
+            .map(|(mat, _)| mat.name.clone())
+            .collect();
+
+        for (mesh, index) in self.meshes.iter_mut().zip(indices) {
+            mesh.material = Some(index);
+        }
+        self.materials = materials;
+        Ok(unused)
     }
 }
 
@@ -84,202 +598,209 @@ pub struct Mesh {
     pub material: usize,
 }
 
+/// The storage buffers the vertex shader morphs and skins with.
+pub struct GpuDeform {
+    /// One [`BoneRaw`] per bone, at least one.
+    pub bone_buffer: wgpu::Buffer,
+    pub bone_count: usize,
+    /// One weight per morph, at least one.
+    pub morph_weight_buffer: wgpu::Buffer,
+    pub morph_count: usize,
+    /// The bones, morph offsets and morph weights.
+    pub bind_group: wgpu::BindGroup,
+}
+
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    /// `None` when the vertices are deformed on the CPU instead.
+    pub deform: Option<GpuDeform>,
 }
 
 impl Model {
-    pub fn load<P: AsRef<Path>>(
+    /// Loads the textures of `data` and creates the GPU buffers for it. With
+    /// a `deform_layout` the bones and morphs go in storage buffers, starting
+    /// out in the rest pose and at 0, see [`Model::write_bones`] and
+    /// [`Model::write_morph_weights`]; without one the vertices are left to
+    /// [`Model::write_vertices`].
+    pub fn upload(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         layout: &wgpu::BindGroupLayout,
-        path: P,
+        deform_layout: Option<&wgpu::BindGroupLayout>,
+        data: ModelData,
     ) -> Result<Self> {
-        let (obj_models, obj_materials) = tobj::load_obj(
-            path.as_ref(),
-            &LoadOptions {
-                triangulate: true,
-                single_index: true,
-                ..Default::default()
-            },
-        )?;
-
-        let obj_materials = obj_materials?;
-
-        // We're assuming that the texture files are stored with the obj file
-        let containing_folder = path.as_ref().parent().context("Directory has no parent")?;
-
         let mut materials = Vec::new();
-        for mat in obj_materials {
-            let diffuse_path = mat.diffuse_texture;
-            let diffuse_texture =
-                texture::Texture::load(device, queue, containing_folder.join(diffuse_path), false)?;
+        for mat in &data.materials {
+            let diffuse_texture = match &mat.diffuse_texture {
+                Some(path) => texture::Texture::load(device, queue, path, false)?,
+                None => texture::Texture::from_color(device, queue, [255; 4], &mat.name, false)?,
+            };
+            let normal_texture = match &mat.normal_texture {
+                Some(path) => Some(texture::Texture::load(device, queue, path, true)?),
+                None => None,
+            };
 
             materials.push(Material::new(
                 device,
+                queue,
                 &mat.name,
                 diffuse_texture,
+                normal_texture,
+                mat.params,
                 layout,
-            ));
+            )?);
         }
 
-        let mut meshes = Vec::new();
-        for m in obj_models {
-            let mut vertices = Vec::new();
-            for i in 0..m.mesh.positions.len() / 3 {
-                vertices.push(ModelVertex {
-                    position: [
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ]
-                    .into(),
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]].into(),
-                });
-            }
+        // Meshes without a material are drawn with a plain white one
+        if data.meshes.iter().any(|mesh| mesh.material.is_none()) {
+            materials.push(Material::new(
+                device,
+                queue,
+                "default",
+                texture::Texture::from_color(device, queue, [255; 4], "default", false)?,
+                None,
+                MaterialParams::default(),
+                layout,
+            )?);
+        }
+        let default_material = materials.len().saturating_sub(1);
 
+        let mut meshes = Vec::new();
+        for m in &data.meshes {
             let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", path.as_ref())),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
+                label: Some(&format!("{:?} Vertex Buffer", data.name)),
+                contents: bytemuck::cast_slice(&m.vertices),
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
             });
             let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", path.as_ref())),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
+                label: Some(&format!("{:?} Index Buffer", data.name)),
+                contents: bytemuck::cast_slice(&m.indices),
                 usage: wgpu::BufferUsages::INDEX,
             });
 
+            let material = match m.material {
+                Some(material) if material < data.materials.len() => material,
+                Some(material) => bail!(
+                    "mesh {:?} of {:?} uses material {}, but there are only {}",
+                    m.name,
+                    data.name,
+                    material,
+                    data.materials.len()
+                ),
+                None => default_material,
+            };
+
             meshes.push(Mesh {
-                name: m.name,
+                name: m.name.clone(),
                 vertex_buffer,
                 index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
+                num_elements: m.indices.len() as u32,
+                material,
             });
         }
 
-        Ok(Self { meshes, materials })
-    }
-
-    pub fn load_mesh<P: AsRef<Path>>(
-        device: &wgpu::Device,
-        path: P,
-        materials: Vec<Material>,
-        material_indices: Vec<usize>,
-    ) -> Result<Self> {
-        let (obj_models, _) = tobj::load_obj(
-            path.as_ref(),
-            &LoadOptions {
-                triangulate: true,
-                single_index: true,
-                
-                ..Default::default()
-            },
-        )?;
+        let deform = deform_layout.map(|layout| GpuDeform::new(device, layout, data));
 
-        let mut meshes = Vec::new();
-        for (i, m) in obj_models.into_iter().enumerate() {
-            let mut vertices = Vec::new();
-            for i in 0..m.mesh.positions.len() / 3 {
-                vertices.push(ModelVertex {
-                    position: [
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ]
-                    .into(),
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]].into(),
-                });
-            }
+        Ok(Self {
+            meshes,
+            materials,
+            deform,
+        })
+    }
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", path.as_ref())),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", path.as_ref())),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsages::INDEX,
-            });
+    /// Replaces the vertices of mesh `mesh`, which has to keep its vertex count.
+    pub fn write_vertices(&self, queue: &wgpu::Queue, mesh: usize, vertices: &[ModelVertex]) {
+        queue.write_buffer(&self.meshes[mesh].vertex_buffer, 0, bytemuck::cast_slice(vertices));
+    }
 
-            meshes.push(Mesh {
-                name: m.name,
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: material_indices[i],
-            });
+    /// Uploads the evaluated pose of `skeleton`, which has to be the skeleton
+    /// the model was loaded with. Does nothing when deforming on the CPU.
+    pub fn write_bones(&self, queue: &wgpu::Queue, skeleton: &Skeleton) {
+        let deform = match &self.deform {
+            Some(deform) => deform,
+            None => return,
+        };
+        let bones = BoneRaw::from_skeleton(skeleton);
+        if bones.len() > deform.bone_count {
+            log::warn!("skeleton has {} bones, more than the model", bones.len());
+            return;
         }
-
-        Ok(Self { meshes, materials })
+        queue.write_buffer(&deform.bone_buffer, 0, bytemuck::cast_slice(&bones));
     }
 
-    pub fn load_mesh_buf<B: BufRead>(
-        device: &wgpu::Device,
-        mut buf: B,
-        materials: Vec<Material>,
-        material_indices: Vec<usize>,
-        name: &str,
-    ) -> Result<Self> {
-        let (obj_models, _) = tobj::load_obj_buf(
-            &mut buf,
-            &LoadOptions {
-                triangulate: true,
-                single_index: true,
-                
-                ..Default::default()
-            },
-            |_|{ Err(tobj::LoadError::OpenFileFailed) },
-        )?;
+    /// Uploads the weight of every morph, after group and flip morphs have
+    /// been resolved. Does nothing when deforming on the CPU.
+    pub fn write_morph_weights(&self, queue: &wgpu::Queue, weights: &[f32]) {
+        let deform = match &self.deform {
+            Some(deform) if !weights.is_empty() => deform,
+            _ => return,
+        };
+        if weights.len() > deform.morph_count {
+            log::warn!("{} morph weights for {} morphs", weights.len(), deform.morph_count);
+            return;
+        }
+        queue.write_buffer(&deform.morph_weight_buffer, 0, bytemuck::cast_slice(weights));
+    }
+}
 
-        let mut meshes = Vec::new();
-        for (i, m) in obj_models.into_iter().enumerate() {
-            let mut vertices = Vec::new();
-            for i in 0..m.mesh.positions.len() / 3 {
-                vertices.push(ModelVertex {
-                    position: [
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ]
-                    .into(),
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]].into(),
-                });
-            }
+impl GpuDeform {
+    fn new(device: &wgpu::Device, deform_layout: &wgpu::BindGroupLayout, data: ModelData) -> Self {
+        let bone_count = data.bone_count.max(1);
+        let bones = vec![BoneRaw::identity(); bone_count];
+        let bone_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Bone Buffer", data.name)),
+            contents: bytemuck::cast_slice(&bones),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+        // Storage buffers cannot be empty
+        let morph_offsets = if data.morph_offsets.is_empty() {
+            vec![MorphOffset::default()]
+        } else {
+            data.morph_offsets
+        };
+        let morph_offset_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Morph Offset Buffer", data.name)),
+            contents: bytemuck::cast_slice(&morph_offsets),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let morph_count = data.morph_count.max(1);
+        let morph_weight_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Morph Weight Buffer", data.name)),
+            contents: bytemuck::cast_slice(&vec![0.0f32; morph_count]),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", name)),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", name)),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsages::INDEX,
-            });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: deform_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: bone_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: morph_offset_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: morph_weight_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some(&format!("{:?} Deform Bind Group", data.name)),
+        });
 
-            meshes.push(Mesh {
-                name: m.name,
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: material_indices[i],
-            });
+        Self {
+            bone_buffer,
+            bone_count,
+            morph_weight_buffer,
+            morph_count,
+            bind_group,
         }
-
-        Ok(Self { meshes, materials })
     }
 }
 
 pub trait DrawModel<'a> {
-    fn draw_mesh(
-        &mut self,
-        mesh: &'a Mesh,
-        material: &'a Material,
-        camera_bind_group: &'a wgpu::BindGroup,
-    );
     fn draw_mesh_instanced(
         &mut self,
         mesh: &'a Mesh,
@@ -287,40 +808,18 @@ pub trait DrawModel<'a> {
         instances: Range<u32>,
         camera_bind_group: &'a wgpu::BindGroup,
     );
-
-    fn draw_model(
-        &mut self,
-        model: &'a Model,
-        camera_bind_group: &'a wgpu::BindGroup,
-    );
     fn draw_model_instanced(
         &mut self,
         model: &'a Model,
         instances: Range<u32>,
         camera_bind_group: &'a wgpu::BindGroup,
     );
-    fn draw_model_instanced_with_material(
-        &mut self,
-        model: &'a Model,
-        material: &'a Material,
-        instances: Range<u32>,
-        camera_bind_group: &'a wgpu::BindGroup,
-    );
 }
 
 impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
 where
     'b: 'a,
 {
-    fn draw_mesh(
-        &mut self,
-        mesh: &'b Mesh,
-        material: &'b Material,
-        camera_bind_group: &'b wgpu::BindGroup,
-    ) {
-        self.draw_mesh_instanced(mesh, material, 0..1, camera_bind_group);
-    }
-
     fn draw_mesh_instanced(
         &mut self,
         mesh: &'b Mesh,
@@ -328,19 +827,13 @@ where
         instances: Range<u32>,
         camera_bind_group: &'b wgpu::BindGroup,
     ) {
+        self.push_debug_group(&format!("{} ({})", mesh.name, material.name));
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
         self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
         self.set_bind_group(0, &material.bind_group, &[]);
         self.set_bind_group(1, camera_bind_group, &[]);
         self.draw_indexed(0..mesh.num_elements, 0, instances);
-    }
-
-    fn draw_model(
-        &mut self,
-        model: &'b Model,
-        camera_bind_group: &'b wgpu::BindGroup,
-    ) {
-        self.draw_model_instanced(model, 0..1, camera_bind_group);
+        self.pop_debug_group();
     }
 
     fn draw_model_instanced(
@@ -349,6 +842,9 @@ where
         instances: Range<u32>,
         camera_bind_group: &'b wgpu::BindGroup,
     ) {
+        if let Some(deform) = &model.deform {
+            self.set_bind_group(2, &deform.bind_group, &[]);
+        }
         for mesh in &model.meshes {
             let material = &model.materials[mesh.material];
             self.draw_mesh_instanced(
@@ -359,21 +855,95 @@ where
             );
         }
     }
+}
 
-    fn draw_model_instanced_with_material(
-        &mut self,
-        model: &'b Model,
-        material: &'b Material,
-        instances: Range<u32>,
-        camera_bind_group: &'b wgpu::BindGroup,
-    ) {
-        for mesh in &model.meshes {
-            self.draw_mesh_instanced(
-                mesh,
-                material,
-                instances.clone(),
-                camera_bind_group,
-            );
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material(name: &str) -> MaterialData {
+        MaterialData {
+            name: name.to_string(),
+            params: MaterialParams::default(),
+            diffuse_texture: None,
+            normal_texture: None,
+        }
+    }
+
+    /// Meshes using the MTL materials `Skin`, `Hair` and `Hairshadow` in
+    /// that order, and one mesh without `usemtl`.
+    fn model() -> ModelData {
+        let mesh = |name: &str, material| {
+            MeshData::new(name.to_string(), Vec::new(), Vec::new(), material)
+        };
+        ModelData {
+            name: "miku".to_string(),
+            meshes: vec![
+                mesh("face", Some(0)),
+                mesh("hair", Some(1)),
+                mesh("shadow", Some(2)),
+                mesh("loose", None),
+            ],
+            materials: vec![material("Skin"), material("Hair"), material("Hairshadow")],
+            left_handed: false,
+            bone_count: 0,
+            morph_count: 0,
+            morph_offsets: Vec::new(),
         }
     }
+
+    fn bound(data: &ModelData) -> Vec<(&str, &str)> {
+        data.meshes
+            .iter()
+            .map(|mesh| {
+                let material = &data.materials[mesh.material.unwrap()];
+                (mesh.name.as_str(), material.name.as_str())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn binds_by_usemtl_name_and_override() {
+        let mut data = model();
+        data.meshes.pop();
+        let mut overrides = MaterialOverrides::new();
+        overrides.insert("Hairshadow".to_string(), "Hair".to_string());
+        // Even a material of the same name loses to the override
+        overrides.insert("Skin".to_string(), "Body".to_string());
+        let materials = vec![
+            material("Hair"),
+            material("Skin"),
+            material("Body"),
+            material("Eyes"),
+        ];
+        let unused = data.bind_materials(materials, &overrides).unwrap();
+
+        assert_eq!(
+            bound(&data),
+            [("face", "Body"), ("hair", "Hair"), ("shadow", "Hair")]
+        );
+        assert_eq!(unused, ["Skin", "Eyes"]);
+    }
+
+    #[test]
+    fn error_lists_every_unmatched_mesh_and_the_materials() {
+        let mut data = model();
+        let mut overrides = MaterialOverrides::new();
+        overrides.insert("Hairshadow".to_string(), "Shadow".to_string());
+        let materials = vec![material("Hair"), material("Eyes")];
+        let error = data.bind_materials(materials, &overrides).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "failed to bind materials of \"miku\"\n\
+             unmatched meshes:\n  \
+             \"face\" uses missing material \"Skin\"\n  \
+             \"shadow\" uses \"Hairshadow\", overridden to missing material \"Shadow\"\n  \
+             \"loose\" has no usemtl material\n\
+             available materials: \"Hair\", \"Eyes\""
+        );
+        // The model is left as it was
+        assert_eq!(data.materials.len(), 3);
+        assert_eq!(data.meshes[0].material, Some(0));
+    }
 }
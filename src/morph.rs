@@ -0,0 +1,549 @@
+//! PMX morphs: facial expressions and other keyed changes to a model.
+//!
+//! Each morph has a weight, keyed by a motion or set by name. Group and flip
+//! morphs only pass their weight on to other morphs, so the weights are first
+//! resolved into the weights of the morphs that change something. Vertex and
+//! UV morphs are then applied by the vertex shader from the offsets in
+//! [`crate::model::ModelData::morph_offsets`], bone morphs through the
+//! skeleton and material morphs by rewriting the material uniforms.
+
+use cgmath::prelude::*;
+use cgmath::{Quaternion, Vector3, Vector4};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::interpolation::slerp;
+use crate::model::{MaterialParams, MaterialTints, Model, ModelVertex, MorphOffset, TextureTint};
+use crate::pmx::{self, MaterialOperation, MorphOffsets};
+use crate::skeleton::Skeleton;
+
+pub struct Morphs {
+    pub morphs: Vec<pmx::Morph>,
+    /// Weight of every morph as keyed, before group and flip morphs pass
+    /// theirs on.
+    pub weights: Vec<f32>,
+    /// The materials before any material morph, one per PMX material.
+    materials: Vec<MaterialParams>,
+    by_name: HashMap<String, usize>,
+}
+
+impl Morphs {
+    /// The morphs of `model`, all at weight 0. `materials` are the material
+    /// parameters the model was loaded with.
+    pub fn new(model: &pmx::PmxModel, materials: Vec<MaterialParams>) -> Self {
+        // The first of several morphs with the same name wins, as in MMD
+        let mut by_name = HashMap::new();
+        for (index, morph) in model.morphs.iter().enumerate() {
+            by_name.entry(morph.name.clone()).or_insert(index);
+        }
+        Self {
+            morphs: model.morphs.clone(),
+            weights: vec![0.0; model.morphs.len()],
+            materials,
+            by_name,
+        }
+    }
+
+    pub fn morph_index(&self, name: &str) -> Option<usize> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Sets the weight of the morph called `name`. Returns false if the model
+    /// has no such morph.
+    pub fn set_weight(&mut self, name: &str, weight: f32) -> bool {
+        match self.morph_index(name) {
+            Some(index) => {
+                self.weights[index] = weight;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The weight every morph is applied with once group and flip morphs
+    /// have passed theirs on; those two always get 0.
+    pub fn resolved_weights(&self) -> Vec<f32> {
+        let mut resolved = vec![0.0; self.morphs.len()];
+        for (index, &weight) in self.weights.iter().enumerate() {
+            if weight != 0.0 {
+                self.resolve(index, weight, &mut resolved, &mut Vec::new());
+            }
+        }
+        resolved
+    }
+
+    /// Adds `weight` of morph `index` to `resolved`, `path` holding the group
+    /// and flip morphs it was reached through.
+    fn resolve(&self, index: usize, weight: f32, resolved: &mut [f32], path: &mut Vec<usize>) {
+        // Morphs that contain themselves are skipped where they loop
+        let count = self.morphs.len();
+        let child = |morph: i32, path: &[usize]| {
+            usize::try_from(morph)
+                .ok()
+                .filter(|&morph| morph < count && !path.contains(&morph))
+        };
+        path.push(index);
+        match &self.morphs[index].offsets {
+            MorphOffsets::Group(children) => {
+                for &(morph, factor) in children {
+                    if let Some(morph) = child(morph, path) {
+                        self.resolve(morph, weight * factor, resolved, path);
+                    }
+                }
+            }
+            // Applies one of its morphs in full, picked by the weight: the
+            // morphs share the range above 0 evenly
+            MorphOffsets::Flip(children) if weight > 0.0 && !children.is_empty() => {
+                let pick =
+                    ((weight * children.len() as f32).ceil() as usize).clamp(1, children.len());
+                let (morph, factor) = children[pick - 1];
+                if let Some(morph) = child(morph, path) {
+                    self.resolve(morph, factor, resolved, path);
+                }
+            }
+            MorphOffsets::Flip(_) => {}
+            _ => resolved[index] += weight,
+        }
+        path.pop();
+    }
+
+    /// Applies the resolved weights of bone morphs to `skeleton`, replacing
+    /// what bone morphs did before.
+    pub fn apply_bones(&self, resolved: &[f32], skeleton: &mut Skeleton) {
+        for translation in &mut skeleton.morph_translation {
+            *translation = Vector3::zero();
+        }
+        for rotation in &mut skeleton.morph_rotation {
+            *rotation = Quaternion::one();
+        }
+        for (morph, &weight) in self.morphs.iter().zip(resolved) {
+            let offsets = match &morph.offsets {
+                MorphOffsets::Bone(offsets) if weight != 0.0 => offsets,
+                _ => continue,
+            };
+            for &(bone, translation, rotation) in offsets {
+                let bone = match usize::try_from(bone) {
+                    Ok(bone) if bone < skeleton.bones.len() => bone,
+                    _ => continue,
+                };
+                let rotation = Quaternion::new(rotation.w, rotation.x, rotation.y, rotation.z);
+                skeleton.morph_translation[bone] += translation * weight;
+                skeleton.morph_rotation[bone] =
+                    skeleton.morph_rotation[bone] * slerp(Quaternion::one(), rotation, weight);
+            }
+        }
+    }
+
+    /// Whether any morph changes materials, so that they have to be
+    /// rewritten when weights change.
+    pub fn has_material_morphs(&self) -> bool {
+        self.morphs
+            .iter()
+            .any(|morph| matches!(morph.offsets, MorphOffsets::Material(_)))
+    }
+
+    /// The parameters and tints of every material under the resolved
+    /// weights. As in MMD, multiplications are applied before additions.
+    pub fn material_params(&self, resolved: &[f32]) -> Vec<(MaterialParams, MaterialTints)> {
+        let count = self.materials.len();
+        let mut multiply = vec![MaterialMorphValues::one(); count];
+        let mut add = vec![MaterialMorphValues::zero(); count];
+        for (morph, &weight) in self.morphs.iter().zip(resolved) {
+            let offsets = match &morph.offsets {
+                MorphOffsets::Material(offsets) if weight != 0.0 => offsets,
+                _ => continue,
+            };
+            for offset in offsets {
+                let values = MaterialMorphValues::from(offset);
+                let targets = match usize::try_from(offset.material) {
+                    Ok(material) if material < count => material..material + 1,
+                    Ok(_) => continue,
+                    // -1 is every material
+                    Err(_) => 0..count,
+                };
+                for material in targets {
+                    match offset.operation {
+                        MaterialOperation::Multiply => {
+                            multiply[material].multiply_towards(&values, weight)
+                        }
+                        MaterialOperation::Add => add[material].add(&values, weight),
+                    }
+                }
+            }
+        }
+
+        self.materials
+            .iter()
+            .zip(multiply.iter().zip(&add))
+            .map(|(params, (multiply, add))| {
+                let apply = |value: f32, multiply: f32, add: f32| value * multiply + add;
+                let mut params = *params;
+                for i in 0..3 {
+                    params.diffuse[i] =
+                        apply(params.diffuse[i], multiply.diffuse[i], add.diffuse[i]);
+                    params.specular[i] =
+                        apply(params.specular[i], multiply.specular[i], add.specular[i]);
+                    params.ambient[i] =
+                        apply(params.ambient[i], multiply.ambient[i], add.ambient[i]);
+                }
+                params.dissolve = apply(params.dissolve, multiply.diffuse.w, add.diffuse.w);
+                params.shininess = apply(
+                    params.shininess,
+                    multiply.specular_strength,
+                    add.specular_strength,
+                );
+                for i in 0..4 {
+                    params.edge_color[i] = apply(
+                        params.edge_color[i],
+                        multiply.edge_color[i],
+                        add.edge_color[i],
+                    );
+                }
+                params.edge_size = apply(params.edge_size, multiply.edge_size, add.edge_size);
+                let tint = |multiply: Vector4<f32>, add: Vector4<f32>| TextureTint {
+                    multiply: multiply.into(),
+                    add: add.into(),
+                };
+                let tints = MaterialTints {
+                    texture: tint(multiply.texture_tint, add.texture_tint),
+                    sphere: tint(multiply.sphere_tint, add.sphere_tint),
+                    toon: tint(multiply.toon_tint, add.toon_tint),
+                };
+                (params, tints)
+            })
+            .collect()
+    }
+
+    /// Resolves the weights and applies them to the skeleton, if there is
+    /// one, and to the materials and morph weights of `model`. Returns the
+    /// resolved weights.
+    pub fn apply(
+        &self,
+        skeleton: Option<&mut Skeleton>,
+        model: &Model,
+        queue: &wgpu::Queue,
+    ) -> Vec<f32> {
+        let resolved = self.resolved_weights();
+        if let Some(skeleton) = skeleton {
+            self.apply_bones(&resolved, skeleton);
+        }
+        if self.has_material_morphs() {
+            let params = self.material_params(&resolved);
+            for (material, (params, tints)) in model.materials.iter().zip(&params) {
+                material.write_params(queue, params, tints);
+            }
+        }
+        model.write_morph_weights(queue, &resolved);
+        resolved
+    }
+}
+
+/// The material values a material morph changes.
+#[derive(Debug, Clone, Copy)]
+struct MaterialMorphValues {
+    diffuse: Vector4<f32>,
+    specular: Vector3<f32>,
+    specular_strength: f32,
+    ambient: Vector3<f32>,
+    edge_color: Vector4<f32>,
+    edge_size: f32,
+    texture_tint: Vector4<f32>,
+    sphere_tint: Vector4<f32>,
+    toon_tint: Vector4<f32>,
+}
+
+impl MaterialMorphValues {
+    fn one() -> Self {
+        Self {
+            diffuse: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            specular: Vector3::new(1.0, 1.0, 1.0),
+            specular_strength: 1.0,
+            ambient: Vector3::new(1.0, 1.0, 1.0),
+            edge_color: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            edge_size: 1.0,
+            texture_tint: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            sphere_tint: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            toon_tint: Vector4::new(1.0, 1.0, 1.0, 1.0),
+        }
+    }
+
+    fn zero() -> Self {
+        Self {
+            diffuse: Vector4::zero(),
+            specular: Vector3::zero(),
+            specular_strength: 0.0,
+            ambient: Vector3::zero(),
+            edge_color: Vector4::zero(),
+            edge_size: 0.0,
+            texture_tint: Vector4::zero(),
+            sphere_tint: Vector4::zero(),
+            toon_tint: Vector4::zero(),
+        }
+    }
+
+    /// Scales by `values` at `weight`, by 1 at weight 0.
+    fn multiply_towards(&mut self, values: &Self, weight: f32) {
+        let towards = |value: f32| 1.0 + (value - 1.0) * weight;
+        self.diffuse = self.diffuse.mul_element_wise(values.diffuse.map(towards));
+        self.specular = self.specular.mul_element_wise(values.specular.map(towards));
+        self.specular_strength *= towards(values.specular_strength);
+        self.ambient = self.ambient.mul_element_wise(values.ambient.map(towards));
+        self.edge_color = self
+            .edge_color
+            .mul_element_wise(values.edge_color.map(towards));
+        self.edge_size *= towards(values.edge_size);
+        self.texture_tint = self
+            .texture_tint
+            .mul_element_wise(values.texture_tint.map(towards));
+        self.sphere_tint = self
+            .sphere_tint
+            .mul_element_wise(values.sphere_tint.map(towards));
+        self.toon_tint = self
+            .toon_tint
+            .mul_element_wise(values.toon_tint.map(towards));
+    }
+
+    fn add(&mut self, values: &Self, weight: f32) {
+        self.diffuse += values.diffuse * weight;
+        self.specular += values.specular * weight;
+        self.specular_strength += values.specular_strength * weight;
+        self.ambient += values.ambient * weight;
+        self.edge_color += values.edge_color * weight;
+        self.edge_size += values.edge_size * weight;
+        self.texture_tint += values.texture_tint * weight;
+        self.sphere_tint += values.sphere_tint * weight;
+        self.toon_tint += values.toon_tint * weight;
+    }
+}
+
+impl From<&pmx::MaterialMorph> for MaterialMorphValues {
+    fn from(morph: &pmx::MaterialMorph) -> Self {
+        Self {
+            diffuse: morph.diffuse,
+            specular: morph.specular,
+            specular_strength: morph.specular_strength,
+            ambient: morph.ambient,
+            edge_color: morph.edge_color,
+            edge_size: morph.edge_size,
+            texture_tint: morph.texture_tint,
+            sphere_tint: morph.sphere_tint,
+            toon_tint: morph.toon_tint,
+        }
+    }
+}
+
+/// A copy of `vertices` moved by the morph offsets under the resolved
+/// weights, for when the vertex shader does not apply them.
+pub fn morph_vertices(
+    vertices: &[ModelVertex],
+    offsets: &[MorphOffset],
+    resolved: &[f32],
+) -> Vec<ModelVertex> {
+    vertices
+        .iter()
+        .map(|vertex| {
+            let mut vertex = *vertex;
+            let [start, count] = vertex.morph_range;
+            let range = start as usize..(start + count) as usize;
+            for offset in offsets.get(range).unwrap_or(&[]) {
+                let weight = resolved.get(offset.morph as usize).copied().unwrap_or(0.0);
+                if weight == 0.0 {
+                    continue;
+                }
+                for i in 0..3 {
+                    vertex.position[i] += offset.position[i] * weight;
+                }
+                for i in 0..2 {
+                    vertex.tex_coords[i] += offset.uv[i] * weight;
+                }
+            }
+            vertex
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn morphs(offsets: Vec<MorphOffsets>, materials: usize) -> Morphs {
+        let mut model = pmx::PmxModel::empty();
+        model.morphs = offsets
+            .into_iter()
+            .enumerate()
+            .map(|(i, offsets)| pmx::Morph {
+                name: format!("morph{}", i),
+                name_en: String::new(),
+                panel: 4,
+                offsets,
+            })
+            .collect();
+        let materials = vec![
+            MaterialParams {
+                diffuse: [1.0, 0.5, 0.0],
+                ..Default::default()
+            };
+            materials
+        ];
+        Morphs::new(&model, materials)
+    }
+
+    fn vertex() -> MorphOffsets {
+        MorphOffsets::Vertex(vec![(0, Vector3::new(0.0, 1.0, 0.0))])
+    }
+
+    fn assert_weights(actual: &[f32], expected: &[f32]) {
+        let close = actual.len() == expected.len()
+            && actual
+                .iter()
+                .zip(expected)
+                .all(|(a, e)| (a - e).abs() < 1e-6);
+        assert!(close, "{:?} is not {:?}", actual, expected);
+    }
+
+    #[test]
+    fn groups_multiply_their_weights() {
+        let mut morphs = morphs(
+            vec![
+                vertex(),
+                MorphOffsets::Group(vec![(0, 0.5)]),
+                MorphOffsets::Group(vec![(1, 0.5), (0, 1.0), (7, 1.0)]),
+            ],
+            0,
+        );
+        morphs.weights = vec![0.0, 0.4, 1.0];
+        // 0.4 * 0.5 + 1.0 * 0.5 * 0.5 + 1.0 * 1.0, and nothing for the
+        // groups themselves or the missing morph
+        assert_weights(&morphs.resolved_weights(), &[1.45, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn flips_pick_one_morph_by_weight() {
+        let mut morphs = morphs(
+            vec![
+                vertex(),
+                vertex(),
+                vertex(),
+                MorphOffsets::Flip(vec![(0, 1.0), (1, 0.5), (2, 0.25)]),
+                MorphOffsets::Flip(vec![(0, 1.0), (1, 1.0)]),
+            ],
+            0,
+        );
+        let mut resolve = |index: usize, weight: f32| {
+            morphs.weights = vec![0.0; 5];
+            morphs.weights[index] = weight;
+            morphs.resolved_weights()
+        };
+        // Applied in full, not scaled by the flip's weight
+        assert_weights(&resolve(3, 0.1), &[1.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_weights(&resolve(3, 0.5), &[0.0, 0.5, 0.0, 0.0, 0.0]);
+        assert_weights(&resolve(3, 1.0), &[0.0, 0.0, 0.25, 0.0, 0.0]);
+        // Each morph's share includes its upper end
+        assert_weights(&resolve(4, 0.5), &[1.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_weights(&resolve(4, 0.51), &[0.0, 1.0, 0.0, 0.0, 0.0]);
+        assert_weights(&resolve(4, 0.0), &[0.0; 5]);
+        assert_weights(&resolve(4, -1.0), &[0.0; 5]);
+    }
+
+    #[test]
+    fn cycles_are_skipped() {
+        let mut morphs = morphs(
+            vec![
+                MorphOffsets::Group(vec![(1, 1.0)]),
+                MorphOffsets::Group(vec![(0, 1.0), (2, 1.0)]),
+                vertex(),
+                MorphOffsets::Group(vec![(3, 1.0), (2, 0.5)]),
+                MorphOffsets::Flip(vec![(4, 1.0)]),
+            ],
+            0,
+        );
+        morphs.weights = vec![1.0, 0.0, 0.0, 1.0, 1.0];
+        assert_weights(&morphs.resolved_weights(), &[0.0, 0.0, 1.5, 0.0, 0.0]);
+    }
+
+    /// A material morph that changes nothing but the diffuse colour.
+    fn material_morph(
+        material: i32,
+        operation: MaterialOperation,
+        diffuse: Vector4<f32>,
+    ) -> pmx::MaterialMorph {
+        let unchanged = match operation {
+            MaterialOperation::Multiply => 1.0,
+            MaterialOperation::Add => 0.0,
+        };
+        pmx::MaterialMorph {
+            material,
+            operation,
+            diffuse,
+            specular: Vector3::new(unchanged, unchanged, unchanged),
+            specular_strength: unchanged,
+            ambient: Vector3::new(unchanged, unchanged, unchanged),
+            edge_color: Vector4::new(unchanged, unchanged, unchanged, unchanged),
+            edge_size: unchanged,
+            texture_tint: Vector4::new(unchanged, unchanged, unchanged, unchanged),
+            sphere_tint: Vector4::new(unchanged, unchanged, unchanged, unchanged),
+            toon_tint: Vector4::new(unchanged, unchanged, unchanged, unchanged),
+        }
+    }
+
+    #[test]
+    fn materials_multiply_then_add() {
+        let mut morphs = morphs(
+            vec![MorphOffsets::Material(vec![
+                // Listed first, still applied after the multiplication
+                material_morph(1, MaterialOperation::Add, Vector4::new(0.2, 0.2, 0.2, 0.0)),
+                material_morph(
+                    -1,
+                    MaterialOperation::Multiply,
+                    Vector4::new(0.5, 0.5, 0.5, 0.0),
+                ),
+                material_morph(5, MaterialOperation::Add, Vector4::new(1.0, 1.0, 1.0, 1.0)),
+            ])],
+            2,
+        );
+        morphs.weights = vec![0.5];
+        let params = morphs.material_params(&morphs.resolved_weights());
+
+        // Halfway towards half the colour and no alpha
+        assert_weights(&params[0].0.diffuse, &[0.75, 0.375, 0.0]);
+        assert_weights(&[params[0].0.dissolve], &[0.5]);
+        assert_weights(&params[1].0.diffuse, &[0.85, 0.475, 0.1]);
+        assert_weights(&[params[1].0.dissolve], &[0.5]);
+        for (params, tints) in &params {
+            assert_weights(&params.ambient, &[1.0; 3]);
+            assert_eq!(*tints, MaterialTints::default());
+        }
+
+        morphs.weights = vec![0.0];
+        let params = morphs.material_params(&morphs.resolved_weights());
+        assert_weights(&params[1].0.diffuse, &[1.0, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn edges_and_tints_are_morphed() {
+        let mut multiply = material_morph(
+            0,
+            MaterialOperation::Multiply,
+            Vector4::new(1.0, 1.0, 1.0, 1.0),
+        );
+        multiply.edge_color = Vector4::new(0.0, 0.5, 1.0, 1.0);
+        multiply.edge_size = 3.0;
+        multiply.toon_tint = Vector4::new(0.0, 0.0, 0.0, 0.0);
+        let mut add = material_morph(0, MaterialOperation::Add, Vector4::zero());
+        add.edge_color = Vector4::new(0.0, 0.0, 0.0, -0.5);
+        add.edge_size = 1.0;
+        add.sphere_tint = Vector4::new(1.0, 0.0, 0.0, 0.0);
+        let mut morphs = morphs(vec![MorphOffsets::Material(vec![multiply, add])], 1);
+        morphs.materials[0].edge_color = [0.2, 0.2, 0.2, 1.0];
+        morphs.materials[0].edge_size = 1.0;
+        morphs.weights = vec![0.5];
+        let (params, tints) = morphs.material_params(&morphs.resolved_weights())[0];
+
+        // Halfway towards each factor, then half of each offset added
+        assert_weights(&params.edge_color, &[0.1, 0.15, 0.2, 0.75]);
+        assert_weights(&[params.edge_size], &[2.5]);
+        assert_eq!(tints.texture, TextureTint::default());
+        assert_weights(&tints.sphere.add, &[0.5, 0.0, 0.0, 0.0]);
+        assert_weights(&tints.toon.multiply, &[0.5; 4]);
+    }
+}
@@ -0,0 +1,167 @@
+/// Options given in front of the file name of an MTL texture statement, e.g.
+/// `map_Kd -o 12 0 0 -s 0.1 0.1 0.1 skin.png`.
+///
+/// tobj keeps the whole statement as the texture name, so the options have
+/// to be split off before the path can be resolved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextureOptions {
+    /// `-o u v w`: shifts the texture origin.
+    pub offset: [f32; 3],
+    /// `-s u v w`: scales the texture pattern.
+    pub scale: [f32; 3],
+    /// `-clamp on|off`: restricts coordinates to 0..1 instead of repeating.
+    pub clamp: bool,
+    /// `-blendu on|off`: horizontal texture blending. Parsed for
+    /// completeness; it does not change how coordinates wrap.
+    pub blend_u: bool,
+    /// `-blendv on|off`: vertical texture blending, like `blend_u`.
+    pub blend_v: bool,
+}
+
+impl Default for TextureOptions {
+    fn default() -> Self {
+        Self {
+            offset: [0.0; 3],
+            scale: [1.0; 3],
+            clamp: false,
+            blend_u: true,
+            blend_v: true,
+        }
+    }
+}
+
+impl TextureOptions {
+    /// Splits `statement` into its options and the texture path. Unknown
+    /// options are skipped with a warning; malformed values leave the default
+    /// in place.
+    pub fn parse(statement: &str) -> (Self, String) {
+        let mut options = Self::default();
+        let mut rest = statement.trim();
+
+        while rest.starts_with('-') {
+            let (option, tail) = split_word(rest);
+            rest = tail;
+            match option {
+                "-o" => rest = parse_vector(rest, &mut options.offset),
+                "-s" => rest = parse_vector(rest, &mut options.scale),
+                "-clamp" => rest = parse_switch(rest, &mut options.clamp),
+                "-blendu" => rest = parse_switch(rest, &mut options.blend_u),
+                "-blendv" => rest = parse_switch(rest, &mut options.blend_v),
+                // Options that do not affect texture coordinates
+                "-t" => rest = parse_vector(rest, &mut [0.0; 3]),
+                "-mm" => rest = skip_words(rest, 2),
+                "-bm" | "-boost" | "-texres" | "-imfchan" | "-type" => rest = skip_words(rest, 1),
+                "-cc" => rest = parse_switch(rest, &mut false),
+                _ => {
+                    log::warn!("unknown texture option {:?} in {:?}", option, statement);
+                    rest = skip_words(rest, 1);
+                }
+            }
+        }
+
+        (options, rest.to_string())
+    }
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+fn skip_words(mut text: &str, count: usize) -> &str {
+    for _ in 0..count {
+        text = split_word(text).1;
+    }
+    text
+}
+
+/// Reads up to three numbers; `-o` and `-s` allow the v and w values to be
+/// left out.
+fn parse_vector<'a>(mut text: &'a str, values: &mut [f32; 3]) -> &'a str {
+    for value in values.iter_mut() {
+        let (word, tail) = split_word(text);
+        match word.parse::<f32>() {
+            Ok(number) => {
+                *value = number;
+                text = tail;
+            }
+            Err(_) => break,
+        }
+    }
+    text
+}
+
+fn parse_switch<'a>(text: &'a str, value: &mut bool) -> &'a str {
+    let (word, tail) = split_word(text);
+    match word {
+        "on" => *value = true,
+        "off" => *value = false,
+        _ => return text,
+    }
+    tail
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_and_scale_without_v_and_w() {
+        let (options, path) = TextureOptions::parse("-o 0.5 -s 2 3 skin.png");
+        assert_eq!(options.offset, [0.5, 0.0, 0.0]);
+        assert_eq!(options.scale, [2.0, 3.0, 1.0]);
+        assert_eq!(path, "skin.png");
+
+        let (options, path) = TextureOptions::parse("-s 0.1 0.2 0.3 -o 12 0 0 skin.png");
+        assert_eq!(options.offset, [12.0, 0.0, 0.0]);
+        assert_eq!(options.scale, [0.1, 0.2, 0.3]);
+        assert_eq!(path, "skin.png");
+    }
+
+    #[test]
+    fn switches() {
+        let (options, _) = TextureOptions::parse("-clamp on -blendu off skin.png");
+        assert!(options.clamp);
+        assert!(!options.blend_u);
+        assert!(options.blend_v);
+
+        let (options, path) = TextureOptions::parse("-clamp off -blendv off skin.png");
+        assert!(!options.clamp);
+        assert!(!options.blend_v);
+        assert_eq!(path, "skin.png");
+
+        // Not a switch value, so it is the file name
+        let (options, path) = TextureOptions::parse("-clamp skin.png");
+        assert!(!options.clamp);
+        assert_eq!(path, "skin.png");
+    }
+
+    #[test]
+    fn options_that_do_not_move_coordinates_are_skipped() {
+        let (options, path) =
+            TextureOptions::parse("-bm 0.5 -mm 0 1 -t 1 1 -cc on -imfchan l bump.png");
+        assert_eq!(options, TextureOptions::default());
+        assert_eq!(path, "bump.png");
+    }
+
+    #[test]
+    fn unknown_options_skip_their_argument() {
+        let (options, path) = TextureOptions::parse("-halo 3 -s 2 skin.png");
+        assert_eq!(options.scale, [2.0, 1.0, 1.0]);
+        assert_eq!(path, "skin.png");
+    }
+
+    #[test]
+    fn file_names_with_spaces() {
+        let (options, path) = TextureOptions::parse("  -o 1 1 textures\\my skin 2.png ");
+        assert_eq!(options.offset, [1.0, 1.0, 0.0]);
+        assert_eq!(path, "textures\\my skin 2.png");
+
+        let (options, path) = TextureOptions::parse("my skin.png");
+        assert_eq!(options, TextureOptions::default());
+        assert_eq!(path, "my skin.png");
+    }
+}
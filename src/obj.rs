@@ -0,0 +1,491 @@
+use anyhow::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use tobj::LoadOptions;
+
+use crate::geometry::{self, UvProjection};
+use crate::model::{MaterialData, MaterialParams, MeshData, ModelData, ModelVertex};
+use crate::mtl::TextureOptions;
+use crate::resolver::TextureResolver;
+
+/// Choices made while importing that the file itself does not answer.
+#[derive(Debug, Clone, Copy)]
+pub struct ImportOptions {
+    /// Used for meshes exported without texture coordinates.
+    pub uv_projection: UvProjection,
+    /// Largest angle in degrees between faces that are smoothed together
+    /// when normals have to be generated.
+    pub crease_angle: f32,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            uv_projection: UvProjection::default(),
+            crease_angle: 60.0,
+        }
+    }
+}
+
+fn load_options() -> LoadOptions {
+    LoadOptions {
+        triangulate: true,
+        single_index: true,
+        ..Default::default()
+    }
+}
+
+/// Imports the OBJ file at `path` and the MTL files it references. Texture
+/// paths are resolved relative to the OBJ file's folder.
+pub fn load<P: AsRef<Path>>(
+    path: P,
+    resolver: &TextureResolver,
+    options: &ImportOptions,
+) -> Result<ModelData> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("failed to read OBJ file {:?}", path))?;
+
+    let containing_folder = path.parent().context("Directory has no parent")?;
+    load_buf(
+        BufReader::new(file),
+        |mtl_path| tobj::load_mtl(containing_folder.join(mtl_path)),
+        &path.to_string_lossy(),
+        containing_folder,
+        resolver,
+        options,
+    )
+}
+
+/// Imports an OBJ held in memory. `material_loader` is handed the `mtllib`
+/// path and provides the MTL; textures are resolved relative to `model_dir`.
+pub fn load_buf<B, F>(
+    mut buf: B,
+    material_loader: F,
+    name: &str,
+    model_dir: &Path,
+    resolver: &TextureResolver,
+    options: &ImportOptions,
+) -> Result<ModelData>
+where
+    B: BufRead,
+    F: Fn(&Path) -> tobj::MTLLoadResult,
+{
+    let mut source = String::new();
+    buf.read_to_string(&mut source)
+        .with_context(|| format!("failed to read OBJ data {:?}", name))?;
+
+    load_source(&source, material_loader, name, model_dir, resolver, options)
+}
+
+fn load_source<F>(
+    source: &str,
+    material_loader: F,
+    name: &str,
+    model_dir: &Path,
+    resolver: &TextureResolver,
+    options: &ImportOptions,
+) -> Result<ModelData>
+where
+    F: Fn(&Path) -> tobj::MTLLoadResult,
+{
+    let (obj_models, obj_materials) =
+        tobj::load_obj_buf(&mut source.as_bytes(), &load_options(), material_loader)
+            .with_context(|| format!("failed to parse OBJ data {:?}", name))?;
+
+    let obj_materials = obj_materials.unwrap_or_else(|e| {
+        log::warn!("{}: could not load MTL file: {}", name, e);
+        Vec::new()
+    });
+
+    let materials = obj_materials
+        .iter()
+        .map(|mat| {
+            let (texture_options, diffuse_path) = TextureOptions::parse(&mat.diffuse_texture);
+            // Materials without a texture are drawn with their Kd colour alone
+            let diffuse_texture = if diffuse_path.is_empty() {
+                None
+            } else {
+                Some(resolver.resolve(model_dir, &diffuse_path))
+            };
+
+            // `bump` and `map_Bump` end up in normal_texture, `norm` does not
+            let normal_statement = match mat.unknown_param.get("norm") {
+                Some(norm) if mat.normal_texture.is_empty() => norm,
+                _ => &mat.normal_texture,
+            };
+            let (_, normal_path) = TextureOptions::parse(normal_statement);
+            let normal_texture = if normal_path.is_empty() {
+                None
+            } else {
+                resolver.find(model_dir, &normal_path).or_else(|| {
+                    log::warn!("{}: normal map {:?} not found", name, normal_path);
+                    None
+                })
+            };
+
+            MaterialData {
+                name: mat.name.clone(),
+                params: material_params(mat, texture_options),
+                diffuse_texture,
+                normal_texture,
+            }
+        })
+        .collect();
+
+    let mut smoothing_groups = smoothing_groups(source, &obj_materials).into_iter();
+    let meshes = obj_models
+        .into_iter()
+        .map(|m| {
+            let groups = smoothing_groups.next().flatten();
+            convert_mesh(m, groups, options)
+        })
+        .collect();
+
+    Ok(ModelData {
+        name: name.to_string(),
+        meshes,
+        materials,
+        left_handed: false,
+        bone_count: 0,
+        morph_count: 0,
+        morph_offsets: Vec::new(),
+    })
+}
+
+/// tobj ignores `s` statements, so they are read in a second pass over the
+/// source that splits it into objects the same way tobj does. Yields the
+/// smoothing group of every triangle of every object, or `None` for all
+/// objects when the file has no `s` statements.
+fn smoothing_groups(source: &str, materials: &[tobj::Material]) -> Vec<Option<Vec<u32>>> {
+    let material_ids = materials
+        .iter()
+        .enumerate()
+        .map(|(id, mat)| (mat.name.as_str(), id))
+        .collect::<HashMap<_, _>>();
+
+    let mut objects = Vec::new();
+    let mut current = Vec::new();
+    let mut group = None;
+    let mut material_id = None;
+
+    for line in source.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("f") | Some("l") => {
+                // tobj fans polygons and pads points and lines to triangles
+                let triangles = match words.count() {
+                    0 => 0,
+                    1 | 2 => 1,
+                    corners => corners - 2,
+                };
+                current.extend(std::iter::repeat_n(group, triangles));
+            }
+            Some("o") | Some("g") if !current.is_empty() => {
+                objects.push(std::mem::take(&mut current));
+            }
+            Some("usemtl") => {
+                let name = line.split_once(' ').unwrap_or_default().1.trim();
+                if !name.is_empty() {
+                    let id = material_ids.get(name).copied();
+                    if id != material_id && !current.is_empty() {
+                        objects.push(std::mem::take(&mut current));
+                    }
+                    material_id = id;
+                }
+            }
+            Some("s") => {
+                group = Some(match words.next() {
+                    Some("off") | None => geometry::FLAT_GROUP,
+                    Some(number) => number.parse().unwrap_or(1),
+                });
+            }
+            _ => {}
+        }
+    }
+    objects.push(current);
+
+    if objects.iter().flatten().all(Option::is_none) {
+        return Vec::new();
+    }
+    objects
+        .into_iter()
+        .map(|groups| {
+            // Faces before the first `s` statement are smoothed together
+            Some(
+                groups
+                    .into_iter()
+                    .map(|group| group.unwrap_or(u32::MAX))
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+fn convert_mesh(
+    m: tobj::Model,
+    smoothing_groups: Option<Vec<u32>>,
+    options: &ImportOptions,
+) -> MeshData {
+    let mesh = m.mesh;
+    let vertex_count = mesh.positions.len() / 3;
+    let mut warnings = Vec::new();
+
+    let has_tex_coords = mesh.texcoords.len() == vertex_count * 2;
+    if !has_tex_coords {
+        warnings.push(if mesh.texcoords.is_empty() {
+            format!(
+                "no texture coordinates, using {:?} projection",
+                options.uv_projection
+            )
+        } else {
+            format!(
+                "{} texture coordinates for {} vertices, using {:?} projection",
+                mesh.texcoords.len() / 2,
+                vertex_count,
+                options.uv_projection
+            )
+        });
+    }
+    let has_normals = mesh.normals.len() == vertex_count * 3;
+    if !has_normals && !mesh.normals.is_empty() {
+        warnings.push(format!(
+            "{} normals for {} vertices, generating them instead",
+            mesh.normals.len() / 3,
+            vertex_count
+        ));
+    }
+
+    let mut vertices = Vec::with_capacity(vertex_count);
+    for i in 0..vertex_count {
+        vertices.push(ModelVertex {
+            position: [
+                mesh.positions[i * 3],
+                mesh.positions[i * 3 + 1],
+                mesh.positions[i * 3 + 2],
+            ],
+            tex_coords: if has_tex_coords {
+                [mesh.texcoords[i * 2], mesh.texcoords[i * 2 + 1]]
+            } else {
+                [0.0; 2]
+            },
+            normal: if has_normals {
+                [
+                    mesh.normals[i * 3],
+                    mesh.normals[i * 3 + 1],
+                    mesh.normals[i * 3 + 2],
+                ]
+            } else {
+                [0.0; 3]
+            },
+            ..Default::default()
+        });
+    }
+    if !has_tex_coords {
+        geometry::project_uvs(&mut vertices, options.uv_projection);
+    }
+
+    // Triangles with an index past the vertices are dropped whole, along with
+    // their smoothing group, so that the ones after them stay intact
+    let in_range = |triangle: &[u32]| {
+        triangle
+            .iter()
+            .all(|&index| (index as usize) < vertex_count)
+    };
+    let triangle_count = mesh.indices.len() / 3;
+    let mut indices = mesh
+        .indices
+        .chunks_exact(3)
+        .filter(|triangle| in_range(triangle))
+        .flatten()
+        .copied()
+        .collect::<Vec<_>>();
+    if indices.len() / 3 != triangle_count {
+        warnings.push(format!(
+            "dropped {} triangles that point past the {} vertices",
+            triangle_count - indices.len() / 3,
+            vertex_count
+        ));
+    }
+    let smoothing_groups = smoothing_groups.map(|groups| {
+        if groups.len() != triangle_count {
+            return groups;
+        }
+        groups
+            .into_iter()
+            .zip(mesh.indices.chunks_exact(3))
+            .filter(|(_, triangle)| in_range(triangle))
+            .map(|(group, _)| group)
+            .collect()
+    });
+
+    if !has_normals {
+        let smoothing_groups = smoothing_groups.filter(|groups| {
+            let matches = groups.len() == indices.len() / 3;
+            if !matches {
+                warnings.push("could not match smoothing groups to faces, ignoring them".into());
+            }
+            matches
+        });
+        let (smooth_vertices, smooth_indices) = geometry::generate_normals(
+            &vertices,
+            &indices,
+            smoothing_groups.as_deref(),
+            options.crease_angle,
+        );
+        vertices = smooth_vertices;
+        indices = smooth_indices;
+    }
+    geometry::generate_tangents(&mut vertices, &indices);
+
+    let mut data = MeshData::new(m.name, vertices, indices, mesh.material_id);
+    data.warnings = warnings;
+    data
+}
+
+fn material_params(mat: &tobj::Material, texture_options: TextureOptions) -> MaterialParams {
+    // tobj does not know about Ke and leaves it in unknown_param
+    let emissive = mat
+        .unknown_param
+        .get("Ke")
+        .and_then(|value| {
+            let values = value
+                .split_whitespace()
+                .map(str::parse::<f32>)
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .ok()?;
+            match values[..] {
+                [r, g, b] => Some([r, g, b]),
+                [v] => Some([v; 3]),
+                _ => None,
+            }
+        })
+        .unwrap_or([0.0; 3]);
+
+    MaterialParams {
+        ambient: mat.ambient,
+        diffuse: mat.diffuse,
+        specular: mat.specular,
+        shininess: mat.shininess,
+        emissive,
+        dissolve: mat.dissolve,
+        illumination: mat.illumination_model.unwrap_or(1),
+        texture_options,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const OBJ: &str = "\
+mtllib cube.mtl
+o Top
+v 0 1 0
+v 1 1 0
+v 1 1 1
+v 0 1 1
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+usemtl Red
+f 1/1 4/4 3/3 2/2
+o Side
+v 0 0 0
+v 1 0 0
+v 1 1 0
+usemtl Textured
+f 5 6 7
+";
+
+    const MTL: &str = "\
+newmtl Red
+Kd 1 0 0
+newmtl Textured
+Kd 1 1 1
+d 0.5
+map_Kd -s 2 2 1 -clamp on textures\\missing.png
+";
+
+    fn import(obj: &str) -> ModelData {
+        let resolver = TextureResolver::new(Vec::new(), PathBuf::from("fallback.png"));
+        load_buf(
+            obj.as_bytes(),
+            |_| tobj::load_mtl_buf(&mut MTL.as_bytes()),
+            "test",
+            Path::new("does-not-exist"),
+            &resolver,
+            &ImportOptions::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn imports_meshes_and_materials_headless() {
+        let data = import(OBJ);
+        assert_eq!(data.name, "test");
+        assert!(!data.left_handed);
+        assert_eq!(data.bone_count, 0);
+
+        let names = data
+            .materials
+            .iter()
+            .map(|mat| mat.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["Red", "Textured"]);
+        let red = &data.materials[0];
+        assert_eq!(red.params.diffuse, [1.0, 0.0, 0.0]);
+        assert_eq!(red.diffuse_texture, None);
+        let textured = &data.materials[1];
+        assert_eq!(textured.params.dissolve, 0.5);
+        assert_eq!(textured.params.texture_options.scale, [2.0, 2.0, 1.0]);
+        assert!(textured.params.texture_options.clamp);
+        assert_eq!(
+            textured.diffuse_texture,
+            Some(PathBuf::from("fallback.png"))
+        );
+
+        assert_eq!(data.meshes.len(), 2);
+        let top = &data.meshes[0];
+        assert_eq!(top.name, "Top");
+        assert_eq!(top.material, Some(0));
+        assert_eq!(top.indices.len(), 6);
+        assert!(top.warnings.is_empty(), "{:?}", top.warnings);
+        // Generated normals of the top face point up
+        for vertex in &top.vertices {
+            assert_eq!(vertex.normal, [0.0, 1.0, 0.0]);
+        }
+
+        let side = &data.meshes[1];
+        assert_eq!(side.name, "Side");
+        assert_eq!(side.material, Some(1));
+        assert_eq!(side.indices.len(), 3);
+        assert_eq!(side.warnings.len(), 1, "{:?}", side.warnings);
+
+        let bounds = data.bounds().unwrap();
+        assert_eq!(bounds.min, [0.0, 0.0, 0.0]);
+        assert_eq!(bounds.max, [1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn drops_whole_triangles_past_the_vertices() {
+        let mesh = tobj::Mesh {
+            positions: vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 1.0, 1.0, 0.0],
+            normals: [0.0, 0.0, 1.0].repeat(4),
+            texcoords: vec![0.0; 8],
+            indices: vec![0, 1, 2, 1, 9, 2, 1, 3, 2],
+            ..Default::default()
+        };
+        let data = convert_mesh(
+            tobj::Model::new(mesh, "quad".to_string()),
+            None,
+            &ImportOptions::default(),
+        );
+        assert_eq!(data.indices, [0, 1, 2, 1, 3, 2]);
+        assert_eq!(data.warnings.len(), 1, "{:?}", data.warnings);
+    }
+}
@@ -0,0 +1,464 @@
+//! Rigid-body physics for the hair, skirts and other loose parts of MMD
+//! models, simulated with rapier.
+//!
+//! The bodies and joints come from the PMX rigid bodies and joints. Bodies
+//! that follow their bone are moved to it before every step; simulated
+//! bodies move their bone once the steps are done. Everything stays in the
+//! model's space and units: physics does not care which way the axes turn,
+//! as long as the bones and the bodies agree.
+
+use cgmath::prelude::*;
+use cgmath::{Matrix3, Matrix4, Quaternion, Rad, Vector3};
+use rapier3d::na::{self, Translation3, UnitQuaternion};
+use rapier3d::prelude::{
+    BroadPhase, CCDSolver, ColliderBuilder, ColliderSet, GenericJointBuilder, Group,
+    ImpulseJointSet, IntegrationParameters, InteractionGroups, IslandManager, Isometry,
+    JointAxesMask, JointAxis, MotorModel, MultibodyJointSet, NarrowPhase, PhysicsPipeline,
+    RigidBodyBuilder, RigidBodyHandle, RigidBodySet, Vector,
+};
+use std::convert::TryFrom;
+
+use crate::pmx::{self, PhysicsMode, PmxModel};
+use crate::skeleton::Skeleton;
+
+/// Length of one simulation step in seconds.
+const STEP: f32 = 1.0 / 120.0;
+/// Steps taken at most per update; time beyond them is dropped, so that a
+/// stall does not have to be caught up with all at once.
+const MAX_STEPS: u32 = 8;
+/// MMD's gravity, in its units of about 8 cm.
+const GRAVITY: f32 = -98.0;
+
+/// One PMX rigid body in the simulation.
+struct Body {
+    handle: RigidBodyHandle,
+    bone: Option<usize>,
+    mode: PhysicsMode,
+    /// Whether the simulation moves the body, rather than its bone.
+    simulated: bool,
+    /// The body's transform relative to its bone's, from the rest pose.
+    offset: Matrix4<f32>,
+    offset_inverse: Matrix4<f32>,
+    /// Where a body that follows its bone was after the last update.
+    previous: Isometry<f32>,
+}
+
+pub struct Physics {
+    bodies: Vec<Body>,
+    rigid_bodies: RigidBodySet,
+    colliders: ColliderSet,
+    joints: ImpulseJointSet,
+    multibody_joints: MultibodyJointSet,
+    pipeline: PhysicsPipeline,
+    islands: IslandManager,
+    broad_phase: BroadPhase,
+    narrow_phase: NarrowPhase,
+    ccd_solver: CCDSolver,
+    parameters: IntegrationParameters,
+    /// Time passed that is not yet stepped, less than a step.
+    pending: f32,
+}
+
+impl Physics {
+    /// The rigid bodies and joints of `model`, in the rest pose of
+    /// `skeleton`. `None` if the model has no rigid bodies.
+    pub fn new(model: &PmxModel, skeleton: &Skeleton) -> Option<Self> {
+        if model.rigid_bodies.is_empty() {
+            return None;
+        }
+        let mut rigid_bodies = RigidBodySet::new();
+        let mut colliders = ColliderSet::new();
+        let mut joints = ImpulseJointSet::new();
+
+        let mut rest = Vec::with_capacity(model.rigid_bodies.len());
+        let mut bodies = Vec::with_capacity(model.rigid_bodies.len());
+        for body in &model.rigid_bodies {
+            let bone = usize::try_from(body.bone)
+                .ok()
+                .filter(|&bone| bone < skeleton.bones.len());
+            let transform = Matrix4::from_translation(body.position) * euler_matrix(body.rotation);
+            let offset = match bone {
+                Some(bone) => {
+                    Matrix4::from_translation(-skeleton.bones[bone].rest_position) * transform
+                }
+                None => transform,
+            };
+            let position = to_isometry(&transform);
+
+            // A body without mass cannot be moved by forces, as in Bullet
+            let simulated = body.mode != PhysicsMode::FollowBone && body.mass > 0.0;
+            let builder = if simulated {
+                RigidBodyBuilder::dynamic()
+                    .linear_damping(damping(body.linear_damping))
+                    .angular_damping(damping(body.angular_damping))
+            } else {
+                RigidBodyBuilder::kinematic_position_based()
+            };
+            let handle = rigid_bodies.insert(builder.position(position).build());
+
+            let groups = InteractionGroups::new(
+                Group::from_bits_truncate(1 << body.group.min(15)),
+                Group::from_bits_truncate(body.collision_mask as u32),
+            );
+            let collider = shape_collider(body)
+                .collision_groups(groups)
+                .friction(body.friction)
+                .restitution(body.restitution)
+                .mass(if simulated { body.mass } else { 0.0 })
+                .build();
+            colliders.insert_with_parent(collider, handle, &mut rigid_bodies);
+
+            rest.push(position);
+            bodies.push(Body {
+                handle,
+                bone,
+                mode: body.mode,
+                simulated,
+                offset,
+                offset_inverse: offset.invert().unwrap_or_else(Matrix4::identity),
+                previous: position,
+            });
+        }
+
+        for joint in &model.joints {
+            let [a, b] = joint.rigid_bodies;
+            let (a, b) = match (usize::try_from(a), usize::try_from(b)) {
+                (Ok(a), Ok(b)) if a != b && a < bodies.len() && b < bodies.len() => (a, b),
+                _ => continue,
+            };
+            let frame = to_isometry(
+                &(Matrix4::from_translation(joint.position) * euler_matrix(joint.rotation)),
+            );
+            let joint = spring_joint(joint)
+                .local_frame1(rest[a].inverse() * frame)
+                .local_frame2(rest[b].inverse() * frame)
+                .build();
+            joints.insert(bodies[a].handle, bodies[b].handle, joint, true);
+        }
+
+        Some(Self {
+            bodies,
+            rigid_bodies,
+            colliders,
+            joints,
+            multibody_joints: MultibodyJointSet::new(),
+            pipeline: PhysicsPipeline::new(),
+            islands: IslandManager::new(),
+            broad_phase: BroadPhase::new(),
+            narrow_phase: NarrowPhase::new(),
+            ccd_solver: CCDSolver::new(),
+            parameters: IntegrationParameters {
+                dt: STEP,
+                ..Default::default()
+            },
+            pending: 0.0,
+        })
+    }
+
+    /// Advances the simulation by `elapsed` seconds of animation time and
+    /// moves the bones of simulated bodies. The bones that deform before
+    /// physics have to be evaluated already; those after physics are left to
+    /// [`Skeleton::evaluate_phase`].
+    ///
+    /// Bodies that follow their bone move there evenly over the steps taken.
+    /// The physics switch of VMD bone keyframes,
+    /// [`crate::vmd::BoneKeyframe::physics_disabled`], is ignored: simulated
+    /// bodies are always simulated.
+    pub fn update(&mut self, skeleton: &mut Skeleton, elapsed: f32) {
+        self.pending += elapsed.max(0.0);
+        let steps = (self.pending / STEP).floor();
+        self.pending -= steps * STEP;
+        let steps = (steps as u32).min(MAX_STEPS);
+
+        if steps > 0 {
+            let targets = self
+                .bodies
+                .iter()
+                .map(|body| to_isometry(&(bone_world(skeleton, body.bone) * body.offset)))
+                .collect::<Vec<_>>();
+            for step in 1..=steps {
+                let t = step as f32 / steps as f32;
+                for (body, target) in self.bodies.iter().zip(&targets) {
+                    if body.simulated {
+                        continue;
+                    }
+                    let position = body
+                        .previous
+                        .try_lerp_slerp(target, t, 1e-6)
+                        .unwrap_or(*target);
+                    if let Some(rigid_body) = self.rigid_bodies.get_mut(body.handle) {
+                        rigid_body.set_next_kinematic_position(position);
+                    }
+                }
+                self.step();
+            }
+            for (body, target) in self.bodies.iter_mut().zip(targets) {
+                body.previous = target;
+            }
+        }
+        self.write_bones(skeleton);
+    }
+
+    /// Puts every body where its bone is, at rest, and forgets the time not
+    /// yet stepped; for seeking, after which the last pose means nothing.
+    /// The bones that deform before physics have to be evaluated already.
+    pub fn reset(&mut self, skeleton: &Skeleton) {
+        for body in &mut self.bodies {
+            let position = to_isometry(&(bone_world(skeleton, body.bone) * body.offset));
+            if let Some(rigid_body) = self.rigid_bodies.get_mut(body.handle) {
+                rigid_body.set_position(position, true);
+                if body.simulated {
+                    rigid_body.set_linvel(Vector::zeros(), true);
+                    rigid_body.set_angvel(Vector::zeros(), true);
+                } else {
+                    rigid_body.set_next_kinematic_position(position);
+                }
+            }
+            body.previous = position;
+        }
+        self.pending = 0.0;
+    }
+
+    fn step(&mut self) {
+        self.pipeline.step(
+            &Vector::new(0.0, GRAVITY, 0.0),
+            &self.parameters,
+            &mut self.islands,
+            &mut self.broad_phase,
+            &mut self.narrow_phase,
+            &mut self.rigid_bodies,
+            &mut self.colliders,
+            &mut self.joints,
+            &mut self.multibody_joints,
+            &mut self.ccd_solver,
+            None,
+            &(),
+            &(),
+        );
+    }
+
+    /// Moves the bones of simulated bodies to them, and the bones below that
+    /// have no body of their own along with them.
+    fn write_bones(&self, skeleton: &mut Skeleton) {
+        let mut moved = vec![false; skeleton.bones.len()];
+        for body in self.bodies.iter().filter(|body| body.simulated) {
+            let (bone, rigid_body) = match (body.bone, self.rigid_bodies.get(body.handle)) {
+                (Some(bone), Some(rigid_body)) => (bone, rigid_body),
+                _ => continue,
+            };
+            let mut world = to_matrix(rigid_body.position()) * body.offset_inverse;
+            if body.mode == PhysicsMode::PhysicsWithBone {
+                world.w = skeleton.world[bone].w;
+            }
+            skeleton.world[bone] = world;
+            moved[bone] = true;
+        }
+
+        for i in 0..skeleton.deform_order.len() {
+            let bone = skeleton.deform_order[i];
+            let parent_moved = skeleton.bones[bone]
+                .parent
+                .is_some_and(|parent| moved[parent]);
+            if !moved[bone] && parent_moved && !skeleton.bones[bone].after_physics {
+                skeleton.update_world(bone);
+                moved[bone] = true;
+            }
+        }
+    }
+}
+
+/// The collider of `body`'s shape. Sizes are kept above 0, which rapier
+/// cannot collide.
+fn shape_collider(body: &pmx::RigidBody) -> ColliderBuilder {
+    let size = body.size.map(|size| size.max(1e-3));
+    match body.shape {
+        pmx::Shape::Sphere => ColliderBuilder::ball(size.x),
+        pmx::Shape::Box => ColliderBuilder::cuboid(size.x, size.y, size.z),
+        // The height is that of the cylinder between the two half spheres
+        pmx::Shape::Capsule => ColliderBuilder::capsule_y(size.y * 0.5, size.x),
+    }
+}
+
+/// A PMX joint as a generic joint without its frames. PMX joints are
+/// Bullet's 6DOF springs: an axis is locked when its limits are equal, free
+/// when the lower one is above the upper one, and pulled back to 0 by a
+/// spring when it has one. Other joint kinds are treated the same, as in MMD.
+fn spring_joint(joint: &pmx::Joint) -> GenericJointBuilder {
+    let axes = [
+        (JointAxis::X, JointAxesMask::X, 0),
+        (JointAxis::Y, JointAxesMask::Y, 1),
+        (JointAxis::Z, JointAxesMask::Z, 2),
+        (JointAxis::AngX, JointAxesMask::ANG_X, 0),
+        (JointAxis::AngY, JointAxesMask::ANG_Y, 1),
+        (JointAxis::AngZ, JointAxesMask::ANG_Z, 2),
+    ];
+    let mut locked = JointAxesMask::empty();
+    for (i, &(_, mask, component)) in axes.iter().enumerate() {
+        let (lower, upper) = axis_limits(joint, i < 3, component);
+        if lower == upper {
+            locked |= mask;
+        }
+    }
+
+    let mut builder = GenericJointBuilder::new(locked);
+    for (i, &(axis, mask, component)) in axes.iter().enumerate() {
+        if locked.contains(mask) {
+            continue;
+        }
+        let (lower, upper) = axis_limits(joint, i < 3, component);
+        if lower < upper {
+            builder = builder.limits(axis, [lower, upper]);
+        }
+        let spring = if i < 3 {
+            joint.linear_spring[component]
+        } else {
+            joint.angular_spring[component]
+        };
+        if spring > 0.0 {
+            builder = builder
+                .motor_model(axis, MotorModel::ForceBased)
+                .motor_position(axis, 0.0, spring, 0.0);
+        }
+    }
+    builder
+}
+
+fn axis_limits(joint: &pmx::Joint, linear: bool, component: usize) -> (f32, f32) {
+    if linear {
+        (joint.linear_lower[component], joint.linear_upper[component])
+    } else {
+        (
+            joint.angular_lower[component],
+            joint.angular_upper[component],
+        )
+    }
+}
+
+/// Bullet damping is the part of the velocity lost per second; rapier's
+/// divides it by `1 + damping * dt` every step.
+fn damping(bullet: f32) -> f32 {
+    -(1.0 - bullet.clamp(0.0, 0.999)).ln()
+}
+
+fn bone_world(skeleton: &Skeleton, bone: Option<usize>) -> Matrix4<f32> {
+    bone.map_or_else(Matrix4::identity, |bone| skeleton.world[bone])
+}
+
+/// Rotation from PMX Euler angles, turned around y, then x, then z.
+fn euler_matrix(angles: Vector3<f32>) -> Matrix4<f32> {
+    Matrix4::from(
+        Quaternion::from_angle_y(Rad(angles.y))
+            * Quaternion::from_angle_x(Rad(angles.x))
+            * Quaternion::from_angle_z(Rad(angles.z)),
+    )
+}
+
+/// `matrix` without its scale.
+fn to_isometry(matrix: &Matrix4<f32>) -> Isometry<f32> {
+    let rotation = Quaternion::from(Matrix3::from_cols(
+        matrix.x.truncate().normalize(),
+        matrix.y.truncate().normalize(),
+        matrix.z.truncate().normalize(),
+    ));
+    let rotation = na::Quaternion::new(rotation.s, rotation.v.x, rotation.v.y, rotation.v.z);
+    Isometry::from_parts(
+        Translation3::new(matrix.w.x, matrix.w.y, matrix.w.z),
+        UnitQuaternion::new_normalize(rotation),
+    )
+}
+
+fn to_matrix(isometry: &Isometry<f32>) -> Matrix4<f32> {
+    let translation = isometry.translation.vector;
+    let rotation = isometry.rotation.quaternion();
+    Matrix4::from_translation(Vector3::new(translation.x, translation.y, translation.z))
+        * Matrix4::from(Quaternion::new(
+            rotation.w, rotation.i, rotation.j, rotation.k,
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A joint whose axes are all free, without springs.
+    fn joint() -> pmx::Joint {
+        let free = (Vector3::new(1.0, 1.0, 1.0), Vector3::new(-1.0, -1.0, -1.0));
+        pmx::Joint {
+            name: "joint".to_string(),
+            name_en: String::new(),
+            kind: pmx::JointKind::Spring6Dof,
+            rigid_bodies: [0, 1],
+            position: Vector3::zero(),
+            rotation: Vector3::zero(),
+            linear_lower: free.0,
+            linear_upper: free.1,
+            angular_lower: free.0,
+            angular_upper: free.1,
+            linear_spring: Vector3::zero(),
+            angular_spring: Vector3::zero(),
+        }
+    }
+
+    #[test]
+    fn equal_limits_lock_the_axis() {
+        let mut pmx_joint = joint();
+        pmx_joint.linear_lower = Vector3::zero();
+        pmx_joint.linear_upper = Vector3::zero();
+        pmx_joint.angular_lower.z = 0.5;
+        pmx_joint.angular_upper.z = 0.5;
+        let joint = spring_joint(&pmx_joint).build();
+        assert_eq!(
+            joint.locked_axes,
+            JointAxesMask::X | JointAxesMask::Y | JointAxesMask::Z | JointAxesMask::ANG_Z
+        );
+        assert!(joint.limit_axes.is_empty());
+        assert!(joint.motor_axes.is_empty());
+    }
+
+    #[test]
+    fn lower_above_upper_is_free() {
+        let mut pmx_joint = joint();
+        pmx_joint.angular_lower.x = -0.25;
+        pmx_joint.angular_upper.x = 0.5;
+        let joint = spring_joint(&pmx_joint).build();
+        assert!(joint.locked_axes.is_empty());
+        // Only the one axis with lower below upper is limited
+        assert_eq!(joint.limit_axes, JointAxesMask::ANG_X);
+        let limits = &joint.limits[JointAxis::AngX as usize];
+        assert_eq!((limits.min, limits.max), (-0.25, 0.5));
+    }
+
+    #[test]
+    fn a_spring_gives_a_motor() {
+        let mut pmx_joint = joint();
+        pmx_joint.linear_spring.y = 10.0;
+        pmx_joint.angular_spring.z = 50.0;
+        // A locked axis needs no spring
+        pmx_joint.angular_lower.x = 0.0;
+        pmx_joint.angular_upper.x = 0.0;
+        pmx_joint.angular_spring.x = 50.0;
+        let joint = spring_joint(&pmx_joint).build();
+        assert_eq!(joint.motor_axes, JointAxesMask::Y | JointAxesMask::ANG_Z);
+        let motor = &joint.motors[JointAxis::AngZ as usize];
+        assert_eq!(
+            (motor.target_pos, motor.stiffness, motor.damping),
+            (0.0, 50.0, 0.0)
+        );
+        assert_eq!(motor.model, MotorModel::ForceBased);
+        assert_eq!(joint.motors[JointAxis::Y as usize].stiffness, 10.0);
+    }
+
+    #[test]
+    fn damping_loses_the_bullet_part_per_second() {
+        assert_eq!(damping(0.0), 0.0);
+        for &bullet in &[0.1, 0.5, 0.9] {
+            let rapier = damping(bullet);
+            let steps = (1.0 / STEP).round() as i32;
+            let kept = (1.0 / (1.0 + rapier * STEP)).powi(steps);
+            assert!((kept - (1.0 - bullet)).abs() < 0.01, "{}: {}", bullet, kept);
+        }
+        // Full damping would be infinite
+        assert!(damping(1.0).is_finite());
+        assert_eq!(damping(-1.0), 0.0);
+    }
+}
@@ -0,0 +1,1069 @@
+//! Reader for PMD models, the format of MikuMikuDance before PMX.
+//!
+//! A [`PmdModel`] holds the file as it is; [`PmdModel::to_pmx`] converts it to
+//! the PMX representation the rest of the MMD code works with.
+
+use cgmath::prelude::*;
+use cgmath::{Deg, Rad, Vector2, Vector3, Vector4};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::binary::BinaryReader;
+use crate::pmx::{self, PmxModel};
+
+#[derive(Debug)]
+pub enum PmdError {
+    Io(io::Error),
+    InvalidSignature([u8; 3]),
+    UnsupportedVersion(f32),
+    InvalidValue {
+        what: &'static str,
+        value: i64,
+        offset: u64,
+    },
+}
+
+impl fmt::Display for PmdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "failed to read PMD data: {}", e),
+            Self::InvalidSignature(signature) => {
+                write!(f, "not a PMD file, the signature is {:?}", signature)
+            }
+            Self::UnsupportedVersion(version) => write!(f, "unsupported PMD version {}", version),
+            Self::InvalidValue {
+                what,
+                value,
+                offset,
+            } => write!(f, "invalid {} {} at byte {}", what, value, offset),
+        }
+    }
+}
+
+impl std::error::Error for PmdError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PmdError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, PmdError>;
+
+/// Index value PMD uses for "no bone".
+pub const NO_BONE: u16 = 0xffff;
+
+#[derive(Debug, Clone)]
+pub struct Vertex {
+    pub position: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    pub uv: Vector2<f32>,
+    pub bones: [u16; 2],
+    /// Weight of the first bone, 0 to 100.
+    pub weight: u8,
+    pub no_edge: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Material {
+    pub diffuse: Vector4<f32>,
+    pub specular_strength: f32,
+    pub specular: Vector3<f32>,
+    pub ambient: Vector3<f32>,
+    /// Index into [`PmdModel::toon_textures`], 255 for none.
+    pub toon: u8,
+    pub edge: bool,
+    pub index_count: u32,
+    /// Texture and sphere map, separated by `*` when there are both.
+    pub texture: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoneKind {
+    Rotate,
+    RotateTranslate,
+    Ik,
+    Unknown,
+    /// Rotated by an IK chain.
+    IkLink,
+    /// Copies the rotation of [`Bone::ik_parent`].
+    RotateAppend,
+    IkTarget,
+    Invisible,
+    /// Rotates around the axis towards its tail only.
+    Twist,
+    /// Copies part of the rotation of [`Bone::ik_parent`], the percentage
+    /// being stored in [`Bone::tail`].
+    RotateRatio,
+}
+
+#[derive(Debug, Clone)]
+pub struct Bone {
+    pub name: String,
+    pub name_en: String,
+    pub parent: u16,
+    pub tail: u16,
+    pub kind: BoneKind,
+    /// The IK bone for [`BoneKind::IkLink`], the followed bone for
+    /// [`BoneKind::RotateAppend`] and [`BoneKind::RotateRatio`].
+    pub ik_parent: u16,
+    pub position: Vector3<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Ik {
+    pub bone: u16,
+    pub target: u16,
+    pub iterations: u16,
+    /// Largest rotation per iteration, in units of 4 radians.
+    pub limit: f32,
+    /// From the bone nearest the target up the chain.
+    pub links: Vec<u16>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Skin {
+    pub name: String,
+    pub name_en: String,
+    /// 0 for the base skin, then eyebrow, eye, mouth and other.
+    pub panel: u8,
+    /// The base skin holds absolute vertex indices and positions; every
+    /// other skin holds indices into the base skin and offsets.
+    pub vertices: Vec<(u32, Vector3<f32>)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BoneGroup {
+    pub name: String,
+    pub name_en: String,
+    pub bones: Vec<u16>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RigidBody {
+    pub name: String,
+    pub bone: u16,
+    pub group: u8,
+    /// Bit `n` set means the body collides with group `n`.
+    pub collision_mask: u16,
+    pub shape: pmx::Shape,
+    pub size: Vector3<f32>,
+    /// Relative to the position of [`RigidBody::bone`].
+    pub position: Vector3<f32>,
+    pub rotation: Vector3<f32>,
+    pub mass: f32,
+    pub linear_damping: f32,
+    pub angular_damping: f32,
+    pub restitution: f32,
+    pub friction: f32,
+    pub mode: pmx::PhysicsMode,
+}
+
+#[derive(Debug, Clone)]
+pub struct Joint {
+    pub name: String,
+    pub rigid_bodies: [u32; 2],
+    pub position: Vector3<f32>,
+    pub rotation: Vector3<f32>,
+    pub linear_lower: Vector3<f32>,
+    pub linear_upper: Vector3<f32>,
+    pub angular_lower: Vector3<f32>,
+    pub angular_upper: Vector3<f32>,
+    pub linear_spring: Vector3<f32>,
+    pub angular_spring: Vector3<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PmdModel {
+    pub name: String,
+    pub name_en: String,
+    pub comment: String,
+    pub comment_en: String,
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u16>,
+    pub materials: Vec<Material>,
+    pub bones: Vec<Bone>,
+    pub iks: Vec<Ik>,
+    pub skins: Vec<Skin>,
+    /// Skins shown in the expression panel, as indices into
+    /// [`PmdModel::skins`].
+    pub skin_display: Vec<u16>,
+    pub bone_groups: Vec<BoneGroup>,
+    /// `toon01.bmp` to `toon10.bmp` unless the model replaces them.
+    pub toon_textures: Vec<String>,
+    pub rigid_bodies: Vec<RigidBody>,
+    pub joints: Vec<Joint>,
+}
+
+/// Reads the PMD file at `path`.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<PmdModel> {
+    let file = File::open(path)?;
+    read(BufReader::new(file))
+}
+
+pub fn read<R: BufRead>(reader: R) -> Result<PmdModel> {
+    let mut reader = BinaryReader::new(reader);
+    let r = &mut reader;
+
+    let signature = r.read_array::<3>()?;
+    if &signature != b"Pmd" {
+        return Err(PmdError::InvalidSignature(signature));
+    }
+    let version = r.read_f32()?;
+    if version != 1.0 {
+        return Err(PmdError::UnsupportedVersion(version));
+    }
+    let name = r.read_shift_jis(20)?;
+    let comment = r.read_shift_jis(256)?;
+
+    let vertices = read_list(r, r_u32, |r| {
+        Ok(Vertex {
+            position: r.read_vec3()?,
+            normal: r.read_vec3()?,
+            uv: r.read_vec2()?,
+            bones: [r.read_u16()?, r.read_u16()?],
+            weight: r.read_u8()?.min(100),
+            no_edge: r.read_u8()? != 0,
+        })
+    })?;
+    let indices = read_list(r, r_u32, |r| Ok(r.read_u16()?))?;
+    let materials = read_list(r, r_u32, |r| {
+        Ok(Material {
+            diffuse: r.read_vec4()?,
+            specular_strength: r.read_f32()?,
+            specular: r.read_vec3()?,
+            ambient: r.read_vec3()?,
+            toon: r.read_u8()?,
+            edge: r.read_u8()? != 0,
+            index_count: r.read_u32()?,
+            texture: r.read_shift_jis(20)?,
+        })
+    })?;
+    let mut bones = read_list(r, r_u16, |r| {
+        let name = r.read_shift_jis(20)?;
+        let parent = r.read_u16()?;
+        let tail = r.read_u16()?;
+        let kind = match r.read_u8()? {
+            0 => BoneKind::Rotate,
+            1 => BoneKind::RotateTranslate,
+            2 => BoneKind::Ik,
+            3 => BoneKind::Unknown,
+            4 => BoneKind::IkLink,
+            5 => BoneKind::RotateAppend,
+            6 => BoneKind::IkTarget,
+            7 => BoneKind::Invisible,
+            8 => BoneKind::Twist,
+            9 => BoneKind::RotateRatio,
+            other => return Err(invalid(r, "bone type", other as i64)),
+        };
+        Ok(Bone {
+            name,
+            name_en: String::new(),
+            parent,
+            tail,
+            kind,
+            ik_parent: r.read_u16()?,
+            position: r.read_vec3()?,
+        })
+    })?;
+    let iks = read_list(r, r_u16, |r| {
+        let bone = r.read_u16()?;
+        let target = r.read_u16()?;
+        let link_count = r.read_u8()?;
+        let iterations = r.read_u16()?;
+        let limit = r.read_f32()?;
+        let mut links = Vec::with_capacity(link_count as usize);
+        for _ in 0..link_count {
+            links.push(r.read_u16()?);
+        }
+        Ok(Ik {
+            bone,
+            target,
+            iterations,
+            limit,
+            links,
+        })
+    })?;
+    let mut skins = read_list(r, r_u16, |r| {
+        let name = r.read_shift_jis(20)?;
+        let count = r.read_u32()?;
+        let panel = r.read_u8()?;
+        let mut vertices = Vec::with_capacity(count.min(1 << 16) as usize);
+        for _ in 0..count {
+            vertices.push((r.read_u32()?, r.read_vec3()?));
+        }
+        Ok(Skin {
+            name,
+            name_en: String::new(),
+            panel,
+            vertices,
+        })
+    })?;
+    let skin_display = read_list(r, r_u8, |r| Ok(r.read_u16()?))?;
+    let mut bone_groups = read_list(r, r_u8, |r| {
+        Ok(BoneGroup {
+            // Names end with a line break that MMD does not show
+            name: r.read_shift_jis(50)?.trim_end().to_string(),
+            name_en: String::new(),
+            bones: Vec::new(),
+        })
+    })?;
+    let bone_group_entries = read_list(r, r_u32, |r| Ok((r.read_u16()?, r.read_u8()?)))?;
+    for (bone, group) in bone_group_entries {
+        // Groups are numbered from 1, 0 being the root frame
+        match bone_groups.get_mut((group as usize).wrapping_sub(1)) {
+            Some(group) => group.bones.push(bone),
+            None => log::warn!("PMD bone {} is in missing display group {}", bone, group),
+        }
+    }
+
+    // Everything after this was added to the format later and may be missing
+    let mut model = PmdModel {
+        name,
+        name_en: String::new(),
+        comment,
+        comment_en: String::new(),
+        vertices,
+        indices,
+        materials,
+        bones: Vec::new(),
+        iks,
+        skins: Vec::new(),
+        skin_display,
+        bone_groups: Vec::new(),
+        toon_textures: (1..=10).map(|i| format!("toon{:02}.bmp", i)).collect(),
+        rigid_bodies: Vec::new(),
+        joints: Vec::new(),
+    };
+
+    if !r.at_end()? && r.read_u8()? != 0 {
+        model.name_en = r.read_shift_jis(20)?;
+        model.comment_en = r.read_shift_jis(256)?;
+        for bone in &mut bones {
+            bone.name_en = r.read_shift_jis(20)?;
+        }
+        // The base skin has no English name
+        for skin in skins.iter_mut().skip(1) {
+            skin.name_en = r.read_shift_jis(20)?;
+        }
+        for group in &mut bone_groups {
+            group.name_en = r.read_shift_jis(50)?.trim_end().to_string();
+        }
+    }
+    model.bones = bones;
+    model.skins = skins;
+    model.bone_groups = bone_groups;
+
+    if !r.at_end()? {
+        for toon in &mut model.toon_textures {
+            *toon = r.read_shift_jis(100)?;
+        }
+    }
+
+    if !r.at_end()? {
+        model.rigid_bodies = read_list(r, r_u32, |r| {
+            let name = r.read_shift_jis(20)?;
+            let bone = r.read_u16()?;
+            let group = r.read_u8()?;
+            // Stored as the groups *not* to collide with
+            let collision_mask = !r.read_u16()?;
+            let shape = match r.read_u8()? {
+                0 => pmx::Shape::Sphere,
+                1 => pmx::Shape::Box,
+                2 => pmx::Shape::Capsule,
+                other => return Err(invalid(r, "rigid body shape", other as i64)),
+            };
+            let size = r.read_vec3()?;
+            let position = r.read_vec3()?;
+            let rotation = r.read_vec3()?;
+            let mass = r.read_f32()?;
+            let linear_damping = r.read_f32()?;
+            let angular_damping = r.read_f32()?;
+            let restitution = r.read_f32()?;
+            let friction = r.read_f32()?;
+            let mode = match r.read_u8()? {
+                0 => pmx::PhysicsMode::FollowBone,
+                1 => pmx::PhysicsMode::Physics,
+                2 => pmx::PhysicsMode::PhysicsWithBone,
+                other => return Err(invalid(r, "physics mode", other as i64)),
+            };
+            Ok(RigidBody {
+                name,
+                bone,
+                group,
+                collision_mask,
+                shape,
+                size,
+                position,
+                rotation,
+                mass,
+                linear_damping,
+                angular_damping,
+                restitution,
+                friction,
+                mode,
+            })
+        })?;
+        model.joints = read_list(r, r_u32, |r| {
+            Ok(Joint {
+                name: r.read_shift_jis(20)?,
+                rigid_bodies: [r.read_u32()?, r.read_u32()?],
+                position: r.read_vec3()?,
+                rotation: r.read_vec3()?,
+                linear_lower: r.read_vec3()?,
+                linear_upper: r.read_vec3()?,
+                angular_lower: r.read_vec3()?,
+                angular_upper: r.read_vec3()?,
+                linear_spring: r.read_vec3()?,
+                angular_spring: r.read_vec3()?,
+            })
+        })?;
+    }
+
+    Ok(model)
+}
+
+fn invalid<R: BufRead>(reader: &BinaryReader<R>, what: &'static str, value: i64) -> PmdError {
+    PmdError::InvalidValue {
+        what,
+        value,
+        offset: reader.position(),
+    }
+}
+
+fn r_u8<R: BufRead>(reader: &mut BinaryReader<R>) -> io::Result<usize> {
+    Ok(reader.read_u8()? as usize)
+}
+
+fn r_u16<R: BufRead>(reader: &mut BinaryReader<R>) -> io::Result<usize> {
+    Ok(reader.read_u16()? as usize)
+}
+
+fn r_u32<R: BufRead>(reader: &mut BinaryReader<R>) -> io::Result<usize> {
+    Ok(reader.read_u32()? as usize)
+}
+
+/// Reads a list whose length is read by `read_count`; the width of the count
+/// differs from section to section.
+fn read_list<R, C, T, F>(reader: &mut BinaryReader<R>, read_count: C, mut read: F) -> Result<Vec<T>>
+where
+    R: BufRead,
+    C: Fn(&mut BinaryReader<R>) -> io::Result<usize>,
+    F: FnMut(&mut BinaryReader<R>) -> Result<T>,
+{
+    let count = read_count(reader)?;
+    // Do not trust the count for the allocation, the file may be cut short
+    let mut list = Vec::with_capacity(count.min(1 << 16));
+    for _ in 0..count {
+        list.push(read(reader)?);
+    }
+    Ok(list)
+}
+
+/// Converts a PMD index to a PMX one, where `-1` means none.
+fn index(index: u16) -> i32 {
+    if index == NO_BONE {
+        -1
+    } else {
+        index as i32
+    }
+}
+
+impl PmdModel {
+    /// Converts the model to PMX, the way PMX Editor does.
+    pub fn to_pmx(&self) -> PmxModel {
+        let mut textures = Vec::<String>::new();
+        let mut texture_index = |path: &str| -> i32 {
+            if path.is_empty() {
+                return -1;
+            }
+            match textures.iter().position(|texture| texture == path) {
+                Some(index) => index as i32,
+                None => {
+                    textures.push(path.to_string());
+                    textures.len() as i32 - 1
+                }
+            }
+        };
+
+        let vertices = self
+            .vertices
+            .iter()
+            .map(|vertex| pmx::Vertex {
+                position: vertex.position,
+                normal: vertex.normal,
+                uv: vertex.uv,
+                additional_uvs: Vec::new(),
+                weight: if vertex.bones[0] == vertex.bones[1] || vertex.weight == 100 {
+                    pmx::Weight::Bdef1 {
+                        bone: index(vertex.bones[0]),
+                    }
+                } else {
+                    pmx::Weight::Bdef2 {
+                        bones: [index(vertex.bones[0]), index(vertex.bones[1])],
+                        weight: vertex.weight as f32 / 100.0,
+                    }
+                },
+                edge_scale: if vertex.no_edge { 0.0 } else { 1.0 },
+            })
+            .collect();
+
+        let materials = self
+            .materials
+            .iter()
+            .enumerate()
+            .map(|(i, mat)| {
+                let (texture, sphere) = split_texture(&mat.texture);
+                let sphere_mode = match sphere {
+                    Some(sphere) if sphere.to_lowercase().ends_with(".spa") => pmx::SphereMode::Add,
+                    Some(_) => pmx::SphereMode::Multiply,
+                    None => pmx::SphereMode::Disabled,
+                };
+                let toon = match self.toon_textures.get(mat.toon as usize) {
+                    // The stock toon textures are shared with MMD
+                    Some(toon) if *toon == format!("toon{:02}.bmp", mat.toon as u32 + 1) => {
+                        pmx::Toon::Shared(mat.toon)
+                    }
+                    Some(toon) => pmx::Toon::Texture(texture_index(toon)),
+                    None => pmx::Toon::Texture(-1),
+                };
+
+                let mut flags = pmx::material_flags::GROUND_SHADOW
+                    | pmx::material_flags::DRAW_SHADOW
+                    | pmx::material_flags::RECEIVE_SHADOW;
+                // MMD draws both sides of translucent PMD materials
+                if mat.diffuse.w < 1.0 {
+                    flags |= pmx::material_flags::NO_CULL;
+                }
+                if mat.edge {
+                    flags |= pmx::material_flags::HAS_EDGE;
+                }
+
+                pmx::Material {
+                    name: format!("material{}", i),
+                    name_en: String::new(),
+                    diffuse: mat.diffuse,
+                    specular: mat.specular,
+                    specular_strength: mat.specular_strength,
+                    ambient: mat.ambient,
+                    flags,
+                    edge_color: Vector4::new(0.0, 0.0, 0.0, 1.0),
+                    edge_size: 1.0,
+                    texture: texture.map_or(-1, &mut texture_index),
+                    sphere_texture: sphere.map_or(-1, &mut texture_index),
+                    sphere_mode,
+                    toon,
+                    memo: String::new(),
+                    index_count: mat.index_count,
+                }
+            })
+            .collect();
+
+        let bones = self
+            .bones
+            .iter()
+            .enumerate()
+            .map(|(i, bone)| self.convert_bone(i, bone))
+            .collect();
+
+        // Skins other than the base are stored relative to it
+        let base = self.skins.iter().find(|skin| skin.panel == 0);
+        let morphs = self
+            .skins
+            .iter()
+            .filter(|skin| skin.panel != 0)
+            .map(|skin| {
+                let offsets = skin
+                    .vertices
+                    .iter()
+                    .filter_map(|&(base_index, offset)| {
+                        let vertex = base?.vertices.get(base_index as usize)?.0;
+                        Some((vertex, offset))
+                    })
+                    .collect();
+                pmx::Morph {
+                    name: skin.name.clone(),
+                    name_en: skin.name_en.clone(),
+                    panel: skin.panel,
+                    offsets: pmx::MorphOffsets::Vertex(offsets),
+                }
+            })
+            .collect();
+        // Indices into the skins become indices into the morphs
+        let morph_index = |skin: u16| {
+            let skin = skin as usize;
+            if skin >= self.skins.len() || self.skins[skin].panel == 0 {
+                return None;
+            }
+            let before = self.skins[..skin].iter().filter(|s| s.panel == 0).count();
+            Some((skin - before) as i32)
+        };
+
+        let mut display_frames = vec![
+            pmx::DisplayFrame {
+                name: "Root".to_string(),
+                name_en: "Root".to_string(),
+                special: true,
+                items: if self.bones.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![pmx::DisplayItem::Bone(0)]
+                },
+            },
+            pmx::DisplayFrame {
+                name: "表情".to_string(),
+                name_en: "Exp".to_string(),
+                special: true,
+                items: self
+                    .skin_display
+                    .iter()
+                    .filter_map(|&skin| morph_index(skin))
+                    .map(pmx::DisplayItem::Morph)
+                    .collect(),
+            },
+        ];
+        display_frames.extend(self.bone_groups.iter().map(|group| {
+            pmx::DisplayFrame {
+                name: group.name.clone(),
+                name_en: group.name_en.clone(),
+                special: false,
+                items: group
+                    .bones
+                    .iter()
+                    .map(|&bone| pmx::DisplayItem::Bone(index(bone)))
+                    .collect(),
+            }
+        }));
+
+        let rigid_bodies = self
+            .rigid_bodies
+            .iter()
+            .map(|body| {
+                // PMX positions are absolute
+                let bone_position = self
+                    .bones
+                    .get(body.bone as usize)
+                    .map_or(Vector3::zero(), |bone| bone.position);
+                pmx::RigidBody {
+                    name: body.name.clone(),
+                    name_en: String::new(),
+                    bone: index(body.bone),
+                    group: body.group,
+                    collision_mask: body.collision_mask,
+                    shape: body.shape,
+                    size: body.size,
+                    position: bone_position + body.position,
+                    rotation: body.rotation,
+                    mass: body.mass,
+                    linear_damping: body.linear_damping,
+                    angular_damping: body.angular_damping,
+                    restitution: body.restitution,
+                    friction: body.friction,
+                    mode: body.mode,
+                }
+            })
+            .collect();
+
+        let joints = self
+            .joints
+            .iter()
+            .map(|joint| pmx::Joint {
+                name: joint.name.clone(),
+                name_en: String::new(),
+                kind: pmx::JointKind::Spring6Dof,
+                rigid_bodies: [joint.rigid_bodies[0] as i32, joint.rigid_bodies[1] as i32],
+                position: joint.position,
+                rotation: joint.rotation,
+                linear_lower: joint.linear_lower,
+                linear_upper: joint.linear_upper,
+                angular_lower: joint.angular_lower,
+                angular_upper: joint.angular_upper,
+                linear_spring: joint.linear_spring,
+                angular_spring: joint.angular_spring,
+            })
+            .collect();
+
+        PmxModel {
+            header: pmx::Header {
+                version: 2.0,
+                encoding: pmx::TextEncoding::Utf16Le,
+                additional_uvs: 0,
+                vertex_index_size: 4,
+                texture_index_size: 4,
+                material_index_size: 4,
+                bone_index_size: 4,
+                morph_index_size: 4,
+                rigid_body_index_size: 4,
+            },
+            name: self.name.clone(),
+            name_en: self.name_en.clone(),
+            comment: self.comment.clone(),
+            comment_en: self.comment_en.clone(),
+            vertices,
+            indices: self.indices.iter().map(|&i| i as u32).collect(),
+            textures,
+            materials,
+            bones,
+            morphs,
+            display_frames,
+            rigid_bodies,
+            joints,
+            soft_bodies: Vec::new(),
+        }
+    }
+
+    fn convert_bone(&self, i: usize, bone: &Bone) -> pmx::Bone {
+        use pmx::bone_flags::*;
+
+        let mut flags = ROTATABLE | ENABLED;
+        if !matches!(bone.kind, BoneKind::Invisible | BoneKind::IkTarget) {
+            flags |= VISIBLE;
+        }
+        if matches!(bone.kind, BoneKind::RotateTranslate | BoneKind::Ik) {
+            flags |= TRANSLATABLE;
+        }
+
+        let tail_bone = self
+            .bones
+            .get(bone.tail as usize)
+            .filter(|_| bone.tail != 0);
+        let tail = match tail_bone {
+            Some(_) if bone.kind != BoneKind::RotateRatio => {
+                flags |= INDEXED_TAIL;
+                pmx::BoneTail::Bone(bone.tail as i32)
+            }
+            _ => pmx::BoneTail::Offset(Vector3::zero()),
+        };
+
+        let append = match bone.kind {
+            BoneKind::RotateAppend => Some(1.0),
+            BoneKind::RotateRatio => Some(bone.tail as f32 / 100.0),
+            _ => None,
+        }
+        .map(|weight| {
+            flags |= APPEND_ROTATION;
+            pmx::Append {
+                parent: index(bone.ik_parent),
+                weight,
+            }
+        });
+
+        let fixed_axis = match (bone.kind, tail_bone) {
+            (BoneKind::Twist, Some(tail)) if tail.position != bone.position => {
+                flags |= FIXED_AXIS;
+                Some((tail.position - bone.position).normalize())
+            }
+            _ => None,
+        };
+
+        let ik = self.iks.iter().find(|ik| ik.bone as usize == i).map(|ik| {
+            flags |= IK;
+            pmx::Ik {
+                target: index(ik.target),
+                loop_count: ik.iterations as i32,
+                limit_angle: ik.limit * 4.0,
+                links: ik
+                    .links
+                    .iter()
+                    .map(|&link| pmx::IkLink {
+                        bone: index(link),
+                        limits: self.knee_limits(link),
+                    })
+                    .collect(),
+            }
+        });
+
+        pmx::Bone {
+            name: bone.name.clone(),
+            name_en: bone.name_en.clone(),
+            position: bone.position,
+            parent: index(bone.parent),
+            layer: 0,
+            flags,
+            tail,
+            append,
+            fixed_axis,
+            local_axis: None,
+            external_parent: None,
+            ik,
+        }
+    }
+
+    /// MMD only lets knees bend one way, which PMD does not store; PMX
+    /// writes it as an angle limit.
+    fn knee_limits(&self, bone: u16) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        let bone = self.bones.get(bone as usize)?;
+        if !bone.name.contains("ひざ") {
+            return None;
+        }
+        let min = Rad::from(Deg(-180.0f32)).0;
+        let max = Rad::from(Deg(-0.5f32)).0;
+        Some((Vector3::new(min, 0.0, 0.0), Vector3::new(max, 0.0, 0.0)))
+    }
+}
+
+/// Splits `texture.bmp*sphere.sph` into the texture and the sphere map. A
+/// lone `.sph` or `.spa` is a sphere map without texture.
+fn split_texture(field: &str) -> (Option<&str>, Option<&str>) {
+    fn non_empty(s: &str) -> Option<&str> {
+        Some(s.trim()).filter(|s| !s.is_empty())
+    }
+    let is_sphere = |s: &str| {
+        let s = s.to_lowercase();
+        s.ends_with(".sph") || s.ends_with(".spa")
+    };
+
+    match field.split_once('*') {
+        Some((texture, sphere)) => (non_empty(texture), non_empty(sphere)),
+        None if is_sphere(field) => (None, non_empty(field)),
+        None => (non_empty(field), None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binary::BinaryWriter;
+
+    fn u16s(w: &mut BinaryWriter<&mut Vec<u8>>, values: &[u16]) {
+        for value in values {
+            w.write_bytes(&value.to_le_bytes()).unwrap();
+        }
+    }
+
+    fn vec3(x: f32, y: f32, z: f32) -> Vector3<f32> {
+        Vector3::new(x, y, z)
+    }
+
+    /// A leg with an IK, two skins, a bone group and, when `extensions` is
+    /// set, the toon textures and one rigid body and joint after them.
+    fn pmd(extensions: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let w = &mut BinaryWriter::new(&mut bytes);
+        w.write_bytes(b"Pmd").unwrap();
+        w.write_f32(1.0).unwrap();
+        w.write_shift_jis("脚", 20).unwrap();
+        w.write_shift_jis("comment", 256).unwrap();
+
+        // Vertices: one on a single bone, one split 60/40
+        w.write_u32(2).unwrap();
+        for &(bones, weight) in &[([2, 3], 100), ([2, 3], 60)] {
+            w.write_vec3(Vector3::zero()).unwrap();
+            w.write_vec3(vec3(0.0, 0.0, -1.0)).unwrap();
+            w.write_f32(0.0).unwrap();
+            w.write_f32(0.0).unwrap();
+            u16s(w, &bones);
+            w.write_u8(weight).unwrap();
+            w.write_u8(0).unwrap();
+        }
+        w.write_u32(3).unwrap();
+        u16s(w, &[0, 1, 1]);
+
+        w.write_u32(1).unwrap();
+        w.write_vec4(Vector4::new(1.0, 1.0, 1.0, 1.0)).unwrap();
+        w.write_f32(5.0).unwrap();
+        w.write_vec3(Vector3::zero()).unwrap();
+        w.write_vec3(vec3(0.5, 0.5, 0.5)).unwrap();
+        w.write_u8(0).unwrap();
+        w.write_u8(1).unwrap();
+        w.write_u32(3).unwrap();
+        w.write_shift_jis("body.bmp*light.spa", 20).unwrap();
+
+        // Bones: name, parent, tail, kind, IK parent, position
+        let bones = [
+            ("センター", NO_BONE, 1, 1, 0, vec3(0.0, 8.0, 0.0)),
+            ("左足", 0, 2, 4, 4, vec3(1.0, 10.0, 0.0)),
+            ("左ひざ", 1, 3, 4, 4, vec3(1.0, 5.0, 0.0)),
+            ("左足首", 2, 0, 0, 0, vec3(1.0, 1.0, 0.0)),
+            ("左足ＩＫ", NO_BONE, 0, 2, 0, vec3(1.0, 1.0, 0.0)),
+        ];
+        u16s(w, &[bones.len() as u16]);
+        for &(name, parent, tail, kind, ik_parent, position) in &bones {
+            w.write_shift_jis(name, 20).unwrap();
+            u16s(w, &[parent, tail]);
+            w.write_u8(kind).unwrap();
+            u16s(w, &[ik_parent]);
+            w.write_vec3(position).unwrap();
+        }
+
+        // The IK of bone 4 on bone 3, through the knee and then the leg
+        u16s(w, &[1, 4, 3]);
+        w.write_u8(2).unwrap();
+        u16s(w, &[40]);
+        w.write_f32(0.5).unwrap();
+        u16s(w, &[2, 1]);
+
+        // The base skin lists vertex 1 then vertex 0; the blink moves them
+        // through it
+        u16s(w, &[3]);
+        let skins = [
+            ("base", 0, [(1, Vector3::zero()), (0, Vector3::zero())]),
+            (
+                "まばたき",
+                2,
+                [(0, vec3(0.0, 1.0, 0.0)), (1, vec3(0.0, 2.0, 0.0))],
+            ),
+            (
+                "あ",
+                3,
+                [(1, vec3(0.0, 0.0, 3.0)), (5, vec3(0.0, 0.0, 4.0))],
+            ),
+        ];
+        for &(name, panel, vertices) in &skins {
+            w.write_shift_jis(name, 20).unwrap();
+            w.write_u32(vertices.len() as u32).unwrap();
+            w.write_u8(panel).unwrap();
+            for &(vertex, offset) in &vertices {
+                w.write_u32(vertex).unwrap();
+                w.write_vec3(offset).unwrap();
+            }
+        }
+        w.write_u8(2).unwrap();
+        u16s(w, &[2, 1]);
+
+        w.write_u8(1).unwrap();
+        w.write_shift_jis("足\n", 50).unwrap();
+        w.write_u32(2).unwrap();
+        for &bone in &[1u16, 2] {
+            u16s(w, &[bone]);
+            w.write_u8(1).unwrap();
+        }
+
+        if extensions {
+            w.write_u8(0).unwrap();
+            for i in 1..=10 {
+                let toon = if i == 2 {
+                    "mytoon.bmp".to_string()
+                } else {
+                    format!("toon{:02}.bmp", i)
+                };
+                w.write_shift_jis(&toon, 100).unwrap();
+            }
+
+            w.write_u32(1).unwrap();
+            w.write_shift_jis("左ひざ", 20).unwrap();
+            u16s(w, &[2]);
+            w.write_u8(3).unwrap();
+            // Does not collide with groups 0 and 5
+            u16s(w, &[0b10_0001]);
+            w.write_u8(2).unwrap();
+            w.write_vec3(vec3(0.5, 4.0, 0.0)).unwrap();
+            w.write_vec3(vec3(0.0, -2.0, 0.0)).unwrap();
+            w.write_vec3(Vector3::zero()).unwrap();
+            for &value in &[1.0, 0.5, 0.5, 0.0, 0.5] {
+                w.write_f32(value).unwrap();
+            }
+            w.write_u8(1).unwrap();
+
+            w.write_u32(1).unwrap();
+            w.write_shift_jis("joint", 20).unwrap();
+            w.write_u32(0).unwrap();
+            w.write_u32(0).unwrap();
+            for _ in 0..8 {
+                w.write_vec3(Vector3::zero()).unwrap();
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn read_and_convert() {
+        let model = read(pmd(true).as_slice()).unwrap();
+        assert_eq!(model.name, "脚");
+        assert_eq!(model.bones.len(), 5);
+        assert_eq!(model.bone_groups[0].name, "足");
+        assert_eq!(model.bone_groups[0].bones, [1, 2]);
+        assert_eq!(model.toon_textures[1], "mytoon.bmp");
+
+        let pmx = model.to_pmx();
+        assert_eq!(pmx.vertices[0].weight, pmx::Weight::Bdef1 { bone: 2 });
+        assert_eq!(
+            pmx.vertices[1].weight,
+            pmx::Weight::Bdef2 {
+                bones: [2, 3],
+                weight: 0.6,
+            }
+        );
+
+        let material = &pmx.materials[0];
+        assert_eq!(pmx.textures, ["body.bmp", "light.spa"]);
+        assert_eq!((material.texture, material.sphere_texture), (0, 1));
+        assert_eq!(material.sphere_mode, pmx::SphereMode::Add);
+        assert_eq!(material.toon, pmx::Toon::Shared(0));
+        assert_ne!(material.flags & pmx::material_flags::HAS_EDGE, 0);
+
+        // Skins go through the base skin's vertex indices, and the base skin
+        // itself is no morph
+        let morphs = pmx
+            .morphs
+            .iter()
+            .map(|morph| (morph.name.as_str(), &morph.offsets))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            morphs,
+            [
+                (
+                    "まばたき",
+                    &pmx::MorphOffsets::Vertex(vec![
+                        (1, vec3(0.0, 1.0, 0.0)),
+                        (0, vec3(0.0, 2.0, 0.0)),
+                    ])
+                ),
+                (
+                    "あ",
+                    &pmx::MorphOffsets::Vertex(vec![(0, vec3(0.0, 0.0, 3.0))])
+                ),
+            ]
+        );
+        let expressions = &pmx.display_frames[1].items;
+        assert_eq!(
+            expressions,
+            &[pmx::DisplayItem::Morph(1), pmx::DisplayItem::Morph(0)]
+        );
+
+        let ik = pmx.bones[4].ik.as_ref().unwrap();
+        assert!(pmx.bones[4].has(pmx::bone_flags::IK));
+        assert_eq!((ik.target, ik.loop_count, ik.limit_angle), (3, 40, 2.0));
+        let (min, max) = ik.links[0].limits.unwrap();
+        assert_eq!(ik.links[0].bone, 2);
+        assert!(min.x < max.x && max.x < 0.0);
+        assert_eq!((min.y, min.z, max.y, max.z), (0.0, 0.0, 0.0, 0.0));
+        assert_eq!(ik.links[1].bone, 1);
+        assert_eq!(ik.links[1].limits, None);
+
+        let body = &pmx.rigid_bodies[0];
+        assert_eq!(body.bone, 2);
+        assert_eq!(body.position, vec3(1.0, 3.0, 0.0));
+        assert_eq!(body.collision_mask, !0b10_0001);
+        assert_eq!(body.mode, pmx::PhysicsMode::Physics);
+        assert_eq!(pmx.joints[0].kind, pmx::JointKind::Spring6Dof);
+    }
+
+    #[test]
+    fn extensions_are_optional() {
+        let model = read(pmd(false).as_slice()).unwrap();
+        assert_eq!(model.toon_textures[1], "toon02.bmp");
+        assert!(model.rigid_bodies.is_empty());
+        assert!(model.joints.is_empty());
+        assert_eq!(model.to_pmx().morphs.len(), 2);
+    }
+
+    #[test]
+    fn bad_signature() {
+        let mut bytes = pmd(false);
+        bytes[..3].copy_from_slice(b"PMX");
+        assert!(matches!(
+            read(bytes.as_slice()),
+            Err(PmdError::InvalidSignature(signature)) if &signature == b"PMX"
+        ));
+    }
+}
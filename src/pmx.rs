@@ -0,0 +1,1581 @@
+//! Reader for PMX 2.0 and 2.1 models.
+//!
+//! Everything is kept in MMD's own left-handed coordinate system; see
+//! [`ModelData::left_handed`].
+
+use cgmath::{Vector2, Vector3, Vector4};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::binary::BinaryReader;
+use crate::geometry;
+use crate::model::{
+    skinning, MaterialData, MaterialParams, MeshData, ModelData, ModelVertex, MorphOffset,
+};
+use crate::resolver::TextureResolver;
+
+#[derive(Debug)]
+pub enum PmxError {
+    Io(io::Error),
+    InvalidSignature([u8; 4]),
+    UnsupportedVersion(f32),
+    InvalidEncoding(u8),
+    InvalidIndexSize {
+        kind: &'static str,
+        size: u8,
+    },
+    InvalidText {
+        offset: u64,
+    },
+    InvalidValue {
+        what: &'static str,
+        value: i64,
+        offset: u64,
+    },
+}
+
+impl fmt::Display for PmxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "failed to read PMX data: {}", e),
+            Self::InvalidSignature(signature) => {
+                write!(f, "not a PMX file, the signature is {:?}", signature)
+            }
+            Self::UnsupportedVersion(version) => write!(f, "unsupported PMX version {}", version),
+            Self::InvalidEncoding(encoding) => write!(f, "unknown text encoding {}", encoding),
+            Self::InvalidIndexSize { kind, size } => {
+                write!(f, "invalid {} index size {}", kind, size)
+            }
+            Self::InvalidText { offset } => write!(f, "invalid text at byte {}", offset),
+            Self::InvalidValue {
+                what,
+                value,
+                offset,
+            } => write!(f, "invalid {} {} at byte {}", what, value, offset),
+        }
+    }
+}
+
+impl std::error::Error for PmxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PmxError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, PmxError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf16Le,
+    Utf8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub version: f32,
+    pub encoding: TextEncoding,
+    pub additional_uvs: u8,
+    pub vertex_index_size: u8,
+    pub texture_index_size: u8,
+    pub material_index_size: u8,
+    pub bone_index_size: u8,
+    pub morph_index_size: u8,
+    pub rigid_body_index_size: u8,
+}
+
+/// How a vertex follows the bones, with `-1` for unused bone slots.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Weight {
+    Bdef1 {
+        bone: i32,
+    },
+    /// The second bone gets `1 - weight`.
+    Bdef2 {
+        bones: [i32; 2],
+        weight: f32,
+    },
+    Bdef4 {
+        bones: [i32; 4],
+        weights: [f32; 4],
+    },
+    /// Spherical deform around the centre `c`, with `r0` and `r1` the
+    /// reference points of the two bones.
+    Sdef {
+        bones: [i32; 2],
+        weight: f32,
+        c: Vector3<f32>,
+        r0: Vector3<f32>,
+        r1: Vector3<f32>,
+    },
+    /// Dual quaternion deform, PMX 2.1.
+    Qdef {
+        bones: [i32; 4],
+        weights: [f32; 4],
+    },
+}
+
+impl Weight {
+    /// The bones and their weights, with unused slots at bone -1.
+    pub fn bones(&self) -> ([i32; 4], [f32; 4]) {
+        match *self {
+            Self::Bdef1 { bone } => ([bone, -1, -1, -1], [1.0, 0.0, 0.0, 0.0]),
+            Self::Bdef2 { bones, weight } | Self::Sdef { bones, weight, .. } => (
+                [bones[0], bones[1], -1, -1],
+                [weight, 1.0 - weight, 0.0, 0.0],
+            ),
+            Self::Bdef4 { bones, weights } | Self::Qdef { bones, weights } => (bones, weights),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Vertex {
+    pub position: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    pub uv: Vector2<f32>,
+    /// As many as [`Header::additional_uvs`].
+    pub additional_uvs: Vec<Vector4<f32>>,
+    pub weight: Weight,
+    pub edge_scale: f32,
+}
+
+pub mod material_flags {
+    pub const NO_CULL: u8 = 0x01;
+    pub const GROUND_SHADOW: u8 = 0x02;
+    pub const DRAW_SHADOW: u8 = 0x04;
+    pub const RECEIVE_SHADOW: u8 = 0x08;
+    pub const HAS_EDGE: u8 = 0x10;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SphereMode {
+    Disabled,
+    Multiply,
+    Add,
+    /// Uses the first additional UV as the sphere texture coordinate.
+    SubTexture,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Toon {
+    /// Index into [`PmxModel::textures`], `-1` for none.
+    Texture(i32),
+    /// One of MMD's built-in `toon01.bmp` to `toon10.bmp`, as 0 to 9.
+    Shared(u8),
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Material {
+    pub name: String,
+    pub name_en: String,
+    pub diffuse: Vector4<f32>,
+    pub specular: Vector3<f32>,
+    pub specular_strength: f32,
+    pub ambient: Vector3<f32>,
+    /// See [`material_flags`].
+    pub flags: u8,
+    pub edge_color: Vector4<f32>,
+    pub edge_size: f32,
+    /// Index into [`PmxModel::textures`], `-1` for none.
+    pub texture: i32,
+    pub sphere_texture: i32,
+    pub sphere_mode: SphereMode,
+    pub toon: Toon,
+    pub memo: String,
+    /// Number of indices, not triangles, drawn with this material.
+    pub index_count: u32,
+}
+
+pub mod bone_flags {
+    pub const INDEXED_TAIL: u16 = 0x0001;
+    pub const ROTATABLE: u16 = 0x0002;
+    pub const TRANSLATABLE: u16 = 0x0004;
+    pub const VISIBLE: u16 = 0x0008;
+    pub const ENABLED: u16 = 0x0010;
+    pub const IK: u16 = 0x0020;
+    pub const LOCAL_APPEND: u16 = 0x0080;
+    pub const APPEND_ROTATION: u16 = 0x0100;
+    pub const APPEND_TRANSLATION: u16 = 0x0200;
+    pub const FIXED_AXIS: u16 = 0x0400;
+    pub const LOCAL_AXIS: u16 = 0x0800;
+    pub const AFTER_PHYSICS: u16 = 0x1000;
+    pub const EXTERNAL_PARENT: u16 = 0x2000;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BoneTail {
+    Bone(i32),
+    Offset(Vector3<f32>),
+}
+
+/// Inherits a weighted part of another bone's rotation and/or translation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Append {
+    pub parent: i32,
+    pub weight: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LocalAxis {
+    pub x: Vector3<f32>,
+    pub z: Vector3<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IkLink {
+    pub bone: i32,
+    /// Euler angle limits in radians, minimum and maximum.
+    pub limits: Option<(Vector3<f32>, Vector3<f32>)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ik {
+    pub target: i32,
+    pub loop_count: i32,
+    /// Largest rotation of a link per iteration, in radians.
+    pub limit_angle: f32,
+    pub links: Vec<IkLink>,
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Bone {
+    pub name: String,
+    pub name_en: String,
+    pub position: Vector3<f32>,
+    pub parent: i32,
+    /// Deformation layer; bones deform in increasing layer order.
+    pub layer: i32,
+    /// See [`bone_flags`].
+    pub flags: u16,
+    pub tail: BoneTail,
+    pub append: Option<Append>,
+    pub fixed_axis: Option<Vector3<f32>>,
+    pub local_axis: Option<LocalAxis>,
+    pub external_parent: Option<i32>,
+    pub ik: Option<Ik>,
+}
+
+impl Bone {
+    pub fn has(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaterialOperation {
+    Multiply,
+    Add,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaterialMorph {
+    /// `-1` applies to every material.
+    pub material: i32,
+    pub operation: MaterialOperation,
+    pub diffuse: Vector4<f32>,
+    pub specular: Vector3<f32>,
+    pub specular_strength: f32,
+    pub ambient: Vector3<f32>,
+    pub edge_color: Vector4<f32>,
+    pub edge_size: f32,
+    pub texture_tint: Vector4<f32>,
+    pub sphere_tint: Vector4<f32>,
+    pub toon_tint: Vector4<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImpulseMorph {
+    pub rigid_body: i32,
+    pub local: bool,
+    pub velocity: Vector3<f32>,
+    pub torque: Vector3<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MorphOffsets {
+    /// `(morph, weight)`
+    Group(Vec<(i32, f32)>),
+    /// `(vertex, translation)`
+    Vertex(Vec<(u32, Vector3<f32>)>),
+    /// `(bone, translation, rotation quaternion as x, y, z, w)`
+    Bone(Vec<(i32, Vector3<f32>, Vector4<f32>)>),
+    /// `(vertex, offset)`; `channel` 0 is the main UV, 1 to 4 the additional
+    /// UVs.
+    Uv {
+        channel: u8,
+        offsets: Vec<(u32, Vector4<f32>)>,
+    },
+    Material(Vec<MaterialMorph>),
+    /// `(morph, weight)`, PMX 2.1.
+    Flip(Vec<(i32, f32)>),
+    /// PMX 2.1.
+    Impulse(Vec<ImpulseMorph>),
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Morph {
+    pub name: String,
+    pub name_en: String,
+    /// Facial panel in MMD: 1 eyebrow, 2 eye, 3 mouth, 4 other.
+    pub panel: u8,
+    pub offsets: MorphOffsets,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayItem {
+    Bone(i32),
+    Morph(i32),
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct DisplayFrame {
+    pub name: String,
+    pub name_en: String,
+    /// The root and expression frames are special.
+    pub special: bool,
+    pub items: Vec<DisplayItem>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    Sphere,
+    Box,
+    Capsule,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhysicsMode {
+    /// Moved by its bone, pushes other bodies.
+    FollowBone,
+    /// Simulated, drives its bone.
+    Physics,
+    /// Simulated for rotation only; the bone keeps its translation.
+    PhysicsWithBone,
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct RigidBody {
+    pub name: String,
+    pub name_en: String,
+    /// `-1` when not attached to a bone.
+    pub bone: i32,
+    pub group: u8,
+    /// Bit `n` set means the body collides with group `n`.
+    pub collision_mask: u16,
+    pub shape: Shape,
+    /// Radius / half extents / radius and height, depending on the shape.
+    pub size: Vector3<f32>,
+    pub position: Vector3<f32>,
+    /// Euler angles in radians.
+    pub rotation: Vector3<f32>,
+    pub mass: f32,
+    pub linear_damping: f32,
+    pub angular_damping: f32,
+    pub restitution: f32,
+    pub friction: f32,
+    pub mode: PhysicsMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JointKind {
+    Spring6Dof,
+    SixDof,
+    PointToPoint,
+    ConeTwist,
+    Slider,
+    Hinge,
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Joint {
+    pub name: String,
+    pub name_en: String,
+    pub kind: JointKind,
+    pub rigid_bodies: [i32; 2],
+    pub position: Vector3<f32>,
+    pub rotation: Vector3<f32>,
+    pub linear_lower: Vector3<f32>,
+    pub linear_upper: Vector3<f32>,
+    pub angular_lower: Vector3<f32>,
+    pub angular_upper: Vector3<f32>,
+    pub linear_spring: Vector3<f32>,
+    pub angular_spring: Vector3<f32>,
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct SoftBody {
+    pub name: String,
+    pub name_en: String,
+    pub shape: u8,
+    pub material: i32,
+    pub group: u8,
+    pub collision_mask: u16,
+    pub flags: u8,
+    pub bending_distance: i32,
+    pub cluster_count: i32,
+    pub total_mass: f32,
+    pub collision_margin: f32,
+    pub aero_model: i32,
+    /// Bullet soft body configuration values in file order, VCF to AHR.
+    pub config: [f32; 12],
+    /// Cluster parameters in file order, SRHR_CL to SS_SPLT_CL.
+    pub cluster: [f32; 6],
+    /// Iterations in file order: velocity, position, drift, cluster.
+    pub iterations: [i32; 4],
+    /// Material stiffness in file order: linear, area, volume.
+    pub stiffness: [f32; 3],
+    /// `(rigid body, vertex, near mode)`
+    pub anchors: Vec<(i32, u32, bool)>,
+    pub pinned_vertices: Vec<u32>,
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct PmxModel {
+    pub header: Header,
+    pub name: String,
+    pub name_en: String,
+    pub comment: String,
+    pub comment_en: String,
+    pub vertices: Vec<Vertex>,
+    /// Triangle list; materials draw consecutive ranges of it.
+    pub indices: Vec<u32>,
+    pub textures: Vec<String>,
+    pub materials: Vec<Material>,
+    pub bones: Vec<Bone>,
+    pub morphs: Vec<Morph>,
+    pub display_frames: Vec<DisplayFrame>,
+    pub rigid_bodies: Vec<RigidBody>,
+    pub joints: Vec<Joint>,
+    pub soft_bodies: Vec<SoftBody>,
+}
+
+/// Reads the PMX file at `path`.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<PmxModel> {
+    let file = File::open(path)?;
+    read(BufReader::new(file))
+}
+
+pub fn read<R: BufRead>(reader: R) -> Result<PmxModel> {
+    let mut reader = BinaryReader::new(reader);
+
+    let signature = reader.read_array::<4>()?;
+    if &signature != b"PMX " {
+        return Err(PmxError::InvalidSignature(signature));
+    }
+    let version = reader.read_f32()?;
+    if !(version == 2.0 || version == 2.1) {
+        return Err(PmxError::UnsupportedVersion(version));
+    }
+
+    let header = read_header(&mut reader, version)?;
+    let mut pmx = PmxReader { reader, header };
+
+    let name = pmx.read_text()?;
+    let name_en = pmx.read_text()?;
+    let comment = pmx.read_text()?;
+    let comment_en = pmx.read_text()?;
+
+    let vertices = pmx.read_list(PmxReader::read_vertex)?;
+    let indices = pmx.read_list(|pmx| pmx.read_vertex_index())?;
+    let textures = pmx.read_list(PmxReader::read_text)?;
+    let materials = pmx.read_list(PmxReader::read_material)?;
+    let bones = pmx.read_list(PmxReader::read_bone)?;
+    let morphs = pmx.read_list(PmxReader::read_morph)?;
+    let display_frames = pmx.read_list(PmxReader::read_display_frame)?;
+    let rigid_bodies = pmx.read_list(PmxReader::read_rigid_body)?;
+    let joints = pmx.read_list(PmxReader::read_joint)?;
+    // Soft bodies only exist in 2.1, and even there some exporters leave
+    // the section out
+    let soft_bodies = if version >= 2.1 && !pmx.reader.at_end()? {
+        pmx.read_list(PmxReader::read_soft_body)?
+    } else {
+        Vec::new()
+    };
+
+    Ok(PmxModel {
+        header: pmx.header,
+        name,
+        name_en,
+        comment,
+        comment_en,
+        vertices,
+        indices,
+        textures,
+        materials,
+        bones,
+        morphs,
+        display_frames,
+        rigid_bodies,
+        joints,
+        soft_bodies,
+    })
+}
+
+fn read_header<R: BufRead>(reader: &mut BinaryReader<R>, version: f32) -> Result<Header> {
+    let globals_count = reader.read_u8()? as usize;
+    let globals = reader.read_bytes(globals_count)?;
+    if globals.len() < 8 {
+        return Err(PmxError::InvalidValue {
+            what: "header size",
+            value: globals_count as i64,
+            offset: reader.position(),
+        });
+    }
+
+    let encoding = match globals[0] {
+        0 => TextEncoding::Utf16Le,
+        1 => TextEncoding::Utf8,
+        other => return Err(PmxError::InvalidEncoding(other)),
+    };
+    let additional_uvs = globals[1];
+    if additional_uvs > 4 {
+        return Err(PmxError::InvalidValue {
+            what: "additional UV count",
+            value: additional_uvs as i64,
+            offset: reader.position(),
+        });
+    }
+
+    let index_size = |kind: &'static str, size: u8| match size {
+        1 | 2 | 4 => Ok(size),
+        _ => Err(PmxError::InvalidIndexSize { kind, size }),
+    };
+
+    Ok(Header {
+        version,
+        encoding,
+        additional_uvs,
+        vertex_index_size: index_size("vertex", globals[2])?,
+        texture_index_size: index_size("texture", globals[3])?,
+        material_index_size: index_size("material", globals[4])?,
+        bone_index_size: index_size("bone", globals[5])?,
+        morph_index_size: index_size("morph", globals[6])?,
+        rigid_body_index_size: index_size("rigid body", globals[7])?,
+    })
+}
+
+struct PmxReader<R> {
+    reader: BinaryReader<R>,
+    header: Header,
+}
+
+impl<R: BufRead> PmxReader<R> {
+    fn invalid(&self, what: &'static str, value: i64) -> PmxError {
+        PmxError::InvalidValue {
+            what,
+            value,
+            offset: self.reader.position(),
+        }
+    }
+
+    fn read_count(&mut self, what: &'static str) -> Result<usize> {
+        let count = self.reader.read_i32()?;
+        if count < 0 {
+            return Err(self.invalid(what, count as i64));
+        }
+        Ok(count as usize)
+    }
+
+    fn read_list<T, F>(&mut self, mut read: F) -> Result<Vec<T>>
+    where
+        F: FnMut(&mut Self) -> Result<T>,
+    {
+        let count = self.read_count("element count")?;
+        // Do not trust the count for the allocation, the file may be cut short
+        let mut list = Vec::with_capacity(count.min(1 << 16));
+        for _ in 0..count {
+            list.push(read(self)?);
+        }
+        Ok(list)
+    }
+
+    fn read_text(&mut self) -> Result<String> {
+        let len = self.read_count("text length")?;
+        let offset = self.reader.position();
+        let bytes = self.reader.read_bytes(len)?;
+        match self.header.encoding {
+            TextEncoding::Utf8 => {
+                String::from_utf8(bytes).map_err(|_| PmxError::InvalidText { offset })
+            }
+            TextEncoding::Utf16Le => {
+                if len % 2 != 0 {
+                    return Err(PmxError::InvalidText { offset });
+                }
+                let units = bytes
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+                std::char::decode_utf16(units)
+                    .collect::<std::result::Result<String, _>>()
+                    .map_err(|_| PmxError::InvalidText { offset })
+            }
+        }
+    }
+
+    /// Vertex indices are unsigned unless they are four bytes wide.
+    fn read_vertex_index(&mut self) -> Result<u32> {
+        Ok(match self.header.vertex_index_size {
+            1 => self.reader.read_u8()? as u32,
+            2 => self.reader.read_u16()? as u32,
+            _ => self.reader.read_i32()? as u32,
+        })
+    }
+
+    /// Every other index is signed, with `-1` meaning none.
+    fn read_index(&mut self, size: u8) -> Result<i32> {
+        Ok(match size {
+            1 => self.reader.read_i8()? as i32,
+            2 => self.reader.read_i16()? as i32,
+            _ => self.reader.read_i32()?,
+        })
+    }
+
+    fn read_texture_index(&mut self) -> Result<i32> {
+        self.read_index(self.header.texture_index_size)
+    }
+
+    fn read_material_index(&mut self) -> Result<i32> {
+        self.read_index(self.header.material_index_size)
+    }
+
+    fn read_bone_index(&mut self) -> Result<i32> {
+        self.read_index(self.header.bone_index_size)
+    }
+
+    fn read_morph_index(&mut self) -> Result<i32> {
+        self.read_index(self.header.morph_index_size)
+    }
+
+    fn read_rigid_body_index(&mut self) -> Result<i32> {
+        self.read_index(self.header.rigid_body_index_size)
+    }
+
+    fn read_bone_indices<const N: usize>(&mut self) -> Result<[i32; N]> {
+        let mut bones = [0; N];
+        for bone in &mut bones {
+            *bone = self.read_bone_index()?;
+        }
+        Ok(bones)
+    }
+
+    fn read_vertex(&mut self) -> Result<Vertex> {
+        let position = self.reader.read_vec3()?;
+        let normal = self.reader.read_vec3()?;
+        let uv = self.reader.read_vec2()?;
+        let mut additional_uvs = Vec::with_capacity(self.header.additional_uvs as usize);
+        for _ in 0..self.header.additional_uvs {
+            additional_uvs.push(self.reader.read_vec4()?);
+        }
+
+        let weight = match self.reader.read_u8()? {
+            0 => Weight::Bdef1 {
+                bone: self.read_bone_index()?,
+            },
+            1 => Weight::Bdef2 {
+                bones: self.read_bone_indices()?,
+                weight: self.reader.read_f32()?,
+            },
+            2 => Weight::Bdef4 {
+                bones: self.read_bone_indices()?,
+                weights: self.reader.read_vec4()?.into(),
+            },
+            3 => Weight::Sdef {
+                bones: self.read_bone_indices()?,
+                weight: self.reader.read_f32()?,
+                c: self.reader.read_vec3()?,
+                r0: self.reader.read_vec3()?,
+                r1: self.reader.read_vec3()?,
+            },
+            4 if self.header.version >= 2.1 => Weight::Qdef {
+                bones: self.read_bone_indices()?,
+                weights: self.reader.read_vec4()?.into(),
+            },
+            other => return Err(self.invalid("weight deform type", other as i64)),
+        };
+
+        Ok(Vertex {
+            position,
+            normal,
+            uv,
+            additional_uvs,
+            weight,
+            edge_scale: self.reader.read_f32()?,
+        })
+    }
+
+    fn read_material(&mut self) -> Result<Material> {
+        let name = self.read_text()?;
+        let name_en = self.read_text()?;
+        let diffuse = self.reader.read_vec4()?;
+        let specular = self.reader.read_vec3()?;
+        let specular_strength = self.reader.read_f32()?;
+        let ambient = self.reader.read_vec3()?;
+        let flags = self.reader.read_u8()?;
+        let edge_color = self.reader.read_vec4()?;
+        let edge_size = self.reader.read_f32()?;
+        let texture = self.read_texture_index()?;
+        let sphere_texture = self.read_texture_index()?;
+        let sphere_mode = match self.reader.read_u8()? {
+            0 => SphereMode::Disabled,
+            1 => SphereMode::Multiply,
+            2 => SphereMode::Add,
+            3 => SphereMode::SubTexture,
+            other => return Err(self.invalid("sphere mode", other as i64)),
+        };
+        let toon = match self.reader.read_u8()? {
+            0 => Toon::Texture(self.read_texture_index()?),
+            1 => Toon::Shared(self.reader.read_u8()?),
+            other => return Err(self.invalid("toon reference", other as i64)),
+        };
+        let memo = self.read_text()?;
+        let index_count = self.read_count("material index count")? as u32;
+
+        Ok(Material {
+            name,
+            name_en,
+            diffuse,
+            specular,
+            specular_strength,
+            ambient,
+            flags,
+            edge_color,
+            edge_size,
+            texture,
+            sphere_texture,
+            sphere_mode,
+            toon,
+            memo,
+            index_count,
+        })
+    }
+
+    fn read_bone(&mut self) -> Result<Bone> {
+        let name = self.read_text()?;
+        let name_en = self.read_text()?;
+        let position = self.reader.read_vec3()?;
+        let parent = self.read_bone_index()?;
+        let layer = self.reader.read_i32()?;
+        let flags = self.reader.read_u16()?;
+        let has = |flag: u16| flags & flag != 0;
+
+        let tail = if has(bone_flags::INDEXED_TAIL) {
+            BoneTail::Bone(self.read_bone_index()?)
+        } else {
+            BoneTail::Offset(self.reader.read_vec3()?)
+        };
+        let append = if has(bone_flags::APPEND_ROTATION) || has(bone_flags::APPEND_TRANSLATION) {
+            Some(Append {
+                parent: self.read_bone_index()?,
+                weight: self.reader.read_f32()?,
+            })
+        } else {
+            None
+        };
+        let fixed_axis = if has(bone_flags::FIXED_AXIS) {
+            Some(self.reader.read_vec3()?)
+        } else {
+            None
+        };
+        let local_axis = if has(bone_flags::LOCAL_AXIS) {
+            Some(LocalAxis {
+                x: self.reader.read_vec3()?,
+                z: self.reader.read_vec3()?,
+            })
+        } else {
+            None
+        };
+        let external_parent = if has(bone_flags::EXTERNAL_PARENT) {
+            Some(self.reader.read_i32()?)
+        } else {
+            None
+        };
+        let ik = if has(bone_flags::IK) {
+            let target = self.read_bone_index()?;
+            let loop_count = self.reader.read_i32()?;
+            let limit_angle = self.reader.read_f32()?;
+            let links = self.read_list(|pmx| {
+                let bone = pmx.read_bone_index()?;
+                let limits = match pmx.reader.read_u8()? {
+                    0 => None,
+                    _ => Some((pmx.reader.read_vec3()?, pmx.reader.read_vec3()?)),
+                };
+                Ok(IkLink { bone, limits })
+            })?;
+            Some(Ik {
+                target,
+                loop_count,
+                limit_angle,
+                links,
+            })
+        } else {
+            None
+        };
+
+        Ok(Bone {
+            name,
+            name_en,
+            position,
+            parent,
+            layer,
+            flags,
+            tail,
+            append,
+            fixed_axis,
+            local_axis,
+            external_parent,
+            ik,
+        })
+    }
+
+    fn read_morph(&mut self) -> Result<Morph> {
+        let name = self.read_text()?;
+        let name_en = self.read_text()?;
+        let panel = self.reader.read_u8()?;
+        let kind = self.reader.read_u8()?;
+
+        let offsets = match kind {
+            0 => MorphOffsets::Group(
+                self.read_list(|pmx| Ok((pmx.read_morph_index()?, pmx.reader.read_f32()?)))?,
+            ),
+            1 => MorphOffsets::Vertex(
+                self.read_list(|pmx| Ok((pmx.read_vertex_index()?, pmx.reader.read_vec3()?)))?,
+            ),
+            2 => MorphOffsets::Bone(self.read_list(|pmx| {
+                Ok((
+                    pmx.read_bone_index()?,
+                    pmx.reader.read_vec3()?,
+                    pmx.reader.read_vec4()?,
+                ))
+            })?),
+            3..=7 => MorphOffsets::Uv {
+                channel: kind - 3,
+                offsets: self
+                    .read_list(|pmx| Ok((pmx.read_vertex_index()?, pmx.reader.read_vec4()?)))?,
+            },
+            8 => MorphOffsets::Material(self.read_list(|pmx| {
+                let material = pmx.read_material_index()?;
+                let operation = match pmx.reader.read_u8()? {
+                    0 => MaterialOperation::Multiply,
+                    1 => MaterialOperation::Add,
+                    other => return Err(pmx.invalid("material morph operation", other as i64)),
+                };
+                Ok(MaterialMorph {
+                    material,
+                    operation,
+                    diffuse: pmx.reader.read_vec4()?,
+                    specular: pmx.reader.read_vec3()?,
+                    specular_strength: pmx.reader.read_f32()?,
+                    ambient: pmx.reader.read_vec3()?,
+                    edge_color: pmx.reader.read_vec4()?,
+                    edge_size: pmx.reader.read_f32()?,
+                    texture_tint: pmx.reader.read_vec4()?,
+                    sphere_tint: pmx.reader.read_vec4()?,
+                    toon_tint: pmx.reader.read_vec4()?,
+                })
+            })?),
+            9 if self.header.version >= 2.1 => MorphOffsets::Flip(
+                self.read_list(|pmx| Ok((pmx.read_morph_index()?, pmx.reader.read_f32()?)))?,
+            ),
+            10 if self.header.version >= 2.1 => MorphOffsets::Impulse(self.read_list(|pmx| {
+                Ok(ImpulseMorph {
+                    rigid_body: pmx.read_rigid_body_index()?,
+                    local: pmx.reader.read_u8()? != 0,
+                    velocity: pmx.reader.read_vec3()?,
+                    torque: pmx.reader.read_vec3()?,
+                })
+            })?),
+            other => return Err(self.invalid("morph type", other as i64)),
+        };
+
+        Ok(Morph {
+            name,
+            name_en,
+            panel,
+            offsets,
+        })
+    }
+
+    fn read_display_frame(&mut self) -> Result<DisplayFrame> {
+        let name = self.read_text()?;
+        let name_en = self.read_text()?;
+        let special = self.reader.read_u8()? != 0;
+        let items = self.read_list(|pmx| match pmx.reader.read_u8()? {
+            0 => Ok(DisplayItem::Bone(pmx.read_bone_index()?)),
+            1 => Ok(DisplayItem::Morph(pmx.read_morph_index()?)),
+            other => Err(pmx.invalid("display frame item type", other as i64)),
+        })?;
+
+        Ok(DisplayFrame {
+            name,
+            name_en,
+            special,
+            items,
+        })
+    }
+
+    fn read_rigid_body(&mut self) -> Result<RigidBody> {
+        let name = self.read_text()?;
+        let name_en = self.read_text()?;
+        let bone = self.read_bone_index()?;
+        let group = self.reader.read_u8()?;
+        // Stored as the groups *not* to collide with
+        let collision_mask = !self.reader.read_u16()?;
+        let shape = match self.reader.read_u8()? {
+            0 => Shape::Sphere,
+            1 => Shape::Box,
+            2 => Shape::Capsule,
+            other => return Err(self.invalid("rigid body shape", other as i64)),
+        };
+        let size = self.reader.read_vec3()?;
+        let position = self.reader.read_vec3()?;
+        let rotation = self.reader.read_vec3()?;
+        let mass = self.reader.read_f32()?;
+        let linear_damping = self.reader.read_f32()?;
+        let angular_damping = self.reader.read_f32()?;
+        let restitution = self.reader.read_f32()?;
+        let friction = self.reader.read_f32()?;
+        let mode = match self.reader.read_u8()? {
+            0 => PhysicsMode::FollowBone,
+            1 => PhysicsMode::Physics,
+            2 => PhysicsMode::PhysicsWithBone,
+            other => return Err(self.invalid("physics mode", other as i64)),
+        };
+
+        Ok(RigidBody {
+            name,
+            name_en,
+            bone,
+            group,
+            collision_mask,
+            shape,
+            size,
+            position,
+            rotation,
+            mass,
+            linear_damping,
+            angular_damping,
+            restitution,
+            friction,
+            mode,
+        })
+    }
+
+    fn read_joint(&mut self) -> Result<Joint> {
+        let name = self.read_text()?;
+        let name_en = self.read_text()?;
+        let kind = match self.reader.read_u8()? {
+            0 => JointKind::Spring6Dof,
+            1 if self.header.version >= 2.1 => JointKind::SixDof,
+            2 if self.header.version >= 2.1 => JointKind::PointToPoint,
+            3 if self.header.version >= 2.1 => JointKind::ConeTwist,
+            4 if self.header.version >= 2.1 => JointKind::Slider,
+            5 if self.header.version >= 2.1 => JointKind::Hinge,
+            other => return Err(self.invalid("joint type", other as i64)),
+        };
+
+        Ok(Joint {
+            name,
+            name_en,
+            kind,
+            rigid_bodies: [self.read_rigid_body_index()?, self.read_rigid_body_index()?],
+            position: self.reader.read_vec3()?,
+            rotation: self.reader.read_vec3()?,
+            linear_lower: self.reader.read_vec3()?,
+            linear_upper: self.reader.read_vec3()?,
+            angular_lower: self.reader.read_vec3()?,
+            angular_upper: self.reader.read_vec3()?,
+            linear_spring: self.reader.read_vec3()?,
+            angular_spring: self.reader.read_vec3()?,
+        })
+    }
+
+    fn read_soft_body(&mut self) -> Result<SoftBody> {
+        let name = self.read_text()?;
+        let name_en = self.read_text()?;
+        let shape = self.reader.read_u8()?;
+        let material = self.read_material_index()?;
+        let group = self.reader.read_u8()?;
+        let collision_mask = !self.reader.read_u16()?;
+        let flags = self.reader.read_u8()?;
+        let bending_distance = self.reader.read_i32()?;
+        let cluster_count = self.reader.read_i32()?;
+        let total_mass = self.reader.read_f32()?;
+        let collision_margin = self.reader.read_f32()?;
+        let aero_model = self.reader.read_i32()?;
+
+        let mut config = [0.0; 12];
+        for value in &mut config {
+            *value = self.reader.read_f32()?;
+        }
+        let mut cluster = [0.0; 6];
+        for value in &mut cluster {
+            *value = self.reader.read_f32()?;
+        }
+        let mut iterations = [0; 4];
+        for value in &mut iterations {
+            *value = self.reader.read_i32()?;
+        }
+        let mut stiffness = [0.0; 3];
+        for value in &mut stiffness {
+            *value = self.reader.read_f32()?;
+        }
+
+        let anchors = self.read_list(|pmx| {
+            Ok((
+                pmx.read_rigid_body_index()?,
+                pmx.read_vertex_index()?,
+                pmx.reader.read_u8()? != 0,
+            ))
+        })?;
+        let pinned_vertices = self.read_list(|pmx| pmx.read_vertex_index())?;
+
+        Ok(SoftBody {
+            name,
+            name_en,
+            shape,
+            material,
+            group,
+            collision_mask,
+            flags,
+            bending_distance,
+            cluster_count,
+            total_mass,
+            collision_margin,
+            aero_model,
+            config,
+            cluster,
+            iterations,
+            stiffness,
+            anchors,
+            pinned_vertices,
+        })
+    }
+}
+
+impl PmxModel {
+    /// Splits the model into one mesh per material. Texture paths are
+    /// resolved relative to `model_dir`.
+    pub fn to_model_data(&self, model_dir: &Path, resolver: &TextureResolver) -> ModelData {
+        let texture_path = |index: i32| {
+            usize::try_from(index)
+                .ok()
+                .and_then(|index| self.textures.get(index))
+                .map(|path| resolver.resolve(model_dir, path))
+        };
+
+        let (morph_offsets, morph_ranges) = self.morph_offsets();
+        let mut materials = Vec::with_capacity(self.materials.len());
+        let mut meshes = Vec::with_capacity(self.materials.len());
+        let mut first_index = 0usize;
+
+        for (material_index, mat) in self.materials.iter().enumerate() {
+            materials.push(MaterialData {
+                name: mat.name.clone(),
+                params: MaterialParams {
+                    ambient: mat.ambient.into(),
+                    diffuse: mat.diffuse.truncate().into(),
+                    specular: mat.specular.into(),
+                    shininess: mat.specular_strength,
+                    emissive: [0.0; 3],
+                    dissolve: mat.diffuse.w,
+                    illumination: 2,
+                    edge_color: mat.edge_color.into(),
+                    edge_size: if mat.flags & material_flags::HAS_EDGE != 0 {
+                        mat.edge_size
+                    } else {
+                        0.0
+                    },
+                    ..Default::default()
+                },
+                diffuse_texture: texture_path(mat.texture),
+                normal_texture: None,
+            });
+
+            let end = (first_index + mat.index_count as usize).min(self.indices.len());
+            let source_indices = &self.indices[first_index.min(end)..end];
+            first_index = end;
+
+            let mut mesh =
+                self.extract_mesh(&mat.name, source_indices, material_index, &morph_ranges);
+            if (mat.index_count as usize) != source_indices.len() {
+                mesh.warnings.push(format!(
+                    "material uses {} indices but only {} are left",
+                    mat.index_count,
+                    source_indices.len()
+                ));
+            }
+            meshes.push(mesh);
+        }
+
+        ModelData {
+            name: self.name.clone(),
+            meshes,
+            materials,
+            left_handed: true,
+            bone_count: self.bones.len(),
+            morph_count: self.morphs.len(),
+            morph_offsets,
+        }
+    }
+
+    /// The vertex and main UV morph offsets grouped by vertex, and the range
+    /// of offsets of each vertex. Additional UV channels are not drawn and
+    /// left out.
+    fn morph_offsets(&self) -> (Vec<MorphOffset>, Vec<[u32; 2]>) {
+        let mut by_vertex = vec![Vec::new(); self.vertices.len()];
+        for (index, morph) in self.morphs.iter().enumerate() {
+            let morph_index = index as u32;
+            match &morph.offsets {
+                MorphOffsets::Vertex(offsets) => {
+                    for (vertex, offset) in offsets {
+                        if let Some(list) = by_vertex.get_mut(*vertex as usize) {
+                            list.push(MorphOffset {
+                                position: (*offset).into(),
+                                morph: morph_index,
+                                uv: [0.0; 4],
+                            });
+                        }
+                    }
+                }
+                MorphOffsets::Uv {
+                    channel: 0,
+                    offsets,
+                } => {
+                    for (vertex, offset) in offsets {
+                        if let Some(list) = by_vertex.get_mut(*vertex as usize) {
+                            // v is flipped like the texture coordinates
+                            list.push(MorphOffset {
+                                position: [0.0; 3],
+                                morph: morph_index,
+                                uv: [offset.x, -offset.y, 0.0, 0.0],
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut offsets = Vec::new();
+        let ranges = by_vertex
+            .into_iter()
+            .map(|list| {
+                let range = [offsets.len() as u32, list.len() as u32];
+                offsets.extend(list);
+                range
+            })
+            .collect();
+        (offsets, ranges)
+    }
+
+    /// A vertex with only the skinning fields set from `weight`. Bones that
+    /// do not exist get no weight, and the weights are normalised.
+    fn skinned_vertex(&self, weight: &Weight) -> ModelVertex {
+        let (bones, mut weights) = weight.bones();
+        let mut bone_indices = [0; 4];
+        for i in 0..4 {
+            match usize::try_from(bones[i]) {
+                Ok(bone) if bone < self.bones.len() => bone_indices[i] = bone as u32,
+                _ => weights[i] = 0.0,
+            }
+        }
+        let total = weights.iter().sum::<f32>();
+        let bone_weights = if total > 0.0 {
+            weights.map(|weight| weight / total)
+        } else {
+            [1.0, 0.0, 0.0, 0.0]
+        };
+
+        let mut vertex = ModelVertex {
+            bone_indices,
+            bone_weights,
+            ..Default::default()
+        };
+        match *weight {
+            Weight::Sdef { c, r0, r1, .. } => {
+                // MMD moves both reference points so that their weighted
+                // average is the centre, then uses the midpoints to the centre
+                let [w0, w1, ..] = bone_weights;
+                let average = r0 * w0 + r1 * w1;
+                let r0 = c + r0 - average;
+                let r1 = c + r1 - average;
+                vertex.sdef_c = c.into();
+                vertex.sdef_r0 = ((c + r0) * 0.5).into();
+                vertex.sdef_r1 = ((c + r1) * 0.5).into();
+                vertex.skinning = skinning::SDEF;
+            }
+            Weight::Qdef { .. } => vertex.skinning = skinning::QDEF,
+            _ => {}
+        }
+        vertex
+    }
+
+    /// Copies the vertices used by `source_indices` into a mesh of their own.
+    fn extract_mesh(
+        &self,
+        name: &str,
+        source_indices: &[u32],
+        material: usize,
+        morph_ranges: &[[u32; 2]],
+    ) -> MeshData {
+        let mut remap = vec![u32::MAX; self.vertices.len()];
+        let mut vertices = Vec::new();
+        let mut source_vertices = Vec::new();
+        let mut indices = Vec::with_capacity(source_indices.len());
+        let mut out_of_range = 0;
+
+        for triangle in source_indices.chunks_exact(3) {
+            if triangle
+                .iter()
+                .any(|&index| index as usize >= self.vertices.len())
+            {
+                out_of_range += 1;
+                continue;
+            }
+            for &index in triangle {
+                let slot = &mut remap[index as usize];
+                if *slot == u32::MAX {
+                    let vertex = &self.vertices[index as usize];
+                    *slot = vertices.len() as u32;
+                    vertices.push(ModelVertex {
+                        position: vertex.position.into(),
+                        // PMX texture coordinates start at the top left
+                        tex_coords: [vertex.uv.x, 1.0 - vertex.uv.y],
+                        normal: vertex.normal.into(),
+                        morph_range: morph_ranges[index as usize],
+                        ..self.skinned_vertex(&vertex.weight)
+                    });
+                    source_vertices.push(index);
+                }
+                indices.push(*slot);
+            }
+        }
+        geometry::generate_tangents(&mut vertices, &indices);
+
+        let mut mesh = MeshData::new(name.to_string(), vertices, indices, Some(material));
+        mesh.source_vertices = source_vertices;
+        if out_of_range > 0 {
+            mesh.warnings.push(format!(
+                "dropped {} triangles with vertex indices past the {} vertices",
+                out_of_range,
+                self.vertices.len()
+            ));
+        }
+        mesh
+    }
+}
+
+#[cfg(test)]
+impl PmxModel {
+    /// A PMX 2.0 model with nothing in it, for tests to fill in.
+    pub fn empty() -> Self {
+        Self {
+            header: Header {
+                version: 2.0,
+                encoding: TextEncoding::Utf8,
+                additional_uvs: 0,
+                vertex_index_size: 4,
+                texture_index_size: 4,
+                material_index_size: 4,
+                bone_index_size: 4,
+                morph_index_size: 4,
+                rigid_body_index_size: 4,
+            },
+            name: String::new(),
+            name_en: String::new(),
+            comment: String::new(),
+            comment_en: String::new(),
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            textures: Vec::new(),
+            materials: Vec::new(),
+            bones: Vec::new(),
+            morphs: Vec::new(),
+            display_frames: Vec::new(),
+            rigid_bodies: Vec::new(),
+            joints: Vec::new(),
+            soft_bodies: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Bone {
+    /// A visible, rotatable bone on layer 0 with nothing else set.
+    pub fn new(name: &str, position: Vector3<f32>, parent: i32) -> Self {
+        Self {
+            name: name.to_string(),
+            name_en: String::new(),
+            position,
+            parent,
+            layer: 0,
+            flags: bone_flags::ROTATABLE | bone_flags::VISIBLE | bone_flags::ENABLED,
+            tail: BoneTail::Offset(Vector3::new(0.0, 0.0, 0.0)),
+            append: None,
+            fixed_axis: None,
+            local_axis: None,
+            external_parent: None,
+            ik: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a PMX file section by section: [`Builder::new`] the header and
+    /// names, then the vertex count and vertices, then [`Builder::finish`]
+    /// the indices and empty lists for everything after them.
+    struct Builder {
+        bytes: Vec<u8>,
+        encoding: TextEncoding,
+        vertex_index_size: u8,
+        bone_index_size: u8,
+    }
+
+    impl Builder {
+        fn new(version: f32, encoding: TextEncoding, vertex_size: u8, bone_size: u8) -> Self {
+            let mut builder = Self {
+                bytes: b"PMX ".to_vec(),
+                encoding,
+                vertex_index_size: vertex_size,
+                bone_index_size: bone_size,
+            };
+            builder.f32(version);
+            let encoding = match encoding {
+                TextEncoding::Utf16Le => 0,
+                TextEncoding::Utf8 => 1,
+            };
+            builder
+                .bytes
+                .extend_from_slice(&[8, encoding, 0, vertex_size, 1, 1, bone_size, 1, 1]);
+            builder.text("初音ミク");
+            builder.text("Miku");
+            builder.text("");
+            builder.text("comment\n");
+            builder
+        }
+
+        fn f32(&mut self, value: f32) {
+            self.bytes.extend_from_slice(&value.to_le_bytes());
+        }
+
+        fn f32s(&mut self, values: &[f32]) {
+            for &value in values {
+                self.f32(value);
+            }
+        }
+
+        fn count(&mut self, count: usize) {
+            self.bytes.extend_from_slice(&(count as i32).to_le_bytes());
+        }
+
+        fn text(&mut self, text: &str) {
+            let bytes = match self.encoding {
+                TextEncoding::Utf8 => text.as_bytes().to_vec(),
+                TextEncoding::Utf16Le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            };
+            self.count(bytes.len());
+            self.bytes.extend_from_slice(&bytes);
+        }
+
+        /// The low `size` bytes of `index`, which is how both the signed and
+        /// the unsigned indices are stored.
+        fn index(&mut self, size: u8, index: i64) {
+            self.bytes
+                .extend_from_slice(&index.to_le_bytes()[..size as usize]);
+        }
+
+        fn bones(&mut self, bones: &[i32]) {
+            for &bone in bones {
+                self.index(self.bone_index_size, bone as i64);
+            }
+        }
+
+        /// A vertex at the origin; `kind` and `weight` are the deform type
+        /// and whatever follows it.
+        fn vertex(&mut self, kind: u8, weight: impl FnOnce(&mut Self)) {
+            self.f32s(&[0.0; 8]);
+            self.bytes.push(kind);
+            weight(self);
+            self.f32(1.0);
+        }
+
+        fn finish(mut self, indices: &[i64]) -> Vec<u8> {
+            self.count(indices.len());
+            for &index in indices {
+                self.index(self.vertex_index_size, index);
+            }
+            // Textures, materials, bones, morphs, display frames, rigid
+            // bodies and joints
+            for _ in 0..7 {
+                self.count(0);
+            }
+            self.bytes
+        }
+    }
+
+    fn no_vertices(mut builder: Builder) -> Vec<u8> {
+        builder.count(0);
+        builder.finish(&[])
+    }
+
+    #[test]
+    fn text_encodings() {
+        for &encoding in &[TextEncoding::Utf8, TextEncoding::Utf16Le] {
+            let bytes = no_vertices(Builder::new(2.0, encoding, 1, 1));
+            let model = read(bytes.as_slice()).unwrap();
+            assert_eq!(model.header.encoding, encoding);
+            assert_eq!(model.name, "初音ミク");
+            assert_eq!(model.name_en, "Miku");
+            assert_eq!(model.comment, "");
+            assert_eq!(model.comment_en, "comment\n");
+        }
+    }
+
+    #[test]
+    fn index_sizes() {
+        // Vertex indices of one and two bytes are unsigned, of four signed
+        let cases: [(u8, i64, u32); 3] = [(1, 200, 200), (2, 40000, 40000), (4, 70000, 70000)];
+        for &(size, written, expected) in &cases {
+            let mut builder = Builder::new(2.0, TextEncoding::Utf8, size, size);
+            builder.count(1);
+            builder.vertex(2, |b| {
+                b.bones(&[-1, 0, 100, 120]);
+                b.f32s(&[0.25; 4]);
+            });
+            let model = read(builder.finish(&[0, written, 1]).as_slice()).unwrap();
+            assert_eq!(model.indices, [0, expected, 1]);
+            // Bone indices are signed at every size
+            let (bones, _) = model.vertices[0].weight.bones();
+            assert_eq!(bones, [-1, 0, 100, 120]);
+        }
+    }
+
+    #[test]
+    fn weight_types() {
+        let mut builder = Builder::new(2.1, TextEncoding::Utf16Le, 2, 2);
+        builder.count(5);
+        builder.vertex(0, |b| b.bones(&[3]));
+        builder.vertex(1, |b| {
+            b.bones(&[1, 2]);
+            b.f32(0.75);
+        });
+        builder.vertex(2, |b| {
+            b.bones(&[1, 2, 3, 4]);
+            b.f32s(&[0.1, 0.2, 0.3, 0.4]);
+        });
+        builder.vertex(3, |b| {
+            b.bones(&[5, 6]);
+            b.f32(0.5);
+            b.f32s(&[0.0, 1.0, 0.0, 0.0, 2.0, 0.0, 0.0, 3.0, 0.0]);
+        });
+        builder.vertex(4, |b| {
+            b.bones(&[7, 8, -1, -1]);
+            b.f32s(&[0.5, 0.5, 0.0, 0.0]);
+        });
+        let model = read(builder.finish(&[0, 1, 2, 2, 3, 4]).as_slice()).unwrap();
+
+        let weights = model.vertices.iter().map(|v| v.weight).collect::<Vec<_>>();
+        assert_eq!(
+            weights,
+            [
+                Weight::Bdef1 { bone: 3 },
+                Weight::Bdef2 {
+                    bones: [1, 2],
+                    weight: 0.75,
+                },
+                Weight::Bdef4 {
+                    bones: [1, 2, 3, 4],
+                    weights: [0.1, 0.2, 0.3, 0.4],
+                },
+                Weight::Sdef {
+                    bones: [5, 6],
+                    weight: 0.5,
+                    c: Vector3::new(0.0, 1.0, 0.0),
+                    r0: Vector3::new(0.0, 2.0, 0.0),
+                    r1: Vector3::new(0.0, 3.0, 0.0),
+                },
+                Weight::Qdef {
+                    bones: [7, 8, -1, -1],
+                    weights: [0.5, 0.5, 0.0, 0.0],
+                },
+            ]
+        );
+        assert!(model.vertices.iter().all(|v| v.edge_scale == 1.0));
+        assert!(model.soft_bodies.is_empty());
+    }
+
+    #[test]
+    fn qdef_needs_2_1() {
+        let mut builder = Builder::new(2.0, TextEncoding::Utf8, 1, 1);
+        builder.count(1);
+        builder.vertex(4, |b| {
+            b.bones(&[0, 0, 0, 0]);
+            b.f32s(&[1.0, 0.0, 0.0, 0.0]);
+        });
+        let error = read(builder.finish(&[]).as_slice()).unwrap_err();
+        assert!(matches!(
+            error,
+            PmxError::InvalidValue {
+                what: "weight deform type",
+                value: 4,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn bad_signature() {
+        let mut bytes = no_vertices(Builder::new(2.0, TextEncoding::Utf8, 1, 1));
+        bytes[..4].copy_from_slice(b"Pmd ");
+        let error = read(bytes.as_slice()).unwrap_err();
+        assert!(matches!(error, PmxError::InvalidSignature(signature) if &signature == b"Pmd "));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = no_vertices(Builder::new(2.0, TextEncoding::Utf8, 1, 1));
+        for len in [3, 20, bytes.len() - 1] {
+            let error = read(&bytes[..len]).unwrap_err();
+            assert!(
+                matches!(&error, PmxError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof),
+                "{} bytes: {}",
+                len,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn text_longer_than_the_file() {
+        // The model name claims to be 2 GB long
+        let mut bytes = Builder::new(2.0, TextEncoding::Utf8, 1, 1).bytes;
+        bytes.truncate(17);
+        bytes.extend_from_slice(&i32::MAX.to_le_bytes());
+        bytes.extend_from_slice(b"Miku");
+        let error = read(bytes.as_slice()).unwrap_err();
+        assert!(matches!(&error, PmxError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How deep below a search root a texture is looked for by basename.
+const MAX_SEARCH_DEPTH: usize = 6;
+
+/// Finds the files that MTL texture statements refer to.
+///
+/// Models exported from MMD or Blender on Windows often keep the absolute path
+/// of the machine they were authored on (`D:\\Models\\Miku\\skin.png`). Such a
+/// path is normalized and then tried against the model folder and every search
+/// root, first as a relative path and then by basename, ignoring case. When
+/// nothing matches the fallback texture is used instead.
+#[derive(Debug, Clone)]
+pub struct TextureResolver {
+    pub search_roots: Vec<PathBuf>,
+    pub fallback: PathBuf,
+}
+
+impl TextureResolver {
+    pub fn new(search_roots: Vec<PathBuf>, fallback: PathBuf) -> Self {
+        Self {
+            search_roots,
+            fallback,
+        }
+    }
+
+    /// Resolves `raw`, the path written in the MTL file, for a model stored in
+    /// `model_dir`. Always returns a path; unresolved textures log a warning
+    /// and map to the fallback.
+    pub fn resolve(&self, model_dir: &Path, raw: &str) -> PathBuf {
+        match self.find(model_dir, raw) {
+            Some(path) => path,
+            None => {
+                if !raw.trim().is_empty() {
+                    log::warn!(
+                        "texture {:?} not found near {:?}, using {:?}",
+                        raw,
+                        model_dir,
+                        self.fallback
+                    );
+                }
+                self.fallback.clone()
+            }
+        }
+    }
+
+    /// Like [`TextureResolver::resolve`] but returns `None` instead of the
+    /// fallback.
+    pub fn find(&self, model_dir: &Path, raw: &str) -> Option<PathBuf> {
+        let components = normalize(raw);
+        let basename = components.last()?;
+
+        let direct = Path::new(raw);
+        if direct.is_absolute() && direct.is_file() {
+            return Some(direct.to_path_buf());
+        }
+
+        let roots = std::iter::once(model_dir)
+            .chain(self.search_roots.iter().map(PathBuf::as_path))
+            .collect::<Vec<_>>();
+
+        // Try the longest trailing part of the path first, so that
+        // `tex/skin.png` wins over some other `skin.png` further away.
+        for root in &roots {
+            for start in 0..components.len() {
+                if let Some(path) = join_ignore_case(root, &components[start..]) {
+                    return Some(path);
+                }
+            }
+        }
+
+        roots
+            .iter()
+            .find_map(|root| find_by_name(root, basename, MAX_SEARCH_DEPTH))
+    }
+}
+
+/// Splits an MTL texture path into its components, treating `\` as a
+/// separator and dropping drive prefixes, roots and `.` segments. A `..`
+/// removes the component before it; one with nothing before it is kept, so
+/// that the path stays relative to the folder it is resolved against.
+pub fn normalize(raw: &str) -> Vec<String> {
+    let unified = raw.trim().replace('\\', "/");
+    let without_drive = match unified.as_bytes() {
+        [drive, b':', ..] if drive.is_ascii_alphabetic() => &unified[2..],
+        _ => &unified[..],
+    };
+
+    let mut components: Vec<String> = Vec::new();
+    for component in without_drive.split('/') {
+        match component {
+            "" | "." => {}
+            ".." if components.last().is_some_and(|last| last != "..") => {
+                components.pop();
+            }
+            _ => components.push(component.to_string()),
+        }
+    }
+    components
+}
+
+fn join_ignore_case(root: &Path, components: &[String]) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for component in components {
+        let exact = path.join(component);
+        path = if exact.exists() {
+            exact
+        } else {
+            fs::read_dir(&path)
+                .ok()?
+                .filter_map(|entry| entry.ok())
+                .find(|entry| {
+                    entry
+                        .file_name()
+                        .to_string_lossy()
+                        .eq_ignore_ascii_case(component)
+                })?
+                .path()
+        };
+    }
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+fn find_by_name(dir: &Path, name: &str, depth: usize) -> Option<PathBuf> {
+    let mut entries = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    let (dirs, files): (Vec<_>, Vec<_>) = entries.into_iter().partition(|path| path.is_dir());
+    if let Some(file) = files.into_iter().find(|path| {
+        path.file_name()
+            .is_some_and(|file_name| file_name.to_string_lossy().eq_ignore_ascii_case(name))
+    }) {
+        return Some(file);
+    }
+
+    if depth == 0 {
+        return None;
+    }
+    dirs.iter()
+        .find_map(|dir| find_by_name(dir, name, depth - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_windows_path() {
+        assert_eq!(
+            normalize(r"D:\noname\Sources\mmd\skin.png"),
+            ["noname", "Sources", "mmd", "skin.png"]
+        );
+    }
+
+    #[test]
+    fn normalize_dot_segments() {
+        assert_eq!(normalize("textures/../tex.png"), ["tex.png"]);
+        assert_eq!(normalize("./a/./b/../c.png"), ["a", "c.png"]);
+        assert_eq!(
+            normalize("../../shared/c.png"),
+            ["..", "..", "shared", "c.png"]
+        );
+    }
+
+    #[test]
+    fn find_ignores_case_after_parent_segment() {
+        let dir = std::env::temp_dir().join(format!("taggix-resolver-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Tex.PNG"), b"").unwrap();
+        let resolver = TextureResolver::new(Vec::new(), PathBuf::from("fallback.png"));
+        let found = resolver.find(&dir, r"textures\..\tex.png");
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found, Some(dir.join("Tex.PNG")));
+    }
+}
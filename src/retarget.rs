@@ -0,0 +1,580 @@
+//! Playing motions made for one model on another.
+//!
+//! VMD tracks name their bones and morphs, so a motion only plays on the
+//! bones of a model that go by the same names. [`Retargeter`] renames the
+//! tracks of a motion to the names the model uses: from a mapping file, then
+//! by the name itself with full-width letters and spacing ignored, then by
+//! the standard MMD bones' Japanese and English names. Given the model the
+//! motion was made for, it also turns the rotations of bones that point
+//! another way at rest, such as arms in an A pose rather than a T pose, and
+//! scales translations by the difference in leg height.
+
+use anyhow::{bail, Context, Result};
+use cgmath::prelude::*;
+use cgmath::{Quaternion, Vector3};
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+
+use crate::pmx::{BoneTail, PmxModel};
+use crate::vmd::Motion;
+
+/// Standard bones as `(Japanese, English)`, English names as PMX Editor
+/// gives them. A bone may have several English names.
+const BONES: &[(&str, &str)] = &[
+    ("全ての親", "master"),
+    ("全ての親", "mother"),
+    ("センター", "center"),
+    ("グルーブ", "groove"),
+    ("腰", "waist"),
+    ("上半身", "upper body"),
+    ("上半身2", "upper body2"),
+    ("下半身", "lower body"),
+    ("首", "neck"),
+    ("頭", "head"),
+    ("両目", "eyes"),
+];
+
+/// Standard bones on both sides, without the side: `左` or `右` goes before
+/// the Japanese name and `_L` or `_R` after the English one.
+const SIDED_BONES: &[(&str, &str)] = &[
+    ("肩", "shoulder"),
+    ("腕", "arm"),
+    ("腕捩", "arm twist"),
+    ("ひじ", "elbow"),
+    ("手捩", "wrist twist"),
+    ("手首", "wrist"),
+    ("親指０", "thumb0"),
+    ("親指１", "thumb1"),
+    ("親指２", "thumb2"),
+    ("人指１", "fore1"),
+    ("人指２", "fore2"),
+    ("人指３", "fore3"),
+    ("中指１", "middle1"),
+    ("中指２", "middle2"),
+    ("中指３", "middle3"),
+    ("薬指１", "third1"),
+    ("薬指２", "third2"),
+    ("薬指３", "third3"),
+    ("小指１", "little1"),
+    ("小指２", "little2"),
+    ("小指３", "little3"),
+    ("足", "leg"),
+    ("ひざ", "knee"),
+    ("足首", "ankle"),
+    ("つま先", "toe"),
+    ("足ＩＫ", "leg IK"),
+    ("つま先ＩＫ", "toe IK"),
+    ("目", "eye"),
+];
+
+const SIDES: [(&str, &str); 2] = [("左", "_L"), ("右", "_R")];
+
+/// The bone whose height at rest gives the scale of a model's motions.
+const LEG: &str = "左足";
+
+/// Rest directions closer than this, in radians, are taken as the same.
+const MIN_CORRECTION: f32 = 0.01;
+
+/// What [`Retargeter::retarget`] did to a motion.
+#[derive(Debug, Default)]
+pub struct RetargetReport {
+    /// `(motion name, model name)` of every track that plays under another
+    /// name.
+    pub renamed: Vec<(String, String)>,
+    /// Bone tracks left out: the model has no bone for them, or another
+    /// track already plays on it.
+    pub unmapped_bones: BTreeSet<String>,
+    pub unmapped_morphs: BTreeSet<String>,
+    /// Bone tracks turned to make up for a different rest pose.
+    pub compensated: usize,
+}
+
+impl RetargetReport {
+    pub fn log(&self, path: &Path) {
+        if !self.renamed.is_empty() || self.compensated > 0 {
+            log::info!(
+                "{:?}: {} tracks renamed, {} bones turned for the rest pose",
+                path,
+                self.renamed.len(),
+                self.compensated
+            );
+        }
+        for (from, to) in &self.renamed {
+            log::debug!("{:?}: {:?} plays on {:?}", path, from, to);
+        }
+        if !self.unmapped_bones.is_empty() {
+            log::warn!(
+                "{:?}: the model has no bones {:?}",
+                path,
+                self.unmapped_bones
+            );
+        }
+        if !self.unmapped_morphs.is_empty() {
+            log::warn!(
+                "{:?}: the model has no morphs {:?}",
+                path,
+                self.unmapped_morphs
+            );
+        }
+    }
+}
+
+pub struct Retargeter {
+    /// Motion names to model names, from a mapping file.
+    mapping: HashMap<String, String>,
+    /// The model the motions were made for.
+    source: Option<PmxModel>,
+}
+
+impl Retargeter {
+    pub fn new(mapping: HashMap<String, String>, source: Option<PmxModel>) -> Self {
+        Self { mapping, source }
+    }
+
+    /// `motion` with its tracks renamed for `target`, leaving out those that
+    /// have no bone or morph there.
+    pub fn retarget(&self, mut motion: Motion, target: &PmxModel) -> (Motion, RetargetReport) {
+        let mut report = RetargetReport::default();
+        let bones = NameIndex::new(target.bones.iter().map(|bone| bone.name.as_str()));
+        let morphs = NameIndex::new(target.morphs.iter().map(|morph| morph.name.as_str()));
+        let (corrections, scale) = match &self.source {
+            Some(source) => self.rest_corrections(source, target, &bones),
+            None => (vec![None; target.bones.len()], 1.0),
+        };
+
+        // Tracks named as in the model go first, so that they keep their bone
+        let mut tracks = motion.bones.drain().collect::<Vec<_>>();
+        tracks.sort_by(|(a, _), (b, _)| {
+            (bones.exact(a).is_none(), a).cmp(&(bones.exact(b).is_none(), b))
+        });
+        let mut taken = vec![false; target.bones.len()];
+        for (name, mut track) in tracks {
+            let bone = match self.find_bone(&name, &bones) {
+                Some(bone) if !taken[bone] => bone,
+                _ => {
+                    report.unmapped_bones.insert(name);
+                    continue;
+                }
+            };
+            taken[bone] = true;
+
+            // A bone whose direction is unknown turns like its parent
+            let parent_correction = ancestors(target, bone).find_map(|parent| corrections[parent]);
+            let correction = corrections[bone].or(parent_correction);
+            let parent_correction = parent_correction.unwrap_or_else(Quaternion::one);
+            let correction = correction.unwrap_or_else(Quaternion::one);
+            if parent_correction != Quaternion::one() || correction != Quaternion::one() {
+                let inverse = parent_correction.invert();
+                for key in &mut track {
+                    key.rotation = inverse * key.rotation * correction;
+                }
+                report.compensated += 1;
+            }
+            if scale != 1.0 {
+                for key in &mut track {
+                    key.translation *= scale;
+                }
+            }
+
+            let model_name = target.bones[bone].name.clone();
+            if model_name != name {
+                report.renamed.push((name, model_name.clone()));
+            }
+            motion.bones.insert(model_name, track);
+        }
+
+        for key in &mut motion.ik {
+            key.ik
+                .retain_mut(|(name, _)| match self.find_bone(name, &bones) {
+                    Some(bone) => {
+                        *name = target.bones[bone].name.clone();
+                        true
+                    }
+                    None => {
+                        report.unmapped_bones.insert(name.clone());
+                        false
+                    }
+                });
+        }
+
+        let mut tracks = motion.morphs.drain().collect::<Vec<_>>();
+        tracks.sort_by(|(a, _), (b, _)| {
+            (morphs.exact(a).is_none(), a).cmp(&(morphs.exact(b).is_none(), b))
+        });
+        let mut taken = vec![false; target.morphs.len()];
+        for (name, track) in tracks {
+            let morph = self
+                .mapping
+                .get(&name)
+                .and_then(|mapped| morphs.find(mapped))
+                .or_else(|| morphs.find(&name));
+            let morph = match morph {
+                Some(morph) if !taken[morph] => morph,
+                _ => {
+                    report.unmapped_morphs.insert(name);
+                    continue;
+                }
+            };
+            taken[morph] = true;
+            let model_name = target.morphs[morph].name.clone();
+            if model_name != name {
+                report.renamed.push((name, model_name.clone()));
+            }
+            motion.morphs.insert(model_name, track);
+        }
+
+        (motion, report)
+    }
+
+    /// The bone of the model that the track `name` plays on.
+    fn find_bone(&self, name: &str, bones: &NameIndex) -> Option<usize> {
+        self.mapping
+            .get(name)
+            .and_then(|mapped| bones.find(mapped))
+            .or_else(|| bones.find(name))
+            .or_else(|| {
+                standard_names(name)
+                    .iter()
+                    .find_map(|standard| bones.find(standard))
+            })
+    }
+
+    /// For every bone of `target`, the rotation that turns it from its rest
+    /// direction to that of the same bone of `source`, `None` where either
+    /// direction is unknown; and how much taller `target` stands.
+    fn rest_corrections(
+        &self,
+        source: &PmxModel,
+        target: &PmxModel,
+        bones: &NameIndex,
+    ) -> (Vec<Option<Quaternion<f32>>>, f32) {
+        let mut corrections = vec![None; target.bones.len()];
+        for (index, bone) in source.bones.iter().enumerate() {
+            let target_index = match self.find_bone(&bone.name, bones) {
+                Some(target_index) => target_index,
+                None => continue,
+            };
+            let (from, to) = match (
+                rest_direction(target, target_index),
+                rest_direction(source, index),
+            ) {
+                (Some(from), Some(to)) => (from, to),
+                _ => continue,
+            };
+            corrections[target_index] = Some(if from.angle(to).0 > MIN_CORRECTION {
+                Quaternion::from_arc(from, to, None)
+            } else {
+                Quaternion::one()
+            });
+        }
+
+        let source_bones = NameIndex::new(source.bones.iter().map(|bone| bone.name.as_str()));
+        let leg_height = |model: &PmxModel, bones: &NameIndex| {
+            self.find_bone(LEG, bones)
+                .map(|bone| model.bones[bone].position.y)
+                .filter(|&height| height > 0.0)
+        };
+        let scale = match (leg_height(source, &source_bones), leg_height(target, bones)) {
+            (Some(source), Some(target)) => target / source,
+            _ => 1.0,
+        };
+        (corrections, scale)
+    }
+}
+
+/// Reads a mapping file: one `motion name = model name` per line, in UTF-8
+/// or Shift-JIS, with `#` starting a comment line.
+pub fn load_mapping<P: AsRef<Path>>(path: P) -> Result<HashMap<String, String>> {
+    let path = path.as_ref();
+    let bytes =
+        fs::read(path).with_context(|| format!("failed to read mapping file {:?}", path))?;
+    let text = match std::str::from_utf8(&bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => encoding_rs::SHIFT_JIS.decode(&bytes).0.into_owned(),
+    };
+    parse_mapping(&text).with_context(|| format!("failed to parse mapping file {:?}", path))
+}
+
+pub fn parse_mapping(source: &str) -> Result<HashMap<String, String>> {
+    let mut mapping = HashMap::new();
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim_start_matches('\u{feff}').trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => {
+                mapping.insert(from.trim().to_string(), to.trim().to_string());
+            }
+            _ => bail!(
+                "line {}: expected `motion name = model name`, got {:?}",
+                number + 1,
+                line
+            ),
+        }
+    }
+    Ok(mapping)
+}
+
+/// Names looked up as given, then with [`normalize`].
+struct NameIndex<'a> {
+    names: HashMap<&'a str, usize>,
+    normalized: HashMap<String, usize>,
+}
+
+impl<'a> NameIndex<'a> {
+    /// The first of several equal names wins, as in MMD.
+    fn new(names: impl Iterator<Item = &'a str>) -> Self {
+        let mut index = Self {
+            names: HashMap::new(),
+            normalized: HashMap::new(),
+        };
+        for (i, name) in names.enumerate() {
+            index.names.entry(name).or_insert(i);
+            index.normalized.entry(normalize(name)).or_insert(i);
+        }
+        index
+    }
+
+    fn exact(&self, name: &str) -> Option<usize> {
+        self.names.get(name).copied()
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.exact(name)
+            .or_else(|| self.normalized.get(&normalize(name)).copied())
+    }
+}
+
+/// `name` with full-width letters and digits made ASCII, in lower case and
+/// without spaces, `_`, `.` or `-`: `左足ＩＫ` and `左足IK`, or `arm_L` and
+/// `Arm.L`, are the same.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xfee0).unwrap_or(c),
+            _ => c,
+        })
+        .filter(|c| !matches!(c, ' ' | '\u{3000}' | '_' | '.' | '-'))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The other names of a standard bone called `name`.
+fn standard_names(name: &str) -> Vec<String> {
+    let name = normalize(name);
+    let sided = SIDES.iter().flat_map(|(left_right, suffix)| {
+        SIDED_BONES.iter().map(move |(japanese, english)| {
+            (
+                format!("{}{}", left_right, japanese),
+                format!("{}{}", english, suffix),
+            )
+        })
+    });
+    let unsided = BONES
+        .iter()
+        .map(|(japanese, english)| (japanese.to_string(), english.to_string()));
+    let mut names = Vec::new();
+    for (japanese, english) in unsided.chain(sided) {
+        if normalize(&japanese) == name {
+            names.push(english);
+        } else if normalize(&english) == name {
+            names.push(japanese);
+        }
+    }
+    names
+}
+
+/// Which way `bone` points at rest, towards its tail; `None` for bones
+/// without a tail.
+fn rest_direction(model: &PmxModel, bone: usize) -> Option<Vector3<f32>> {
+    let direction = match model.bones[bone].tail {
+        BoneTail::Bone(tail) => {
+            let tail = usize::try_from(tail)
+                .ok()
+                .filter(|&tail| tail < model.bones.len())?;
+            model.bones[tail].position - model.bones[bone].position
+        }
+        BoneTail::Offset(offset) => offset,
+    };
+    (direction.magnitude2() > 1e-8).then(|| direction.normalize())
+}
+
+/// The parent of `bone`, its parent and so on, stopping at a loop.
+fn ancestors(model: &PmxModel, bone: usize) -> impl Iterator<Item = usize> + '_ {
+    let count = model.bones.len();
+    let parent = move |bone: usize| {
+        usize::try_from(model.bones[bone].parent)
+            .ok()
+            .filter(|&parent| parent < count)
+    };
+    let mut current = parent(bone);
+    let mut steps = 0;
+    std::iter::from_fn(move || {
+        let bone = current?;
+        steps += 1;
+        current = if steps < count { parent(bone) } else { None };
+        Some(bone)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pmx::{Bone, Header, TextEncoding};
+    use crate::vmd::{BoneInterpolation, BoneKeyframe, IkKeyframe};
+
+    /// A model of `(name, parent, position, tail offset)` bones.
+    fn model(bones: &[(&str, i32, [f32; 3], [f32; 3])]) -> PmxModel {
+        let bones = bones
+            .iter()
+            .map(|&(name, parent, position, tail)| Bone {
+                name: name.to_string(),
+                name_en: String::new(),
+                position: position.into(),
+                parent,
+                layer: 0,
+                flags: 0,
+                tail: BoneTail::Offset(tail.into()),
+                append: None,
+                fixed_axis: None,
+                local_axis: None,
+                external_parent: None,
+                ik: None,
+            })
+            .collect();
+        PmxModel {
+            header: Header {
+                version: 2.0,
+                encoding: TextEncoding::Utf16Le,
+                additional_uvs: 0,
+                vertex_index_size: 4,
+                texture_index_size: 4,
+                material_index_size: 4,
+                bone_index_size: 4,
+                morph_index_size: 4,
+                rigid_body_index_size: 4,
+            },
+            name: String::new(),
+            name_en: String::new(),
+            comment: String::new(),
+            comment_en: String::new(),
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            textures: Vec::new(),
+            materials: Vec::new(),
+            bones,
+            morphs: Vec::new(),
+            display_frames: Vec::new(),
+            rigid_bodies: Vec::new(),
+            joints: Vec::new(),
+            soft_bodies: Vec::new(),
+        }
+    }
+
+    /// A motion with one identity keyframe on each of `bones`.
+    fn motion(bones: &[&str]) -> Motion {
+        let mut motion = Motion::default();
+        for name in bones {
+            let key = BoneKeyframe {
+                frame: 0,
+                translation: Vector3::zero(),
+                rotation: Quaternion::one(),
+                interpolation: BoneInterpolation::default(),
+                physics_disabled: false,
+            };
+            motion.bones.insert(name.to_string(), vec![key]);
+        }
+        motion
+    }
+
+    /// The bone tracks of `motion`, sorted.
+    fn track_names(motion: &Motion) -> Vec<&str> {
+        let mut names = motion.bones.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn maps_standard_japanese_and_english_names() {
+        let english = model(&[
+            ("arm_L", -1, [0.0; 3], [1.0, 0.0, 0.0]),
+            ("leg IK_L", -1, [0.0; 3], [0.0; 3]),
+        ]);
+        let mut source = motion(&["左腕", "左足ＩＫ"]);
+        source.ik.push(IkKeyframe {
+            frame: 0,
+            visible: true,
+            ik: vec![("左足ＩＫ".to_string(), false)],
+        });
+        let retargeter = Retargeter::new(HashMap::new(), None);
+        let (retargeted, report) = retargeter.retarget(source, &english);
+        assert_eq!(track_names(&retargeted), ["arm_L", "leg IK_L"]);
+        assert_eq!(retargeted.ik[0].ik, [("leg IK_L".to_string(), false)]);
+        assert!(report
+            .renamed
+            .contains(&("左腕".to_string(), "arm_L".to_string())));
+        assert!(report.unmapped_bones.is_empty());
+
+        let japanese = model(&[
+            ("左腕", -1, [0.0; 3], [1.0, 0.0, 0.0]),
+            ("左足ＩＫ", -1, [0.0; 3], [0.0; 3]),
+        ]);
+        let (retargeted, _) = retargeter.retarget(motion(&["Arm.L", "leg_IK_L"]), &japanese);
+        assert_eq!(track_names(&retargeted), ["左腕", "左足ＩＫ"]);
+    }
+
+    #[test]
+    fn mapping_file_goes_before_the_standard_names() {
+        let target = model(&[
+            ("arm_L", -1, [0.0; 3], [0.0; 3]),
+            ("腕L", -1, [0.0; 3], [0.0; 3]),
+        ]);
+        let mapping = parse_mapping("# own rig\n左腕 = 腕L\n").unwrap();
+        let (retargeted, _) = Retargeter::new(mapping, None).retarget(motion(&["左腕"]), &target);
+        assert_eq!(track_names(&retargeted), ["腕L"]);
+        let (retargeted, _) =
+            Retargeter::new(HashMap::new(), None).retarget(motion(&["左腕"]), &target);
+        assert_eq!(track_names(&retargeted), ["arm_L"]);
+    }
+
+    #[test]
+    fn second_track_on_a_bone_is_unmapped() {
+        let target = model(&[("左腕", -1, [0.0; 3], [1.0, 0.0, 0.0])]);
+        let retargeter = Retargeter::new(HashMap::new(), None);
+        let (retargeted, report) = retargeter.retarget(motion(&["arm_L", "左腕", "尻尾"]), &target);
+        assert_eq!(track_names(&retargeted), ["左腕"]);
+        let unmapped = report
+            .unmapped_bones
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        assert_eq!(unmapped, ["arm_L", "尻尾"]);
+        assert!(report.renamed.is_empty());
+    }
+
+    #[test]
+    fn turns_a_pose_arms_to_t_pose() {
+        let t_pose = model(&[
+            ("上半身", -1, [0.0, 8.0, 0.0], [0.0, 2.0, 0.0]),
+            ("左腕", 0, [1.0, 10.0, 0.0], [3.0, 0.0, 0.0]),
+        ]);
+        let a_pose = model(&[
+            ("上半身", -1, [0.0, 8.0, 0.0], [0.0, 2.0, 0.0]),
+            ("左腕", 0, [1.0, 10.0, 0.0], [2.0, -2.0, 0.0]),
+        ]);
+        let retargeter = Retargeter::new(HashMap::new(), Some(t_pose));
+        let (retargeted, report) = retargeter.retarget(motion(&["上半身", "左腕"]), &a_pose);
+        assert_eq!(report.compensated, 1);
+        assert_eq!(retargeted.bones["上半身"][0].rotation, Quaternion::one());
+        let arm = retargeted.bones["左腕"][0].rotation;
+        let direction = arm.rotate_vector(Vector3::new(1.0, -1.0, 0.0).normalize());
+        assert!(
+            direction.angle(Vector3::unit_x()).0 < 1e-4,
+            "{:?}",
+            direction
+        );
+    }
+}
@@ -0,0 +1,24 @@
+// Vertex shader for vertices morphed and skinned on the CPU, for adapters
+// without storage buffers in the vertex stage
+
+[[stage(vertex)]]
+fn main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+
+    var out: VertexOutput;
+    out.tex_coords = model.tex_coords;
+    out.world_position = world_position.xyz;
+    out.world_normal = (model_matrix * vec4<f32>(model.normal, 0.0)).xyz;
+    out.world_tangent = world_tangent(model_matrix, model.tangent);
+    out.clip_position = camera.view_proj * world_position;
+    return out;
+}
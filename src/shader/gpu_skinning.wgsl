@@ -0,0 +1,202 @@
+// Vertex shader skinning with the bones and morphs in storage buffers
+
+struct Bone {
+    // Moves a vertex from the rest pose to the posed bone
+    skinning: mat4x4<f32>;
+    // The rotation of `skinning` as a quaternion, and the dual part of
+    // `skinning` as a dual quaternion
+    rotation: vec4<f32>;
+    dual: vec4<f32>;
+};
+[[block]]
+struct Bones {
+    bones: array<Bone>;
+};
+[[group(2), binding(0)]]
+var<storage, read> bones: Bones;
+
+// How far one morph moves one vertex
+struct MorphOffset {
+    position: vec3<f32>;
+    morph: u32;
+    uv: vec4<f32>;
+};
+[[block]]
+struct MorphOffsets {
+    offsets: array<MorphOffset>;
+};
+[[group(2), binding(1)]]
+var<storage, read> morph_offsets: MorphOffsets;
+[[block]]
+struct MorphWeights {
+    weights: array<f32>;
+};
+[[group(2), binding(2)]]
+var<storage, read> morph_weights: MorphWeights;
+
+// Position, normal and tangent in model space after skinning
+struct Skinned {
+    position: vec3<f32>;
+    normal: vec3<f32>;
+    tangent: vec3<f32>;
+};
+
+fn quat_rotate(q: vec4<f32>, v: vec3<f32>) -> vec3<f32> {
+    return v + 2.0 * cross(q.xyz, cross(q.xyz, v) + q.w * v);
+}
+
+fn quat_slerp(a: vec4<f32>, b: vec4<f32>, t: f32) -> vec4<f32> {
+    var to: vec4<f32> = b;
+    var d: f32 = dot(a, b);
+    // Take the short way round
+    if (d < 0.0) {
+        to = -b;
+        d = -d;
+    }
+    if (d > 0.9995) {
+        return normalize(mix(a, to, t));
+    }
+    let theta = acos(d);
+    return (sin((1.0 - t) * theta) * a + sin(t * theta) * to) / sin(theta);
+}
+
+// BDEF1, BDEF2 and BDEF4
+fn skin_linear(model: VertexInput) -> Skinned {
+    var out: Skinned;
+    out.position = vec3<f32>(0.0);
+    out.normal = vec3<f32>(0.0);
+    out.tangent = vec3<f32>(0.0);
+    var i: u32 = 0u;
+    loop {
+        if (i >= 4u) {
+            break;
+        }
+        let weight = model.bone_weights[i];
+        if (weight > 0.0) {
+            let index = model.bone_indices[i];
+            let m = bones.bones[index].skinning;
+            out.position = out.position + weight * (m * vec4<f32>(model.position, 1.0)).xyz;
+            out.normal = out.normal + weight * (m * vec4<f32>(model.normal, 0.0)).xyz;
+            out.tangent = out.tangent + weight * (m * vec4<f32>(model.tangent.xyz, 0.0)).xyz;
+        }
+        continuing {
+            i = i + 1u;
+        }
+    }
+    return out;
+}
+
+// MMD's spherical deform: the vertex turns around the centre by the blended
+// rotation of both bones while the centre follows their blended positions
+fn skin_sdef(model: VertexInput) -> Skinned {
+    let bone0 = bones.bones[model.bone_indices.x];
+    let bone1 = bones.bones[model.bone_indices.y];
+    let w0 = model.bone_weights.x;
+    let w1 = model.bone_weights.y;
+    let q = quat_slerp(bone0.rotation, bone1.rotation, w1);
+
+    var out: Skinned;
+    out.position = quat_rotate(q, model.position - model.sdef_c)
+        + (bone0.skinning * vec4<f32>(model.sdef_r0, 1.0)).xyz * w0
+        + (bone1.skinning * vec4<f32>(model.sdef_r1, 1.0)).xyz * w1;
+    out.normal = quat_rotate(q, model.normal);
+    out.tangent = quat_rotate(q, model.tangent.xyz);
+    return out;
+}
+
+// Dual quaternion blend of up to four bones
+fn skin_qdef(model: VertexInput) -> Skinned {
+    let pivot = bones.bones[model.bone_indices.x].rotation;
+    var real: vec4<f32> = vec4<f32>(0.0);
+    var dual: vec4<f32> = vec4<f32>(0.0);
+    var i: u32 = 0u;
+    loop {
+        if (i >= 4u) {
+            break;
+        }
+        let index = model.bone_indices[i];
+        let bone = bones.bones[index];
+        var weight: f32 = model.bone_weights[i];
+        // q and -q are the same rotation; blend them all on one side
+        if (dot(bone.rotation, pivot) < 0.0) {
+            weight = -weight;
+        }
+        real = real + weight * bone.rotation;
+        dual = dual + weight * bone.dual;
+        continuing {
+            i = i + 1u;
+        }
+    }
+    // Weights that are all zero or cancel out blend to nothing; fall back
+    // to the first bone rather than divide by zero
+    let len = length(real);
+    if (len < 1e-6) {
+        real = pivot;
+        dual = bones.bones[model.bone_indices.x].dual;
+    } else {
+        real = real / len;
+        dual = dual / len;
+    }
+    let translation = 2.0 * (real.w * dual.xyz - dual.w * real.xyz + cross(real.xyz, dual.xyz));
+
+    var out: Skinned;
+    out.position = quat_rotate(real, model.position) + translation;
+    out.normal = quat_rotate(real, model.normal);
+    out.tangent = quat_rotate(real, model.tangent.xyz);
+    return out;
+}
+
+[[stage(vertex)]]
+fn main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    // Morphs move the vertex in the rest pose, before skinning
+    var morphed: VertexInput = model;
+    if (model.skinning != 3u) {
+        var i: u32 = model.morph_range.x;
+        let end = model.morph_range.x + model.morph_range.y;
+        loop {
+            if (i >= end) {
+                break;
+            }
+            let offset = morph_offsets.offsets[i];
+            let weight = morph_weights.weights[offset.morph];
+            morphed.position = morphed.position + weight * offset.position;
+            morphed.tex_coords = morphed.tex_coords + weight * offset.uv.xy;
+            continuing {
+                i = i + 1u;
+            }
+        }
+    }
+
+    var skinned: Skinned;
+    if (model.skinning == 3u) {
+        skinned.position = model.position;
+        skinned.normal = model.normal;
+        skinned.tangent = model.tangent.xyz;
+    } elseif (model.skinning == 1u) {
+        skinned = skin_sdef(morphed);
+    } elseif (model.skinning == 2u) {
+        skinned = skin_qdef(morphed);
+    } else {
+        skinned = skin_linear(morphed);
+    }
+
+    let world_position = model_matrix * vec4<f32>(skinned.position, 1.0);
+
+    var out: VertexOutput;
+    out.tex_coords = morphed.tex_coords;
+    out.world_position = world_position.xyz;
+    out.world_normal = (model_matrix * vec4<f32>(skinned.normal, 0.0)).xyz;
+    out.world_tangent = world_tangent(model_matrix, vec4<f32>(skinned.tangent, model.tangent.w));
+    out.clip_position = camera.view_proj * world_position;
+    return out;
+}
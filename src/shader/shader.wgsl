@@ -1,4 +1,6 @@
-// Vertex shader
+// Declarations shared by both vertex shaders, and the fragment shader. The
+// vertex shader that goes with them is appended from gpu_skinning.wgsl or
+// cpu_skinning.wgsl.
 
 [[block]]
 struct Camera {
@@ -11,6 +13,17 @@ var<uniform> camera: Camera;
 struct VertexInput {
     [[location(0)]] position: vec3<f32>;
     [[location(1)]] tex_coords: vec2<f32>;
+    [[location(2)]] normal: vec3<f32>;
+    [[location(3)]] tangent: vec4<f32>;
+    [[location(4)]] bone_indices: vec4<u32>;
+    [[location(9)]] bone_weights: vec4<f32>;
+    [[location(10)]] sdef_c: vec3<f32>;
+    [[location(11)]] sdef_r0: vec3<f32>;
+    [[location(12)]] sdef_r1: vec3<f32>;
+    // 0 linear blend, 1 SDEF, 2 QDEF, 3 already skinned on the CPU
+    [[location(13)]] skinning: u32;
+    // First entry and number of entries in morph_offsets
+    [[location(14)]] morph_range: vec2<u32>;
 };
 struct InstanceInput {
     [[location(5)]] model_matrix_0: vec4<f32>;
@@ -22,24 +35,23 @@ struct InstanceInput {
 struct VertexOutput {
     [[builtin(position)]] clip_position: vec4<f32>;
     [[location(0)]] tex_coords: vec2<f32>;
+    [[location(1)]] world_position: vec3<f32>;
+    [[location(2)]] world_normal: vec3<f32>;
+    [[location(3)]] world_tangent: vec4<f32>;
 };
 
-[[stage(vertex)]]
-fn main(
-    model: VertexInput,
-    instance: InstanceInput,
-) -> VertexOutput {
-    let model_matrix = mat4x4<f32>(
-        instance.model_matrix_0,
-        instance.model_matrix_1,
-        instance.model_matrix_2,
-        instance.model_matrix_3,
-    );
-
-    var out: VertexOutput;
-    out.tex_coords = model.tex_coords;
-    out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
-    return out;
+// Takes a model space tangent and bitangent sign to world space. A mirroring
+// instance transform, such as the z = -1 scale left-handed models are drawn
+// with, turns cross(normal, tangent) around, so the sign turns with it.
+fn world_tangent(model_matrix: mat4x4<f32>, tangent: vec4<f32>) -> vec4<f32> {
+    let x = model_matrix[0].xyz;
+    let y = model_matrix[1].xyz;
+    let z = model_matrix[2].xyz;
+    var handedness: f32 = tangent.w;
+    if (dot(cross(x, y), z) < 0.0) {
+        handedness = -handedness;
+    }
+    return vec4<f32>((model_matrix * vec4<f32>(tangent.xyz, 0.0)).xyz, handedness);
 }
 
 // Fragment shader
@@ -49,8 +61,77 @@ var t_diffuse: texture_2d<f32>;
 [[group(0), binding(1)]]
 var s_diffuse: sampler;
 
+[[block]]
+struct Material {
+    ambient: vec4<f32>;
+    // w is the dissolve
+    diffuse: vec4<f32>;
+    // w is the specular exponent
+    specular: vec4<f32>;
+    emissive: vec4<f32>;
+    uv_offset: vec2<f32>;
+    uv_scale: vec2<f32>;
+    illumination: u32;
+    normal_map: u32;
+    // Texture tint of material morphs
+    texture_multiply: vec4<f32>;
+    texture_add: vec4<f32>;
+};
+[[group(0), binding(2)]]
+var<uniform> material: Material;
+[[group(0), binding(3)]]
+var t_normal: texture_2d<f32>;
+[[group(0), binding(4)]]
+var s_normal: sampler;
+
+// Applies the MTL -s/-o options; the samplers repeat or clamp the result
+fn transform_uv(tex_coords: vec2<f32>) -> vec2<f32> {
+    return tex_coords * material.uv_scale + material.uv_offset;
+}
+
+let light_direction: vec3<f32> = vec3<f32>(0.3, 0.8, 0.5);
+let light_color: vec3<f32> = vec3<f32>(0.6, 0.6, 0.6);
+let ambient_color: vec3<f32> = vec3<f32>(0.4, 0.4, 0.4);
+
 [[stage(fragment)]]
 fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
-    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
-    return object_color;
-}
\ No newline at end of file
+    let uv = transform_uv(in.tex_coords);
+    let object_color: vec4<f32> =
+        textureSample(t_diffuse, s_diffuse, uv) * material.texture_multiply + material.texture_add;
+    let normal_sample: vec4<f32> = textureSample(t_normal, s_normal, uv);
+    let diffuse_color = material.diffuse.rgb * object_color.rgb;
+    let alpha = material.diffuse.a * object_color.a;
+
+    // illum 0: colour on, ambient off
+    if (material.illumination == 0u) {
+        return vec4<f32>(diffuse_color + material.emissive.rgb, alpha);
+    }
+
+    let view_dir = normalize(camera.view_pos.xyz - in.world_position);
+    var normal: vec3<f32> = normalize(in.world_normal);
+    if (material.normal_map != 0u) {
+        // Re-orthogonalize the interpolated tangent frame
+        let tangent = normalize(in.world_tangent.xyz - normal * dot(in.world_tangent.xyz, normal));
+        let bitangent = in.world_tangent.w * cross(normal, tangent);
+        let tangent_normal = normal_sample.xyz * 2.0 - 1.0;
+        normal = normalize(tangent * tangent_normal.x + bitangent * tangent_normal.y + normal * tangent_normal.z);
+    }
+    // Back faces are drawn too, so turn the normal towards the viewer
+    if (dot(normal, view_dir) < 0.0) {
+        normal = -normal;
+    }
+
+    let light_dir = normalize(light_direction);
+    let diffuse_strength = max(dot(normal, light_dir), 0.0);
+    var color: vec3<f32> = material.ambient.rgb * ambient_color * object_color.rgb
+        + diffuse_color * light_color * diffuse_strength
+        + material.emissive.rgb;
+
+    if (material.illumination >= 2u) {
+        let half_dir = normalize(view_dir + light_dir);
+        let specular_strength = pow(max(dot(normal, half_dir), 0.0), max(material.specular.w, 1.0));
+        color = color + material.specular.rgb * light_color * specular_strength;
+    }
+
+    return vec4<f32>(color, alpha);
+}
@@ -0,0 +1,577 @@
+//! Bones of a model and the pose they are in.
+//!
+//! Nothing in here touches the GPU; [`Skeleton::evaluate`] turns the local
+//! pose of every bone into world and skinning matrices that the renderer
+//! uploads.
+
+use cgmath::prelude::*;
+use cgmath::{Matrix3, Matrix4, Quaternion, Vector3};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::ik;
+use crate::interpolation::slerp;
+use crate::pmx::{self, PmxModel};
+
+/// Local transform of a bone relative to its rest pose, as keyed in MMD.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BonePose {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    /// MMD never scales bones, but other sources may.
+    pub scale: Vector3<f32>,
+}
+
+impl Default for BonePose {
+    fn default() -> Self {
+        Self {
+            translation: Vector3::zero(),
+            rotation: Quaternion::one(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Bone {
+    pub name: String,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    /// Model space position in the rest pose.
+    pub rest_position: Vector3<f32>,
+    /// Rest position relative to the parent's.
+    pub rest_offset: Vector3<f32>,
+    /// PMX transform level; lower levels deform first.
+    pub layer: i32,
+    /// Deformed after the physics step rather than before it.
+    pub after_physics: bool,
+    /// Set for IK bones, see [`crate::ik`].
+    pub ik: Option<IkChain>,
+    pub append: Option<Append>,
+}
+
+/// Inherits a weighted part of another bone's rotation and/or translation,
+/// PMX's 付与.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Append {
+    pub parent: usize,
+    pub weight: f32,
+    pub rotation: bool,
+    pub translation: bool,
+    /// Inherit everything that moved the parent in model space, its own
+    /// parents included, rather than only the parent's own pose.
+    pub local: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IkLink {
+    pub bone: usize,
+    /// Euler angle limits in radians, minimum and maximum.
+    pub limits: Option<(Vector3<f32>, Vector3<f32>)>,
+}
+
+/// Turns the links so that `target` reaches the IK bone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IkChain {
+    pub target: usize,
+    pub loop_count: u32,
+    /// Largest rotation of a link per iteration, in radians.
+    pub limit_angle: f32,
+    /// From the bone nearest the target up the chain.
+    pub links: Vec<IkLink>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Skeleton {
+    pub bones: Vec<Bone>,
+    /// One entry per bone.
+    pub pose: Vec<BonePose>,
+    /// Bone indices in the order they deform: before physics, then after,
+    /// each sorted by layer and then by index.
+    pub deform_order: Vec<usize>,
+    /// Model space transform of every bone, written by [`Skeleton::evaluate`].
+    pub world: Vec<Matrix4<f32>>,
+    /// Rotation the IK solver adds on top of the pose, one per bone.
+    pub ik_rotation: Vec<Quaternion<f32>>,
+    /// Whether the IK of each bone is solved; only IK bones look at it.
+    pub ik_enabled: Vec<bool>,
+    /// What bone morphs add to the pose of each bone, see [`crate::morph`].
+    pub morph_translation: Vec<Vector3<f32>>,
+    pub morph_rotation: Vec<Quaternion<f32>>,
+    /// What each bone inherits from its append parent, written as the bone
+    /// deforms.
+    pub append_rotation: Vec<Quaternion<f32>>,
+    pub append_translation: Vec<Vector3<f32>>,
+    by_name: HashMap<String, usize>,
+}
+
+impl Skeleton {
+    pub fn from_pmx(model: &PmxModel) -> Self {
+        let count = model.bones.len();
+        let parent_of = |index: usize| {
+            let parent = model.bones[index].parent;
+            usize::try_from(parent)
+                .ok()
+                .filter(|&parent| parent < count && parent != index)
+        };
+
+        let mut parents = (0..count).map(parent_of).collect::<Vec<_>>();
+        // A parent loop would never finish evaluating; cut it where found
+        for index in 0..count {
+            let mut ancestor = parents[index];
+            let mut steps = 0;
+            while let Some(current) = ancestor {
+                steps += 1;
+                if current == index || steps > count {
+                    log::warn!(
+                        "bone {:?} is its own ancestor, detaching it from its parent",
+                        model.bones[index].name
+                    );
+                    parents[index] = None;
+                    break;
+                }
+                ancestor = parents[current];
+            }
+        }
+
+        let mut bones = model
+            .bones
+            .iter()
+            .zip(&parents)
+            .map(|(bone, &parent)| Bone {
+                name: bone.name.clone(),
+                parent,
+                children: Vec::new(),
+                rest_position: bone.position,
+                rest_offset: match parent {
+                    Some(parent) => bone.position - model.bones[parent].position,
+                    None => bone.position,
+                },
+                layer: bone.layer,
+                after_physics: bone.has(pmx::bone_flags::AFTER_PHYSICS),
+                ik: bone.ik.as_ref().and_then(|ik| ik_chain(ik, count)),
+                append: bone.append.and_then(|append| {
+                    let parent = usize::try_from(append.parent)
+                        .ok()
+                        .filter(|&parent| parent < count)?;
+                    Some(Append {
+                        parent,
+                        weight: append.weight,
+                        rotation: bone.has(pmx::bone_flags::APPEND_ROTATION),
+                        translation: bone.has(pmx::bone_flags::APPEND_TRANSLATION),
+                        local: bone.has(pmx::bone_flags::LOCAL_APPEND),
+                    })
+                }),
+            })
+            .collect::<Vec<_>>();
+        for (index, &parent) in parents.iter().enumerate() {
+            if let Some(parent) = parent {
+                bones[parent].children.push(index);
+            }
+        }
+
+        let mut deform_order = (0..count).collect::<Vec<_>>();
+        deform_order.sort_by_key(|&index| (bones[index].after_physics, bones[index].layer, index));
+
+        // The first of several bones with the same name wins, as in MMD
+        let mut by_name = HashMap::new();
+        for (index, bone) in bones.iter().enumerate() {
+            by_name.entry(bone.name.clone()).or_insert(index);
+        }
+
+        let world = bones
+            .iter()
+            .map(|bone| Matrix4::from_translation(bone.rest_position))
+            .collect();
+
+        Self {
+            pose: vec![BonePose::default(); count],
+            ik_rotation: vec![Quaternion::one(); count],
+            ik_enabled: vec![true; count],
+            morph_translation: vec![Vector3::zero(); count],
+            morph_rotation: vec![Quaternion::one(); count],
+            append_rotation: vec![Quaternion::one(); count],
+            append_translation: vec![Vector3::zero(); count],
+            bones,
+            deform_order,
+            world,
+            by_name,
+        }
+    }
+
+    pub fn bone_index(&self, name: &str) -> Option<usize> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Transform of `bone` relative to its parent.
+    pub fn local_matrix(&self, bone: usize) -> Matrix4<f32> {
+        let pose = &self.pose[bone];
+        let translation = self.own_translation(bone) + self.append_translation[bone];
+        let rotation =
+            self.ik_rotation[bone] * self.own_rotation(bone) * self.append_rotation[bone];
+        Matrix4::from_translation(self.bones[bone].rest_offset + translation)
+            * Matrix4::from(rotation)
+            * Matrix4::from_nonuniform_scale(pose.scale.x, pose.scale.y, pose.scale.z)
+    }
+
+    /// Recomputes the world matrices of every bone from the pose.
+    pub fn evaluate(&mut self) {
+        self.evaluate_phase(false);
+        self.evaluate_phase(true);
+    }
+
+    /// Recomputes the world matrices of the bones that deform before or
+    /// after physics. As in MMD, bones follow [`Skeleton::deform_order`], so
+    /// a bone on a lower layer than its parent uses the parent's matrix from
+    /// before this update, and each IK bone is solved when its turn comes.
+    /// Append parents are read as they are at that point, so a bone that
+    /// inherits from an IK link has to deform after the IK bone.
+    pub fn evaluate_phase(&mut self, after_physics: bool) {
+        for i in 0..self.deform_order.len() {
+            let bone = self.deform_order[i];
+            if self.bones[bone].after_physics == after_physics {
+                self.update_world(bone);
+                if self.bones[bone].ik.is_some() {
+                    ik::solve(self, bone);
+                }
+            }
+        }
+    }
+
+    /// Recomputes the world matrix of `bone` from its parent's, and what it
+    /// inherits from its append parent as that bone is now.
+    pub fn update_world(&mut self, bone: usize) {
+        self.update_append(bone);
+        let local = self.local_matrix(bone);
+        self.world[bone] = match self.bones[bone].parent {
+            Some(parent) => self.world[parent] * local,
+            None => local,
+        };
+    }
+
+    /// The pose of `bone` with its bone morphs.
+    pub fn own_translation(&self, bone: usize) -> Vector3<f32> {
+        self.pose[bone].translation + self.morph_translation[bone]
+    }
+
+    pub fn own_rotation(&self, bone: usize) -> Quaternion<f32> {
+        self.pose[bone].rotation * self.morph_rotation[bone]
+    }
+
+    fn update_append(&mut self, bone: usize) {
+        let append = match self.bones[bone].append {
+            Some(append) => append,
+            None => return,
+        };
+        let parent = append.parent;
+        if append.rotation {
+            let rotation = if append.local {
+                Quaternion::from(Matrix3::from_cols(
+                    self.world[parent].x.truncate().normalize(),
+                    self.world[parent].y.truncate().normalize(),
+                    self.world[parent].z.truncate().normalize(),
+                ))
+            } else {
+                // An append parent's own append is passed on
+                self.ik_rotation[parent] * self.own_rotation(parent) * self.append_rotation[parent]
+            };
+            self.append_rotation[bone] = slerp(Quaternion::one(), rotation, append.weight);
+        }
+        if append.translation {
+            let translation = if append.local {
+                self.world_position(parent) - self.bones[parent].rest_position
+            } else {
+                self.own_translation(parent) + self.append_translation[parent]
+            };
+            self.append_translation[bone] = translation * append.weight;
+        }
+    }
+
+    /// Recomputes the world matrices of `bone` and all its descendants, for
+    /// when a solver changes the pose of a bone that has already deformed.
+    pub fn update_subtree(&mut self, bone: usize) {
+        let mut stack = vec![bone];
+        while let Some(bone) = stack.pop() {
+            self.update_world(bone);
+            stack.extend(self.bones[bone].children.iter().copied());
+        }
+    }
+
+    /// Model space position of `bone` in the evaluated pose.
+    pub fn world_position(&self, bone: usize) -> Vector3<f32> {
+        self.world[bone].w.truncate()
+    }
+
+    /// Moves vertices from the rest pose to where `bone` has taken them.
+    pub fn skinning_matrix(&self, bone: usize) -> Matrix4<f32> {
+        self.world[bone] * Matrix4::from_translation(-self.bones[bone].rest_position)
+    }
+
+    /// [`Skeleton::skinning_matrix`] as a unit dual quaternion, the rotation
+    /// and the dual part. Scale is ignored.
+    pub fn skinning_dual_quaternion(&self, bone: usize) -> (Quaternion<f32>, Quaternion<f32>) {
+        let matrix = self.skinning_matrix(bone);
+        let rotation = Matrix3::from_cols(
+            matrix.x.truncate().normalize(),
+            matrix.y.truncate().normalize(),
+            matrix.z.truncate().normalize(),
+        );
+        let rotation = Quaternion::from(rotation).normalize();
+        let translation = Quaternion::from_sv(0.0, matrix.w.truncate());
+        (rotation, translation * rotation * 0.5)
+    }
+}
+
+/// The IK of a PMX bone, `None` if it refers to bones that do not exist.
+fn ik_chain(ik: &pmx::Ik, count: usize) -> Option<IkChain> {
+    let bone = |index: i32| usize::try_from(index).ok().filter(|&index| index < count);
+    Some(IkChain {
+        target: bone(ik.target)?,
+        loop_count: u32::try_from(ik.loop_count).unwrap_or(0),
+        limit_angle: ik.limit_angle,
+        links: ik
+            .links
+            .iter()
+            .map(|link| {
+                Some(IkLink {
+                    bone: bone(link.bone)?,
+                    limits: link.limits,
+                })
+            })
+            .collect::<Option<_>>()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Deg;
+
+    use crate::pmx::bone_flags;
+
+    fn assert_near(actual: Vector3<f32>, expected: Vector3<f32>) {
+        assert!(
+            actual.distance(expected) < 1e-5,
+            "{:?} is not {:?}",
+            actual,
+            expected
+        );
+    }
+
+    fn model(bones: Vec<pmx::Bone>) -> PmxModel {
+        let mut model = PmxModel::empty();
+        model.bones = bones;
+        model
+    }
+
+    /// Three bones straight up, 2 and then 3 apart.
+    fn chain() -> Skeleton {
+        Skeleton::from_pmx(&model(vec![
+            pmx::Bone::new("0", Vector3::new(0.0, 1.0, 0.0), -1),
+            pmx::Bone::new("1", Vector3::new(0.0, 3.0, 0.0), 0),
+            pmx::Bone::new("2", Vector3::new(0.0, 6.0, 0.0), 1),
+        ]))
+    }
+
+    fn quarter_turn() -> Quaternion<f32> {
+        Quaternion::from_angle_z(Deg(90.0))
+    }
+
+    #[test]
+    fn deform_order_is_physics_then_layer_then_index() {
+        let bone = |layer, after_physics| {
+            let mut bone = pmx::Bone::new("", Vector3::zero(), -1);
+            bone.layer = layer;
+            if after_physics {
+                bone.flags |= bone_flags::AFTER_PHYSICS;
+            }
+            bone
+        };
+        let skeleton = Skeleton::from_pmx(&model(vec![
+            bone(1, false),
+            bone(0, true),
+            bone(0, false),
+            bone(1, false),
+            bone(-1, true),
+        ]));
+        assert_eq!(skeleton.deform_order, [2, 0, 3, 4, 1]);
+    }
+
+    #[test]
+    fn parent_cycles_are_cut() {
+        let mut skeleton = Skeleton::from_pmx(&model(vec![
+            pmx::Bone::new("a", Vector3::zero(), 1),
+            pmx::Bone::new("b", Vector3::zero(), 0),
+            pmx::Bone::new("self", Vector3::zero(), 2),
+            pmx::Bone::new("child", Vector3::zero(), 1),
+            pmx::Bone::new("missing", Vector3::zero(), 99),
+        ]));
+        let parents = skeleton
+            .bones
+            .iter()
+            .map(|bone| bone.parent)
+            .collect::<Vec<_>>();
+        assert_eq!(parents, [None, Some(0), None, Some(1), None]);
+        assert_eq!(skeleton.bones[0].children, [1]);
+        assert_eq!(skeleton.bones[1].children, [3]);
+        // Finishes rather than following the loop
+        skeleton.evaluate();
+    }
+
+    #[test]
+    fn evaluate_composes_down_the_chain() {
+        let mut skeleton = chain();
+        skeleton.pose[0].translation = Vector3::new(1.0, 0.0, 0.0);
+        skeleton.pose[0].rotation = quarter_turn();
+        skeleton.pose[1].rotation = quarter_turn();
+        skeleton.evaluate();
+
+        assert_near(skeleton.world_position(0), Vector3::new(1.0, 1.0, 0.0));
+        assert_near(skeleton.world_position(1), Vector3::new(-1.0, 1.0, 0.0));
+        assert_near(skeleton.world_position(2), Vector3::new(-1.0, -2.0, 0.0));
+        // The last bone inherits both turns
+        let up = skeleton.world[2] * Vector3::unit_y().extend(0.0);
+        assert_near(up.truncate(), -Vector3::unit_y());
+    }
+
+    #[test]
+    fn skinning_matrix_is_identity_at_rest() {
+        let mut skeleton = chain();
+        skeleton.evaluate();
+        for bone in 0..3 {
+            let matrix = skeleton.skinning_matrix(bone);
+            let error = matrix - Matrix4::identity();
+            let columns = [error.x, error.y, error.z, error.w];
+            assert!(columns.iter().all(|c| c.magnitude() < 1e-6), "{:?}", matrix);
+        }
+    }
+
+    #[test]
+    fn dual_quaternion_matches_skinning_matrix() {
+        let mut skeleton = chain();
+        skeleton.pose[0].translation = Vector3::new(0.5, -1.0, 2.0);
+        skeleton.pose[0].rotation = Quaternion::from_angle_x(Deg(30.0));
+        skeleton.pose[1].rotation = Quaternion::from_angle_y(Deg(-70.0));
+        skeleton.evaluate();
+
+        let (real, dual) = skeleton.skinning_dual_quaternion(1);
+        assert!((real.magnitude() - 1.0).abs() < 1e-6);
+        // t = 2 * dual * conjugate(real)
+        let translation = (dual * real.conjugate() * 2.0).v;
+        let matrix = skeleton.skinning_matrix(1);
+        for &point in &[
+            Vector3::zero(),
+            Vector3::new(0.0, 3.0, 0.0),
+            Vector3::new(1.0, 4.0, -2.0),
+        ] {
+            let expected = (matrix * point.extend(1.0)).truncate();
+            assert_near(real.rotate_vector(point) + translation, expected);
+        }
+    }
+
+    fn assert_rotation(actual: Quaternion<f32>, expected: Quaternion<f32>) {
+        assert!(
+            actual.dot(expected).abs() > 1.0 - 1e-6,
+            "{:?} is not {:?}",
+            actual,
+            expected
+        );
+    }
+
+    /// A bone at `position` that inherits `flags` from `parent` at `weight`.
+    fn appending(position: Vector3<f32>, parent: i32, weight: f32, flags: u16) -> pmx::Bone {
+        let mut bone = pmx::Bone::new("", position, -1);
+        bone.flags |= flags;
+        bone.append = Some(pmx::Append { parent, weight });
+        bone
+    }
+
+    #[test]
+    fn append_rotation_is_slerped_by_weight() {
+        let mut skeleton = Skeleton::from_pmx(&model(vec![
+            pmx::Bone::new("", Vector3::zero(), -1),
+            appending(Vector3::unit_x(), 0, 0.5, bone_flags::APPEND_ROTATION),
+        ]));
+        skeleton.pose[0].rotation = quarter_turn();
+        skeleton.evaluate();
+
+        let half_turn = Quaternion::from_angle_z(Deg(45.0));
+        assert_rotation(skeleton.append_rotation[1], half_turn);
+        // Turned about its own position, which stays put
+        assert_near(skeleton.world_position(1), Vector3::unit_x());
+        let x = skeleton.world[1] * Vector3::unit_x().extend(0.0);
+        assert_near(x.truncate(), half_turn.rotate_vector(Vector3::unit_x()));
+    }
+
+    #[test]
+    fn append_translation_is_scaled_by_weight() {
+        let mut skeleton = Skeleton::from_pmx(&model(vec![
+            pmx::Bone::new("", Vector3::zero(), -1),
+            appending(Vector3::unit_y(), 0, 0.5, bone_flags::APPEND_TRANSLATION),
+        ]));
+        skeleton.pose[0].translation = Vector3::new(2.0, 0.0, -4.0);
+        skeleton.pose[0].rotation = quarter_turn();
+        skeleton.evaluate();
+
+        assert_near(skeleton.append_translation[1], Vector3::new(1.0, 0.0, -2.0));
+        assert_near(skeleton.world_position(1), Vector3::new(1.0, 1.0, -2.0));
+        // Only translation was asked for
+        assert_rotation(skeleton.append_rotation[1], Quaternion::one());
+    }
+
+    #[test]
+    fn local_append_follows_the_parents_world_transform() {
+        let flags = bone_flags::APPEND_ROTATION | bone_flags::APPEND_TRANSLATION;
+        let skeleton = |local: bool| {
+            let mut skeleton = Skeleton::from_pmx(&model(vec![
+                pmx::Bone::new("", Vector3::zero(), -1),
+                pmx::Bone::new("", Vector3::unit_y(), 0),
+                appending(
+                    Vector3::unit_z(),
+                    1,
+                    1.0,
+                    if local {
+                        flags | bone_flags::LOCAL_APPEND
+                    } else {
+                        flags
+                    },
+                ),
+            ]));
+            // Only the append parent's parent moves
+            skeleton.pose[0].translation = Vector3::new(3.0, 0.0, 0.0);
+            skeleton.pose[0].rotation = quarter_turn();
+            skeleton.evaluate();
+            skeleton
+        };
+
+        let own = skeleton(false);
+        assert_rotation(own.append_rotation[2], Quaternion::one());
+        assert_near(own.append_translation[2], Vector3::zero());
+
+        // The append parent went from (0, 1, 0) to (2, 0, 0), turned with the root
+        let local = skeleton(true);
+        assert_rotation(local.append_rotation[2], quarter_turn());
+        assert_near(local.append_translation[2], Vector3::new(2.0, -1.0, 0.0));
+    }
+
+    #[test]
+    fn chained_appends_pass_on_the_parents_append() {
+        let flags = bone_flags::APPEND_ROTATION | bone_flags::APPEND_TRANSLATION;
+        let mut skeleton = Skeleton::from_pmx(&model(vec![
+            pmx::Bone::new("", Vector3::zero(), -1),
+            appending(Vector3::zero(), 0, 1.0, flags),
+            appending(Vector3::zero(), 1, 0.5, flags),
+        ]));
+        skeleton.pose[0].translation = Vector3::new(0.0, 4.0, 0.0);
+        skeleton.pose[0].rotation = Quaternion::from_angle_x(Deg(60.0));
+        // Added to what bone 1 inherits before bone 2 takes half
+        skeleton.pose[1].translation = Vector3::new(2.0, 0.0, 0.0);
+        skeleton.evaluate();
+
+        assert_rotation(
+            skeleton.append_rotation[2],
+            Quaternion::from_angle_x(Deg(30.0)),
+        );
+        assert_near(skeleton.append_translation[2], Vector3::new(1.0, 2.0, 0.0));
+    }
+}
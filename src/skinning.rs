@@ -0,0 +1,323 @@
+//! CPU version of the skinning in `gpu_skinning.wgsl`.
+//!
+//! Deforms vertices with the same BDEF, SDEF and QDEF math as the vertex
+//! shader, for checking the GPU against known positions and for drawing
+//! without a bone storage buffer.
+
+use cgmath::prelude::*;
+use cgmath::{Matrix4, Point3, Quaternion, Vector3, Vector4};
+use rayon::prelude::*;
+
+use crate::interpolation::slerp;
+use crate::model::{skinning, ModelData, ModelVertex, MorphOffset};
+use crate::morph;
+use crate::skeleton::Skeleton;
+
+/// What the vertex shader reads of one bone.
+#[derive(Debug, Clone, Copy)]
+pub struct BoneTransform {
+    pub matrix: Matrix4<f32>,
+    pub rotation: Quaternion<f32>,
+    pub dual: Quaternion<f32>,
+}
+
+impl BoneTransform {
+    pub fn identity() -> Self {
+        Self {
+            matrix: Matrix4::identity(),
+            rotation: Quaternion::one(),
+            dual: Quaternion::zero(),
+        }
+    }
+
+    /// The skinning transform of every bone in the evaluated pose.
+    pub fn from_skeleton(skeleton: &Skeleton) -> Vec<Self> {
+        (0..skeleton.bones.len())
+            .map(|bone| {
+                let (rotation, dual) = skeleton.skinning_dual_quaternion(bone);
+                Self {
+                    matrix: skeleton.skinning_matrix(bone),
+                    rotation,
+                    dual,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The rest pose of a model's meshes, kept to deform them on the CPU every
+/// frame.
+pub struct CpuDeformer {
+    meshes: Vec<Vec<ModelVertex>>,
+    morph_offsets: Vec<MorphOffset>,
+}
+
+impl CpuDeformer {
+    pub fn new(data: &ModelData) -> Self {
+        Self {
+            meshes: data
+                .meshes
+                .iter()
+                .map(|mesh| mesh.vertices.clone())
+                .collect(),
+            morph_offsets: data.morph_offsets.clone(),
+        }
+    }
+
+    /// The vertices of every mesh with the morphs at their resolved weights,
+    /// then skinned to `bones`.
+    pub fn deform(&self, bones: &[BoneTransform], morph_weights: &[f32]) -> Vec<Vec<ModelVertex>> {
+        self.meshes
+            .iter()
+            .map(|vertices| {
+                let morphed = morph::morph_vertices(vertices, &self.morph_offsets, morph_weights);
+                skin_vertices(&morphed, bones)
+            })
+            .collect()
+    }
+}
+
+/// A copy of `vertices` in the pose of `bones`, marked as already skinned.
+/// Vertices referring to bones past the end of `bones` use the identity.
+pub fn skin_vertices(vertices: &[ModelVertex], bones: &[BoneTransform]) -> Vec<ModelVertex> {
+    vertices
+        .par_iter()
+        .map(|vertex| skin_vertex(vertex, bones))
+        .collect()
+}
+
+pub fn skin_vertex(vertex: &ModelVertex, bones: &[BoneTransform]) -> ModelVertex {
+    let identity = BoneTransform::identity();
+    let bone = |slot: usize| {
+        bones
+            .get(vertex.bone_indices[slot] as usize)
+            .unwrap_or(&identity)
+    };
+    let position = Vector3::from(vertex.position);
+    let normal = Vector3::from(vertex.normal);
+    let tangent = Vector4::from(vertex.tangent).truncate();
+
+    let (position, normal, tangent) = match vertex.skinning {
+        skinning::NONE => (position, normal, tangent),
+        skinning::SDEF => {
+            let (bone0, bone1) = (bone(0), bone(1));
+            let [w0, w1, ..] = vertex.bone_weights;
+            let q = slerp(bone0.rotation, bone1.rotation, w1);
+            let c = Vector3::from(vertex.sdef_c);
+            let r0 = bone0.matrix.transform_point(Point3::from(vertex.sdef_r0));
+            let r1 = bone1.matrix.transform_point(Point3::from(vertex.sdef_r1));
+            (
+                q.rotate_vector(position - c) + r0.to_vec() * w0 + r1.to_vec() * w1,
+                q.rotate_vector(normal),
+                q.rotate_vector(tangent),
+            )
+        }
+        skinning::QDEF => {
+            let pivot = bone(0).rotation;
+            let mut real = Quaternion::zero();
+            let mut dual = Quaternion::zero();
+            for slot in 0..4 {
+                let bone = bone(slot);
+                let mut weight = vertex.bone_weights[slot];
+                // q and -q are the same rotation; blend them all on one side
+                if bone.rotation.dot(pivot) < 0.0 {
+                    weight = -weight;
+                }
+                real += bone.rotation * weight;
+                dual += bone.dual * weight;
+            }
+            // Weights that are all zero or cancel out blend to nothing; fall
+            // back to the first bone rather than divide by zero
+            let len = real.magnitude();
+            let (real, dual) = if len < 1e-6 {
+                (pivot, bone(0).dual)
+            } else {
+                (real / len, dual / len)
+            };
+            let translation = (dual * real.conjugate()).v * 2.0;
+            (
+                real.rotate_vector(position) + translation,
+                real.rotate_vector(normal),
+                real.rotate_vector(tangent),
+            )
+        }
+        _ => {
+            let mut skinned = (Vector3::zero(), Vector3::zero(), Vector3::zero());
+            for slot in 0..4 {
+                let weight = vertex.bone_weights[slot];
+                if weight > 0.0 {
+                    let m = bone(slot).matrix;
+                    skinned.0 += (m * position.extend(1.0)).truncate() * weight;
+                    skinned.1 += m.transform_vector(normal) * weight;
+                    skinned.2 += m.transform_vector(tangent) * weight;
+                }
+            }
+            skinned
+        }
+    };
+
+    ModelVertex {
+        position: position.into(),
+        normal: normal.into(),
+        tangent: tangent.extend(vertex.tangent[3]).into(),
+        skinning: skinning::NONE,
+        ..*vertex
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Matrix3};
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    use crate::model::MeshData;
+
+    /// Bone 0 stays at rest, bone 1 moves up by 1 and bone 2 turns 90 degrees
+    /// around z.
+    fn bones() -> Vec<BoneTransform> {
+        let up = Vector3::new(0.0, 1.0, 0.0);
+        let turn = Quaternion::from(Matrix3::from_angle_z(Deg(90.0)));
+        vec![
+            BoneTransform::identity(),
+            BoneTransform {
+                matrix: Matrix4::from_translation(up),
+                rotation: Quaternion::one(),
+                dual: Quaternion::from_sv(0.0, up) * 0.5,
+            },
+            BoneTransform {
+                matrix: Matrix4::from(turn),
+                rotation: turn,
+                dual: Quaternion::zero(),
+            },
+        ]
+    }
+
+    fn vertex(skinning: u32, bone_indices: [u32; 4], bone_weights: [f32; 4]) -> ModelVertex {
+        ModelVertex {
+            position: [1.0, 0.0, 0.0],
+            normal: [1.0, 0.0, 0.0],
+            bone_indices,
+            bone_weights,
+            skinning,
+            ..Default::default()
+        }
+    }
+
+    fn deform(
+        vertices: Vec<ModelVertex>,
+        offsets: Vec<MorphOffset>,
+        weights: &[f32],
+    ) -> Vec<ModelVertex> {
+        let data = ModelData {
+            name: "test".to_string(),
+            meshes: vec![MeshData::new(
+                "mesh".to_string(),
+                vertices,
+                Vec::new(),
+                None,
+            )],
+            materials: Vec::new(),
+            left_handed: true,
+            bone_count: 3,
+            morph_count: weights.len(),
+            morph_offsets: offsets,
+        };
+        CpuDeformer::new(&data).deform(&bones(), weights).remove(0)
+    }
+
+    fn assert_near(actual: [f32; 3], expected: [f32; 3]) {
+        let close = actual
+            .iter()
+            .zip(&expected)
+            .all(|(a, e)| (a - e).abs() < 1e-4);
+        assert!(close, "{:?} is not {:?}", actual, expected);
+    }
+
+    #[test]
+    fn bdef() {
+        let skinned = deform(
+            vec![
+                vertex(skinning::LINEAR, [1, 0, 0, 0], [1.0, 0.0, 0.0, 0.0]),
+                vertex(skinning::LINEAR, [0, 1, 0, 0], [0.5, 0.5, 0.0, 0.0]),
+                vertex(skinning::LINEAR, [0, 1, 2, 1], [0.25; 4]),
+            ],
+            Vec::new(),
+            &[],
+        );
+        assert_near(skinned[0].position, [1.0, 1.0, 0.0]);
+        assert_near(skinned[0].normal, [1.0, 0.0, 0.0]);
+        assert_near(skinned[1].position, [1.0, 0.5, 0.0]);
+        assert_near(skinned[2].position, [0.75, 0.75, 0.0]);
+        assert_near(skinned[2].normal, [0.75, 0.25, 0.0]);
+        assert!(skinned
+            .iter()
+            .all(|vertex| vertex.skinning == skinning::NONE));
+    }
+
+    #[test]
+    fn sdef() {
+        // Turns half way around the centre, which moves with both bones
+        let skinned = deform(
+            vec![ModelVertex {
+                position: [1.0, 1.0, 0.0],
+                sdef_c: [0.0, 1.0, 0.0],
+                sdef_r0: [0.0, 1.0, 0.0],
+                sdef_r1: [0.0, 1.0, 0.0],
+                ..vertex(skinning::SDEF, [0, 2, 0, 0], [0.5, 0.5, 0.0, 0.0])
+            }],
+            Vec::new(),
+            &[],
+        );
+        assert_near(skinned[0].position, [0.207107, 1.207107, 0.0]);
+        assert_near(skinned[0].normal, [FRAC_1_SQRT_2, FRAC_1_SQRT_2, 0.0]);
+    }
+
+    #[test]
+    fn qdef() {
+        let skinned = deform(
+            vec![vertex(skinning::QDEF, [1, 2, 0, 0], [0.5, 0.5, 0.0, 0.0])],
+            Vec::new(),
+            &[],
+        );
+        assert_near(skinned[0].position, [0.5, 1.207107, 0.0]);
+        assert_near(skinned[0].normal, [FRAC_1_SQRT_2, FRAC_1_SQRT_2, 0.0]);
+    }
+
+    #[test]
+    fn qdef_without_weight() {
+        // No weight at all, or weights that cancel, follow the first bone
+        let skinned = deform(
+            vec![
+                vertex(skinning::QDEF, [1, 2, 0, 0], [0.0; 4]),
+                vertex(skinning::QDEF, [2, 0, 0, 0], [0.0; 4]),
+                vertex(skinning::QDEF, [1, 1, 0, 0], [0.5, -0.5, 0.0, 0.0]),
+            ],
+            Vec::new(),
+            &[],
+        );
+        assert_near(skinned[0].position, [1.0, 1.0, 0.0]);
+        assert_near(skinned[1].position, [0.0, 1.0, 0.0]);
+        assert_near(skinned[1].normal, [0.0, 1.0, 0.0]);
+        assert_near(skinned[2].position, [1.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn morphs_before_skinning() {
+        let offset = MorphOffset {
+            position: [0.0, 0.0, 2.0],
+            morph: 0,
+            uv: [0.5, 0.0, 0.0, 0.0],
+        };
+        let skinned = deform(
+            vec![ModelVertex {
+                morph_range: [0, 1],
+                ..vertex(skinning::LINEAR, [2, 0, 0, 0], [1.0, 0.0, 0.0, 0.0])
+            }],
+            vec![offset],
+            &[0.5],
+        );
+        assert_near(skinned[0].position, [0.0, 1.0, 1.0]);
+        assert_eq!(skinned[0].tex_coords, [0.25, 0.0]);
+    }
+}
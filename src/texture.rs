@@ -3,6 +3,7 @@ use image::GenericImageView;
 use std::{num::NonZeroU32, path::Path};
 
 pub struct Texture {
+    #[allow(dead_code)]
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
     pub sampler: wgpu::Sampler,
@@ -20,7 +21,8 @@ impl Texture {
         // Needed to appease the borrow checker
         let path_copy = path.as_ref().to_path_buf();
         let label = path_copy.to_str();
-        let img = image::open(path)?;
+        let img =
+            image::open(path).with_context(|| format!("failed to open texture {:?}", path_copy))?;
         Self::from_image(device, queue, &img, label, is_normal_map)
     }
 
@@ -77,6 +79,19 @@ impl Texture {
         Self::from_image(device, queue, &img, Some(label), is_normal_map)
     }
 
+    /// A 1x1 texture of a single colour, for materials without a texture.
+    pub fn from_color(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color: [u8; 4],
+        label: &str,
+        is_normal_map: bool,
+    ) -> Result<Self> {
+        let img =
+            image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba(color)));
+        Self::from_image(device, queue, &img, Some(label), is_normal_map)
+    }
+
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
@@ -124,15 +139,7 @@ impl Texture {
         );
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
-        });
+        let sampler = Self::image_sampler(device, wgpu::AddressMode::Repeat);
 
         Ok(Self {
             texture,
@@ -140,4 +147,25 @@ impl Texture {
             sampler,
         })
     }
+
+    /// The texture sampled with `address_mode` outside 0..1 instead of
+    /// repeating.
+    pub fn with_address_mode(self, device: &wgpu::Device, address_mode: wgpu::AddressMode) -> Self {
+        Self {
+            sampler: Self::image_sampler(device, address_mode),
+            ..self
+        }
+    }
+
+    fn image_sampler(device: &wgpu::Device, address_mode: wgpu::AddressMode) -> wgpu::Sampler {
+        device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: address_mode,
+            address_mode_v: address_mode,
+            address_mode_w: address_mode,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        })
+    }
 }
@@ -0,0 +1,668 @@
+//! Reader and writer for VMD motions, MikuMikuDance's keyframe format.
+//!
+//! Like the models, motions stay in MMD's left-handed coordinate system.
+
+use cgmath::{Quaternion, Vector3, Vector4};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::binary::{BinaryReader, BinaryWriter};
+
+#[derive(Debug)]
+pub enum VmdError {
+    Io(io::Error),
+    InvalidSignature(String),
+}
+
+impl fmt::Display for VmdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "failed to read or write VMD data: {}", e),
+            Self::InvalidSignature(signature) => {
+                write!(f, "not a VMD file, the signature is {:?}", signature)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmdError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VmdError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, VmdError>;
+
+const SIGNATURE: &str = "Vocaloid Motion Data 0002";
+/// MMD before version 3 wrote this, with a shorter model name.
+const OLD_SIGNATURE: &str = "Vocaloid Motion Data file";
+
+/// Control points of a cubic Bezier easing curve from (0, 0) to (127, 127),
+/// each coordinate from 0 to 127.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bezier {
+    pub x1: u8,
+    pub y1: u8,
+    pub x2: u8,
+    pub y2: u8,
+}
+
+impl Bezier {
+    /// The curve MMD gives new keyframes, a straight line.
+    pub const LINEAR: Self = Self {
+        x1: 20,
+        y1: 20,
+        x2: 107,
+        y2: 107,
+    };
+}
+
+impl Default for Bezier {
+    fn default() -> Self {
+        Self::LINEAR
+    }
+}
+
+/// Easing of each channel of a bone keyframe, applied between the previous
+/// keyframe and this one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BoneInterpolation {
+    pub x: Bezier,
+    pub y: Bezier,
+    pub z: Bezier,
+    pub rotation: Bezier,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoneKeyframe {
+    pub frame: u32,
+    /// Offset from the bone's rest position.
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub interpolation: BoneInterpolation,
+    /// MMD turns physics off for the bone from this keyframe on.
+    pub physics_disabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MorphKeyframe {
+    pub frame: u32,
+    pub weight: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CameraInterpolation {
+    pub x: Bezier,
+    pub y: Bezier,
+    pub z: Bezier,
+    pub rotation: Bezier,
+    pub distance: Bezier,
+    pub fov: Bezier,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraKeyframe {
+    pub frame: u32,
+    /// Distance of the camera from `target`, negative in front of it.
+    pub distance: f32,
+    pub target: Vector3<f32>,
+    /// Euler angles in radians.
+    pub rotation: Vector3<f32>,
+    pub interpolation: CameraInterpolation,
+    /// Vertical field of view in degrees.
+    pub fov: u32,
+    pub perspective: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LightKeyframe {
+    pub frame: u32,
+    pub color: Vector3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SelfShadowKeyframe {
+    pub frame: u32,
+    /// 0 off, 1 and 2 the two shadow modes of MMD.
+    pub mode: u8,
+    pub distance: f32,
+}
+
+/// Turns the model and its IK bones on and off.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IkKeyframe {
+    pub frame: u32,
+    pub visible: bool,
+    /// `(IK bone name, enabled)`
+    pub ik: Vec<(String, bool)>,
+}
+
+/// A parsed VMD file. Every track is sorted by frame, with at most one
+/// keyframe per frame; MMD plays at 30 frames per second.
+#[derive(Debug, Clone, Default)]
+pub struct Motion {
+    /// Model the motion was made for. Camera motions use `カメラ・照明`.
+    pub model_name: String,
+    pub bones: HashMap<String, Vec<BoneKeyframe>>,
+    pub morphs: HashMap<String, Vec<MorphKeyframe>>,
+    pub camera: Vec<CameraKeyframe>,
+    pub light: Vec<LightKeyframe>,
+    pub self_shadow: Vec<SelfShadowKeyframe>,
+    pub ik: Vec<IkKeyframe>,
+}
+
+impl Motion {
+    /// The last frame with a keyframe on any track.
+    pub fn last_frame(&self) -> u32 {
+        let bones = self.bones.values().flatten().map(|key| key.frame);
+        let morphs = self.morphs.values().flatten().map(|key| key.frame);
+        let camera = self.camera.iter().map(|key| key.frame);
+        let light = self.light.iter().map(|key| key.frame);
+        let self_shadow = self.self_shadow.iter().map(|key| key.frame);
+        let ik = self.ik.iter().map(|key| key.frame);
+        bones
+            .chain(morphs)
+            .chain(camera)
+            .chain(light)
+            .chain(self_shadow)
+            .chain(ik)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Writes the motion to the VMD file at `path`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the motion in the layout of MMD 3 and later. Names are cut to
+    /// the 15 bytes of bone and morph names and the 20 bytes of model and IK
+    /// names at the last whole Shift-JIS character. Bone and morph keyframes
+    /// are written track by track in name order.
+    pub fn write<W: Write>(&self, writer: W) -> Result<()> {
+        let mut writer = BinaryWriter::new(writer);
+        let w = &mut writer;
+
+        w.write_shift_jis(SIGNATURE, 30)?;
+        w.write_shift_jis(&self.model_name, 20)?;
+
+        let bones = sorted_tracks(&self.bones);
+        w.write_u32(bones.iter().map(|(_, track)| track.len()).sum::<usize>() as u32)?;
+        for (name, track) in bones {
+            for key in track {
+                let rotation = key.rotation;
+                w.write_shift_jis(name, 15)?;
+                w.write_u32(key.frame)?;
+                w.write_vec3(key.translation)?;
+                w.write_vec4(Vector4::new(
+                    rotation.v.x,
+                    rotation.v.y,
+                    rotation.v.z,
+                    rotation.s,
+                ))?;
+                w.write_bytes(&bone_block(&key.interpolation, key.physics_disabled))?;
+            }
+        }
+
+        let morphs = sorted_tracks(&self.morphs);
+        w.write_u32(morphs.iter().map(|(_, track)| track.len()).sum::<usize>() as u32)?;
+        for (name, track) in morphs {
+            for key in track {
+                w.write_shift_jis(name, 15)?;
+                w.write_u32(key.frame)?;
+                w.write_f32(key.weight)?;
+            }
+        }
+
+        w.write_u32(self.camera.len() as u32)?;
+        for key in &self.camera {
+            let i = &key.interpolation;
+            w.write_u32(key.frame)?;
+            w.write_f32(key.distance)?;
+            w.write_vec3(key.target)?;
+            w.write_vec3(key.rotation)?;
+            for curve in &[i.x, i.y, i.z, i.rotation, i.distance, i.fov] {
+                w.write_bytes(&[curve.x1, curve.x2, curve.y1, curve.y2])?;
+            }
+            w.write_u32(key.fov)?;
+            w.write_u8(if key.perspective { 0 } else { 1 })?;
+        }
+
+        w.write_u32(self.light.len() as u32)?;
+        for key in &self.light {
+            w.write_u32(key.frame)?;
+            w.write_vec3(key.color)?;
+            w.write_vec3(key.direction)?;
+        }
+
+        w.write_u32(self.self_shadow.len() as u32)?;
+        for key in &self.self_shadow {
+            w.write_u32(key.frame)?;
+            w.write_u8(key.mode)?;
+            w.write_f32(key.distance)?;
+        }
+
+        w.write_u32(self.ik.len() as u32)?;
+        for key in &self.ik {
+            w.write_u32(key.frame)?;
+            w.write_u8(key.visible as u8)?;
+            w.write_u32(key.ik.len() as u32)?;
+            for (name, enabled) in &key.ik {
+                w.write_shift_jis(name, 20)?;
+                w.write_u8(*enabled as u8)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the VMD file at `path`.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Motion> {
+    let file = File::open(path)?;
+    read(BufReader::new(file))
+}
+
+pub fn read<R: BufRead>(reader: R) -> Result<Motion> {
+    let mut reader = BinaryReader::new(reader);
+    let r = &mut reader;
+
+    let signature = r.read_shift_jis(30)?;
+    let name_len = match signature.as_str() {
+        SIGNATURE => 20,
+        OLD_SIGNATURE => 10,
+        _ => return Err(VmdError::InvalidSignature(signature)),
+    };
+    let mut motion = Motion {
+        model_name: r.read_shift_jis(name_len)?,
+        ..Default::default()
+    };
+
+    for _ in 0..r.read_u32()? {
+        let name = r.read_shift_jis(15)?;
+        let frame = r.read_u32()?;
+        let translation = r.read_vec3()?;
+        let rotation = r.read_vec4()?;
+        let block = r.read_array::<64>()?;
+        motion.bones.entry(name).or_default().push(BoneKeyframe {
+            frame,
+            translation,
+            rotation: Quaternion::new(rotation.w, rotation.x, rotation.y, rotation.z),
+            interpolation: BoneInterpolation {
+                x: bone_curve(&block, 0),
+                y: bone_curve(&block, 1),
+                z: bone_curve(&block, 2),
+                rotation: bone_curve(&block, 3),
+            },
+            physics_disabled: block[2] == PHYSICS_DISABLED[0] && block[3] == PHYSICS_DISABLED[1],
+        });
+    }
+
+    // Files from older tools end after any section
+    if r.at_end()? {
+        return Ok(motion.sorted());
+    }
+    for _ in 0..r.read_u32()? {
+        let name = r.read_shift_jis(15)?;
+        let frame = r.read_u32()?;
+        let weight = r.read_f32()?;
+        motion
+            .morphs
+            .entry(name)
+            .or_default()
+            .push(MorphKeyframe { frame, weight });
+    }
+
+    if r.at_end()? {
+        return Ok(motion.sorted());
+    }
+    for _ in 0..r.read_u32()? {
+        let frame = r.read_u32()?;
+        let distance = r.read_f32()?;
+        let target = r.read_vec3()?;
+        let rotation = r.read_vec3()?;
+        let block = r.read_array::<24>()?;
+        let curve = |channel: usize| {
+            let c = &block[channel * 4..channel * 4 + 4];
+            Bezier {
+                x1: c[0],
+                x2: c[1],
+                y1: c[2],
+                y2: c[3],
+            }
+        };
+        motion.camera.push(CameraKeyframe {
+            frame,
+            distance,
+            target,
+            rotation,
+            interpolation: CameraInterpolation {
+                x: curve(0),
+                y: curve(1),
+                z: curve(2),
+                rotation: curve(3),
+                distance: curve(4),
+                fov: curve(5),
+            },
+            fov: r.read_u32()?,
+            // Stored inverted, 0 is a perspective camera
+            perspective: r.read_u8()? == 0,
+        });
+    }
+
+    if r.at_end()? {
+        return Ok(motion.sorted());
+    }
+    for _ in 0..r.read_u32()? {
+        motion.light.push(LightKeyframe {
+            frame: r.read_u32()?,
+            color: r.read_vec3()?,
+            direction: r.read_vec3()?,
+        });
+    }
+
+    if r.at_end()? {
+        return Ok(motion.sorted());
+    }
+    for _ in 0..r.read_u32()? {
+        motion.self_shadow.push(SelfShadowKeyframe {
+            frame: r.read_u32()?,
+            mode: r.read_u8()?,
+            distance: r.read_f32()?,
+        });
+    }
+
+    if r.at_end()? {
+        return Ok(motion.sorted());
+    }
+    for _ in 0..r.read_u32()? {
+        let frame = r.read_u32()?;
+        let visible = r.read_u8()? != 0;
+        let count = r.read_u32()?;
+        let mut ik = Vec::with_capacity(count.min(256) as usize);
+        for _ in 0..count {
+            ik.push((r.read_shift_jis(20)?, r.read_u8()? != 0));
+        }
+        motion.ik.push(IkKeyframe { frame, visible, ik });
+    }
+
+    Ok(motion.sorted())
+}
+
+/// Bytes 2 and 3 of a bone interpolation block, where MMD stores the physics
+/// switch over the copy of the z and rotation `x1` values.
+const PHYSICS_DISABLED: [u8; 2] = [99, 15];
+
+/// The bone interpolation block is four 16 byte rows, each the one before
+/// shifted by a byte. Row 0 holds `x1` of the four channels, then `y1`, `x2`
+/// and `y2`; channel `n` is read from row `n`, which has its values in
+/// columns 0, 4, 8 and 12 and is clear of the physics switch.
+fn bone_curve(block: &[u8; 64], channel: usize) -> Bezier {
+    let row = &block[channel * 16..channel * 16 + 16];
+    Bezier {
+        x1: row[0],
+        y1: row[4],
+        x2: row[8],
+        y2: row[12],
+    }
+}
+
+/// The bone interpolation block of a keyframe, laid out as [`bone_curve`]
+/// reads it. The rows after the first are cut short by the shift and end in
+/// zeros, as MMD writes them.
+fn bone_block(interpolation: &BoneInterpolation, physics_disabled: bool) -> [u8; 64] {
+    let i = interpolation;
+    let mut row = [0; 16];
+    for (channel, curve) in [i.x, i.y, i.z, i.rotation].iter().enumerate() {
+        row[channel] = curve.x1;
+        row[4 + channel] = curve.y1;
+        row[8 + channel] = curve.x2;
+        row[12 + channel] = curve.y2;
+    }
+    let mut block = [0; 64];
+    for shift in 0..4 {
+        block[shift * 16..shift * 16 + 16 - shift].copy_from_slice(&row[shift..]);
+    }
+    if physics_disabled {
+        block[2..4].copy_from_slice(&PHYSICS_DISABLED);
+    }
+    block
+}
+
+/// `tracks` in name order, so that a motion is written the same every time.
+fn sorted_tracks<T>(tracks: &HashMap<String, Vec<T>>) -> Vec<(&String, &Vec<T>)> {
+    let mut tracks = tracks.iter().collect::<Vec<_>>();
+    tracks.sort_by(|a, b| a.0.cmp(b.0));
+    tracks
+}
+
+/// Sorts every track by frame, keeping the last keyframe read for a frame.
+fn sort_track<T>(track: &mut Vec<T>, frame: impl Fn(&T) -> u32) {
+    // Stable, so duplicates stay in file order
+    track.sort_by_key(|key| frame(key));
+    let mut deduped: Vec<T> = Vec::with_capacity(track.len());
+    for key in track.drain(..) {
+        match deduped.last_mut() {
+            Some(last) if frame(last) == frame(&key) => *last = key,
+            _ => deduped.push(key),
+        }
+    }
+    *track = deduped;
+}
+
+impl Motion {
+    fn sorted(mut self) -> Self {
+        for track in self.bones.values_mut() {
+            sort_track(track, |key| key.frame);
+        }
+        for track in self.morphs.values_mut() {
+            sort_track(track, |key| key.frame);
+        }
+        sort_track(&mut self.camera, |key| key.frame);
+        sort_track(&mut self.light, |key| key.frame);
+        sort_track(&mut self.self_shadow, |key| key.frame);
+        sort_track(&mut self.ik, |key| key.frame);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::AssetConfig;
+
+    #[test]
+    fn round_trip() {
+        let path = AssetConfig::res_dir().join("yyb_school_miku_pose/baked.vmd");
+        let motion = load(path).unwrap();
+        assert!(!motion.bones.is_empty());
+        let mut bytes = Vec::new();
+        motion.write(&mut bytes).unwrap();
+        let read_back = read(bytes.as_slice()).unwrap();
+        assert_eq!(read_back.model_name, motion.model_name);
+        assert_eq!(read_back.bones, motion.bones);
+        assert_eq!(read_back.morphs, motion.morphs);
+        assert_eq!(read_back.camera, motion.camera);
+        assert_eq!(read_back.ik, motion.ik);
+    }
+
+    #[test]
+    fn long_names_are_cut_at_a_whole_character() {
+        let mut motion = Motion::default();
+        let key = MorphKeyframe {
+            frame: 0,
+            weight: 0.5,
+        };
+        // Two bytes a character in Shift-JIS, so the eighth would end at 16
+        motion
+            .morphs
+            .insert("右ひじ捩れ補助ボーン".to_string(), vec![key]);
+        motion.morphs.insert("まばたきa".to_string(), vec![key]);
+        let mut bytes = Vec::new();
+        motion.write(&mut bytes).unwrap();
+        let read_back = read(bytes.as_slice()).unwrap();
+        let mut names = read_back
+            .morphs
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["まばたきa", "右ひじ捩れ補助"]);
+    }
+
+    fn bone_key(frame: u32, x: f32) -> BoneKeyframe {
+        BoneKeyframe {
+            frame,
+            translation: Vector3::new(x, 0.0, 0.0),
+            rotation: Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            interpolation: BoneInterpolation::default(),
+            physics_disabled: false,
+        }
+    }
+
+    fn camera_key(frame: u32, distance: f32) -> CameraKeyframe {
+        CameraKeyframe {
+            frame,
+            distance,
+            target: Vector3::new(0.0, 10.0, 0.0),
+            rotation: Vector3::new(0.0, 0.0, 0.0),
+            interpolation: CameraInterpolation::default(),
+            fov: 30,
+            perspective: true,
+        }
+    }
+
+    /// A motion with one keyframe in each of the first `sections` sections.
+    fn motion_with(sections: usize) -> Motion {
+        let mut motion = Motion {
+            model_name: "初音ミク".to_string(),
+            ..Default::default()
+        };
+        motion
+            .bones
+            .insert("センター".to_string(), vec![bone_key(0, 1.0)]);
+        if sections > 1 {
+            let key = MorphKeyframe {
+                frame: 1,
+                weight: 0.5,
+            };
+            motion.morphs.insert("あ".to_string(), vec![key]);
+        }
+        if sections > 2 {
+            motion.camera.push(camera_key(2, -45.0));
+        }
+        if sections > 3 {
+            motion.light.push(LightKeyframe {
+                frame: 3,
+                color: Vector3::new(0.6, 0.6, 0.6),
+                direction: Vector3::new(-0.5, -1.0, 0.5),
+            });
+        }
+        if sections > 4 {
+            motion.self_shadow.push(SelfShadowKeyframe {
+                frame: 4,
+                mode: 1,
+                distance: 0.01,
+            });
+        }
+        if sections > 5 {
+            motion.ik.push(IkKeyframe {
+                frame: 5,
+                visible: true,
+                ik: vec![("右足ＩＫ".to_string(), false)],
+            });
+        }
+        motion
+    }
+
+    #[test]
+    fn old_signature_has_a_shorter_model_name() {
+        let mut bytes = Vec::new();
+        let mut w = BinaryWriter::new(&mut bytes);
+        w.write_shift_jis(OLD_SIGNATURE, 30).unwrap();
+        w.write_shift_jis("初音ミク", 10).unwrap();
+        w.write_u32(1).unwrap();
+        w.write_shift_jis("センター", 15).unwrap();
+        w.write_u32(7).unwrap();
+        w.write_vec3(Vector3::new(1.0, 2.0, 3.0)).unwrap();
+        w.write_vec4(Vector4::new(0.0, 0.0, 0.0, 1.0)).unwrap();
+        w.write_bytes(&bone_block(&BoneInterpolation::default(), false))
+            .unwrap();
+
+        let motion = read(bytes.as_slice()).unwrap();
+        assert_eq!(motion.model_name, "初音ミク");
+        let key = motion.bones["センター"][0];
+        assert_eq!(key.frame, 7);
+        assert_eq!(key.translation, Vector3::new(1.0, 2.0, 3.0));
+        assert_eq!(key.interpolation, BoneInterpolation::default());
+    }
+
+    #[test]
+    fn files_may_end_after_any_section() {
+        for sections in 1..=6 {
+            let motion = motion_with(sections);
+            let mut bytes = Vec::new();
+            motion.write(&mut bytes).unwrap();
+            // Drop the counts of the empty sections after the last one
+            bytes.truncate(bytes.len() - 4 * (6 - sections));
+
+            let read_back = read(bytes.as_slice())
+                .unwrap_or_else(|e| panic!("ending after {} sections: {}", sections, e));
+            assert_eq!(read_back.bones, motion.bones);
+            assert_eq!(read_back.morphs, motion.morphs);
+            assert_eq!(read_back.camera, motion.camera);
+            assert_eq!(read_back.light, motion.light);
+            assert_eq!(read_back.self_shadow, motion.self_shadow);
+            assert_eq!(read_back.ik, motion.ik);
+        }
+    }
+
+    #[test]
+    fn files_cut_inside_a_section_fail() {
+        let mut bytes = Vec::new();
+        motion_with(6).write(&mut bytes).unwrap();
+        bytes.pop();
+        assert!(matches!(read(bytes.as_slice()), Err(VmdError::Io(_))));
+    }
+
+    #[test]
+    fn last_duplicate_keyframe_wins() {
+        let mut motion = Motion::default();
+        motion.bones.insert(
+            "センター".to_string(),
+            vec![bone_key(10, 1.0), bone_key(0, 2.0), bone_key(10, 3.0)],
+        );
+        motion.camera.extend_from_slice(&[
+            camera_key(5, -10.0),
+            camera_key(5, -20.0),
+            camera_key(0, -30.0),
+        ]);
+        let mut bytes = Vec::new();
+        motion.write(&mut bytes).unwrap();
+        let read_back = read(bytes.as_slice()).unwrap();
+
+        assert_eq!(
+            read_back.bones["センター"],
+            [bone_key(0, 2.0), bone_key(10, 3.0)]
+        );
+        assert_eq!(
+            read_back.camera,
+            [camera_key(0, -30.0), camera_key(5, -20.0)]
+        );
+    }
+}
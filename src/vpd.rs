@@ -0,0 +1,298 @@
+//! Reader and writer for VPD poses, the Shift-JIS text files MMD saves a
+//! single pose to.
+
+use anyhow::{anyhow, bail, Context, Result};
+use cgmath::{Quaternion, Vector3};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::morph::Morphs;
+use crate::skeleton::{BonePose, Skeleton};
+
+const SIGNATURE: &str = "Vocaloid Pose Data file";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoseBone {
+    pub name: String,
+    pub pose: BonePose,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pose {
+    /// Model the pose was saved from, without the `.osm` extension.
+    pub model_name: String,
+    pub bones: Vec<PoseBone>,
+    /// `(morph name, weight)`, only written by newer versions of MMD.
+    pub morphs: Vec<(String, f32)>,
+}
+
+/// Reads the VPD file at `path`.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Pose> {
+    let path = path.as_ref();
+    let bytes = fs::read(path).with_context(|| format!("failed to read VPD file {:?}", path))?;
+    let (text, _, _) = encoding_rs::SHIFT_JIS.decode(&bytes);
+    parse(&text).with_context(|| format!("failed to parse VPD file {:?}", path))
+}
+
+pub fn parse(source: &str) -> Result<Pose> {
+    let mut lines = source
+        .lines()
+        .enumerate()
+        .map(|(number, line)| (number + 1, strip_comment(line)))
+        .filter(|(_, line)| !line.is_empty());
+    let mut next_line = |what: &str| {
+        lines
+            .next()
+            .with_context(|| format!("file ends before the {}", what))
+    };
+
+    let (_, signature) = next_line("signature")?;
+    if signature != SIGNATURE {
+        bail!("not a VPD file, the first line is {:?}", signature);
+    }
+    let (number, model) = next_line("model name")?;
+    let model = statement(number, model)?;
+    let model_name = model.strip_suffix(".osm").unwrap_or(model).to_string();
+    let (number, count) = next_line("bone count")?;
+    let count = statement(number, count)?;
+    let bone_count = count
+        .parse::<usize>()
+        .with_context(|| format!("line {}: invalid bone count {:?}", number, count))?;
+
+    let mut pose = Pose {
+        model_name,
+        bones: Vec::with_capacity(bone_count),
+        morphs: Vec::new(),
+    };
+
+    while let Ok((number, line)) = next_line("next block") {
+        let (kind, name) = line.split_once('{').with_context(|| {
+            format!(
+                "line {}: expected a Bone or Morph block, got {:?}",
+                number, line
+            )
+        })?;
+        let name = name.trim().to_string();
+
+        if kind.starts_with("Bone") {
+            let (number, line) = next_line("bone translation")?;
+            let [x, y, z] = numbers::<3>(number, line)?;
+            let (number, line) = next_line("bone rotation")?;
+            let [qx, qy, qz, qw] = numbers::<4>(number, line)?;
+            pose.bones.push(PoseBone {
+                name,
+                pose: BonePose {
+                    translation: Vector3::new(x, y, z),
+                    rotation: Quaternion::new(qw, qx, qy, qz),
+                    ..Default::default()
+                },
+            });
+        } else if kind.starts_with("Morph") {
+            let (number, line) = next_line("morph weight")?;
+            let [weight] = numbers::<1>(number, line)?;
+            pose.morphs.push((name, weight));
+        } else {
+            bail!("line {}: unknown block {:?}", number, kind);
+        }
+
+        let (number, line) = next_line("end of the block")?;
+        if line != "}" {
+            bail!(
+                "line {}: expected }} to end {:?}, got {:?}",
+                number,
+                kind,
+                line
+            );
+        }
+    }
+
+    if pose.bones.len() != bone_count {
+        log::warn!(
+            "VPD file says it has {} bones but has {}",
+            bone_count,
+            pose.bones.len()
+        );
+    }
+    Ok(pose)
+}
+
+/// `line` without its `//` comment. Comments follow the `;` of a statement
+/// or fill a line of their own; a block's name runs to the end of the line
+/// after its `{`, so names may contain `//`.
+fn strip_comment(line: &str) -> &str {
+    let line = line.trim();
+    if line.starts_with("//") {
+        return "";
+    }
+    match line.find([';', '{']) {
+        Some(end) if line[end..].starts_with(';') => &line[..=end],
+        _ => line,
+    }
+}
+
+/// The text of a `value;` line.
+fn statement(number: usize, line: &str) -> Result<&str> {
+    line.strip_suffix(';')
+        .map(str::trim)
+        .with_context(|| format!("line {}: expected ; at the end of {:?}", number, line))
+}
+
+fn numbers<const N: usize>(number: usize, line: &str) -> Result<[f32; N]> {
+    let values = statement(number, line)?
+        .split(',')
+        .map(|value| value.trim().parse::<f32>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .with_context(|| format!("line {}: invalid number in {:?}", number, line))?;
+    values.try_into().map_err(|values: Vec<f32>| {
+        anyhow!(
+            "line {}: expected {} numbers, got {}",
+            number,
+            N,
+            values.len()
+        )
+    })
+}
+
+impl Pose {
+    /// Captures the pose of every bone of `skeleton`.
+    pub fn from_skeleton(model_name: &str, skeleton: &Skeleton) -> Self {
+        Self {
+            model_name: model_name.to_string(),
+            bones: skeleton
+                .bones
+                .iter()
+                .zip(&skeleton.pose)
+                .map(|(bone, pose)| PoseBone {
+                    name: bone.name.clone(),
+                    pose: *pose,
+                })
+                .collect(),
+            morphs: Vec::new(),
+        }
+    }
+
+    /// Adds the morphs whose weight is not zero.
+    pub fn with_morphs(mut self, morphs: &Morphs) -> Self {
+        self.morphs = morphs
+            .morphs
+            .iter()
+            .zip(&morphs.weights)
+            .filter(|(_, &weight)| weight != 0.0)
+            .map(|(morph, &weight)| (morph.name.clone(), weight))
+            .collect();
+        self
+    }
+
+    /// Poses the bones of `skeleton` that are named in this pose; the others
+    /// keep their pose. Returns the names of the bones the skeleton lacks.
+    pub fn apply(&self, skeleton: &mut Skeleton) -> Vec<&str> {
+        let mut missing = Vec::new();
+        for bone in &self.bones {
+            match skeleton.bone_index(&bone.name) {
+                Some(index) => skeleton.pose[index] = bone.pose,
+                None => missing.push(bone.name.as_str()),
+            }
+        }
+        missing
+    }
+
+    /// Sets the weights of the morphs named in this pose. Returns the names
+    /// of the morphs the model lacks.
+    pub fn apply_morphs(&self, morphs: &mut Morphs) -> Vec<&str> {
+        let mut missing = Vec::new();
+        for (name, weight) in &self.morphs {
+            if !morphs.set_weight(name, *weight) {
+                missing.push(name.as_str());
+            }
+        }
+        missing
+    }
+
+    /// Writes the pose to `path` as Shift-JIS. Names that Shift-JIS cannot
+    /// hold are written as HTML character references, as encoding_rs does.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let text = self.to_string();
+        let (bytes, _, unmappable) = encoding_rs::SHIFT_JIS.encode(&text);
+        if unmappable {
+            log::warn!("{:?}: some names cannot be written in Shift-JIS", path);
+        }
+        fs::write(path, bytes).with_context(|| format!("failed to write VPD file {:?}", path))
+    }
+}
+
+/// Formats the pose the way MMD writes it.
+impl fmt::Display for Pose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}\n", SIGNATURE)?;
+        writeln!(f, "{}.osm;\t\t// 親ファイル名", self.model_name)?;
+        writeln!(f, "{};\t\t\t\t// 総ポーズボーン数\n", self.bones.len())?;
+
+        for (index, bone) in self.bones.iter().enumerate() {
+            let t = bone.pose.translation;
+            let q = bone.pose.rotation;
+            writeln!(f, "Bone{}{{{}", index, bone.name)?;
+            writeln!(
+                f,
+                "  {:.6},{:.6},{:.6};\t\t\t\t// trans x,y,z",
+                t.x, t.y, t.z
+            )?;
+            writeln!(
+                f,
+                "  {:.6},{:.6},{:.6},{:.6};\t\t// Quaternion x,y,z,w",
+                q.v.x, q.v.y, q.v.z, q.s
+            )?;
+            writeln!(f, "}}\n")?;
+        }
+        for (index, (name, weight)) in self.morphs.iter().enumerate() {
+            writeln!(f, "Morph{}{{{}", index, name)?;
+            writeln!(f, "  {:.6};\t\t\t\t// weight", weight)?;
+            writeln!(f, "}}\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::AssetConfig;
+
+    #[test]
+    fn round_trip() {
+        let path = AssetConfig::res_dir().join("yyb_school_miku_pose/sm pose.vpd");
+        let pose = load(path).unwrap();
+        assert_eq!(pose.model_name, "YYB School Miku");
+        assert_eq!(pose.bones.len(), 410);
+        assert_eq!(pose.bones[2].name, "センター");
+
+        let reparsed = parse(&pose.to_string()).unwrap();
+        assert_eq!(reparsed, pose);
+    }
+
+    #[test]
+    fn names_may_contain_comment_markers() {
+        let source = "Vocaloid Pose Data file
+
+model.osm;\t\t// 親ファイル名
+1;\t\t\t\t// 総ポーズボーン数
+
+Bone0{左//腕
+  1.000000,2.000000,3.000000;\t\t\t\t// trans x,y,z
+  0.000000,0.000000,0.000000,1.000000;\t\t// Quaternion x,y,z,w
+}
+
+Morph0{あ//い
+  0.500000;\t\t\t\t// weight
+}
+";
+        let pose = parse(source).unwrap();
+        assert_eq!(pose.model_name, "model");
+        assert_eq!(pose.bones[0].name, "左//腕");
+        assert_eq!(pose.bones[0].pose.translation, Vector3::new(1.0, 2.0, 3.0));
+        assert_eq!(pose.morphs, [("あ//い".to_string(), 0.5)]);
+        assert_eq!(parse(&pose.to_string()).unwrap(), pose);
+    }
+}